@@ -0,0 +1,27 @@
+#!/usr/bin/python3
+
+import struct
+import zlib
+
+SIZE = 256
+
+def pixel(x, y):
+    # diagonal stripes on the same background as no_tile.png
+    if (x + y) % 32 < 8:
+        return (64, 32, 32)
+    return (32, 32, 32)
+
+def chunk(kind, data):
+    return (struct.pack('>I', len(data)) + kind + data +
+            struct.pack('>I', zlib.crc32(kind + data) & 0xffffffff))
+
+rows = b''.join(
+    b'\x00' + bytes(c for x in range(SIZE) for c in pixel(x, y))
+    for y in range(SIZE)
+)
+
+with open('missing_tile.png', 'wb') as f:
+    f.write(b'\x89PNG\r\n\x1a\n')
+    f.write(chunk(b'IHDR', struct.pack('>IIBBBBB', SIZE, SIZE, 8, 2, 0, 0, 0)))
+    f.write(chunk(b'IDAT', zlib.compress(rows, 9)))
+    f.write(chunk(b'IEND', b''))
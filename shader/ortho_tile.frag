@@ -4,7 +4,9 @@ precision highp float;
 varying vec2 v_tex;
 varying vec4 v_tex_minmax;
 uniform sampler2D tex_map;
+uniform float opacity;
 
 void main() {
-    gl_FragColor = vec4(texture2D(tex_map, clamp(v_tex.xy, v_tex_minmax.xy, v_tex_minmax.zw)).rgb, 1.0);
+    vec4 color = texture2D(tex_map, clamp(v_tex.xy, v_tex_minmax.xy, v_tex_minmax.zw));
+    gl_FragColor = vec4(color.rgb, color.a * opacity);
 }
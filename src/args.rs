@@ -1,5 +1,5 @@
 use clap;
-use clap::Arg;
+use clap::{Arg, SubCommand};
 
 
 pub fn parse<'a>() -> clap::ArgMatches<'a> {
@@ -17,6 +17,10 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
             .long("list-paths")
             .help("Print paths of configuration files and directories \
                 and exit the program."))
+        .arg(Arg::with_name("list-sources")
+            .long("list-sources")
+            .help("Print the tile sources with their attribution, description and license \
+                and exit the program."))
         .arg(Arg::with_name("tile-sources")
             .short("t")
             .long("tile-sources")
@@ -71,5 +75,86 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
             .long("sync")
             .help("Load tiles in a synchronous fashion. \
                 The UI is blocked while tiles are loading."))
+        .subcommand(SubCommand::with_name("seed")
+            .about("Download all tiles of an area into the tile cache without opening a window. \
+                Tiles that are already cached are skipped, so an interrupted run can be resumed.")
+            .arg(Arg::with_name("source")
+                .long("source")
+                .value_name("NAME")
+                .help("Name of the tile source")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("bbox")
+                .long("bbox")
+                .value_name("W,S,E,N")
+                .allow_hyphen_values(true)
+                .help("Bounding box in degrees. The view of the last session is used if this \
+                    is not given.")
+                .takes_value(true))
+            .arg(Arg::with_name("zoom")
+                .long("zoom")
+                .value_name("MIN-MAX")
+                .help("Zoom level or range of zoom levels, e.g. 10-15")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("hidpi")
+                .long("hidpi")
+                .help("Download the high-resolution tiles that are shown on high-DPI displays. \
+                    This only has an effect on tile sources with an {r} placeholder.")))
+        .subcommand(SubCommand::with_name("check-sources")
+            .about("Check the configuration of the tile sources and load a sample tile at the \
+                minimum and maximum zoom level of each source. With --offline the sample tiles \
+                are read from the cache.")
+            .arg(source_arg()))
+        .subcommand(SubCommand::with_name("cache")
+            .about("Show or reduce the size of the tile cache without opening a window")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("usage")
+                .about("Print the number of cached tiles and their size for each tile source"))
+            .subcommand(SubCommand::with_name("trim")
+                .about("Remove the least recently accessed tiles until the cache fits within \
+                    the configured max_cache_size limits")
+                .arg(source_arg())
+                .arg(Arg::with_name("max-size")
+                    .long("max-size")
+                    .value_name("SIZE")
+                    .help("Size limit that is used instead of the configured one, e.g. \"500 MB\"")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("clear")
+                .about("Remove all cached tiles")
+                .arg(source_arg()))
+            .subcommand(SubCommand::with_name("export")
+                .about("Write the cached tiles of a tile source to a new MBTiles file")
+                .arg(source_arg().required(true))
+                .arg(Arg::with_name("bbox")
+                    .long("bbox")
+                    .value_name("W,S,E,N")
+                    .allow_hyphen_values(true)
+                    .help("Only export tiles that overlap with this bounding box in degrees")
+                    .takes_value(true))
+                .arg(Arg::with_name("zoom")
+                    .long("zoom")
+                    .value_name("MIN-MAX")
+                    .help("Only export tiles of this zoom level or range of zoom levels, e.g. 10-15")
+                    .takes_value(true))
+                .arg(Arg::with_name("file")
+                    .value_name("FILE")
+                    .help("Path of the MBTiles file")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("import")
+                .about("Copy the tiles of an MBTiles file into the cache of a tile source")
+                .arg(source_arg().required(true))
+                .arg(Arg::with_name("file")
+                    .value_name("FILE")
+                    .help("Path of the MBTiles file")
+                    .required(true))))
         .get_matches()
 }
+
+fn source_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("source")
+        .long("source")
+        .value_name("NAME")
+        .help("Name of the tile source")
+        .takes_value(true)
+}
@@ -0,0 +1,135 @@
+use ::std::ffi::CStr;
+use bitmap_font;
+use buffer::{Buffer, DrawMode};
+use context::Context;
+use image::{DynamicImage, Rgba};
+use program::Program;
+use texture::{Texture, TextureFormat};
+use vertex_attrib::VertexAttribParams;
+
+
+/// Draws a line of text in a corner of the viewport, e.g. the attribution of the visible tile
+/// sources.
+#[derive(Debug)]
+pub struct AttributionLayer {
+    buffer: Buffer,
+    program: Program,
+    texture: Texture,
+    text: String,
+    scale: u32,
+    corner: Corner,
+}
+
+/// The corner of the viewport that a text is aligned with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    BottomRight,
+}
+
+impl AttributionLayer {
+    pub fn new(cx: &mut Context) -> AttributionLayer {
+        let buffer = Buffer::new(cx, &[], 0);
+        cx.bind_buffer(buffer.id());
+        check_gl_errors!(cx);
+
+        let mut program = Program::new(
+            cx,
+            include_bytes!("../shader/marker.vert"),
+            include_bytes!("../shader/marker.frag"),
+        ).unwrap();
+
+        let texture = Texture::empty(cx, 1, 1, TextureFormat::Rgba8);
+
+        program.add_texture(cx, &texture, CStr::from_bytes_with_nul(b"tex\0").unwrap());
+
+        program.add_attribute(
+            cx,
+            CStr::from_bytes_with_nul(b"position\0").unwrap(),
+            &VertexAttribParams::new(2, 4, 0)
+        );
+        program.add_attribute(
+            cx,
+            CStr::from_bytes_with_nul(b"tex_coord\0").unwrap(),
+            &VertexAttribParams::new(2, 4, 2)
+        );
+
+        AttributionLayer {
+            buffer,
+            program,
+            texture,
+            text: String::new(),
+            scale: 0,
+            corner: Corner::BottomRight,
+        }
+    }
+
+    /// Set the corner that the text is aligned with. The default is `Corner::BottomRight`.
+    pub fn with_corner(mut self, corner: Corner) -> Self {
+        self.corner = corner;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Sets the text and the integer scale factor of the font. The texture is only updated if one
+    /// of them changes.
+    pub fn set_text(&mut self, cx: &mut Context, text: &str, scale: u32) {
+        if self.text == text && self.scale == scale {
+            return;
+        }
+
+        self.text = text.to_string();
+        self.scale = scale;
+
+        if !text.is_empty() {
+            let img = bitmap_font::render_label(
+                text,
+                scale,
+                Rgba([0x33, 0x33, 0x33, 0xff]),
+                Rgba([0xff, 0xff, 0xff, 0xb0]),
+            );
+            self.texture.resize(cx, img.width(), img.height());
+            self.texture.sub_image(cx, 0, 0, &DynamicImage::ImageRgba8(img));
+        }
+    }
+
+    // Has to be called once before one or multiple calls to `draw`.
+    pub fn prepare_draw(&mut self, cx: &mut Context) {
+        cx.set_active_texture_unit(self.texture.unit());
+        self.program.enable_vertex_attribs(cx);
+        self.program.set_vertex_attribs(cx, &self.buffer);
+    }
+
+    /// Draws the text. `viewport_size` is given in physical pixels.
+    pub fn draw(&mut self, cx: &mut Context, viewport_size: (u32, u32)) {
+        if self.text.is_empty() || viewport_size.0 == 0 || viewport_size.1 == 0 {
+            return;
+        }
+
+        let scale_x = 2.0 / viewport_size.0 as f32;
+        let scale_y = 2.0 / viewport_size.1 as f32;
+
+        // Align the label with the pixel grid in the chosen corner.
+        let width = self.texture.width() as f32 * scale_x;
+        let height = self.texture.height() as f32 * scale_y;
+        let (x1, x2, y1, y2) = match self.corner {
+            Corner::TopLeft => (-1.0, -1.0 + width, 1.0, 1.0 - height),
+            Corner::BottomRight => (1.0 - width, 1.0, -1.0 + height, -1.0),
+        };
+
+        let vertex_data = [
+            x1, y1, 0.0, 0.0,
+            x2, y1, 1.0, 0.0,
+            x2, y2, 1.0, 1.0,
+            x1, y1, 0.0, 0.0,
+            x2, y2, 1.0, 1.0,
+            x1, y2, 0.0, 1.0,
+        ];
+
+        self.buffer.set_data(cx, &vertex_data, vertex_data.len() / 4);
+        self.buffer.draw(cx, &self.program, DrawMode::Triangles);
+    }
+}
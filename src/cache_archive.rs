@@ -0,0 +1,272 @@
+//! Packs the downloaded tiles of a source into a single MBTiles file and unpacks them again, e.g.
+//! to pass an area that was cached while online to another computer.
+
+use clap;
+use config::Config;
+use coord::{BoundingBox, TileCoord};
+use http_cache::metadata_path;
+use mbtiles::{MAX_ZOOM, MbTiles};
+use seed::{parse_bbox, parse_zoom_range};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tile_source::TileSource;
+
+/// Restricts which tiles are exported.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TileFilter {
+    pub bbox: Option<BoundingBox>,
+    /// Inclusive range of zoom levels
+    pub zoom_range: Option<(u32, u32)>,
+}
+
+impl TileFilter {
+    pub fn contains(&self, tile_coord: TileCoord) -> bool {
+        let in_zoom_range = match self.zoom_range {
+            Some((min_zoom, max_zoom)) => tile_coord.zoom >= min_zoom && tile_coord.zoom <= max_zoom,
+            None => true,
+        };
+        let in_bbox = match self.bbox {
+            Some(bbox) => bbox.intersects_tile(tile_coord),
+            None => true,
+        };
+        in_zoom_range && in_bbox
+    }
+}
+
+/// Runs the `cache export` command with its argument matches.
+pub fn run_export<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<(), String> {
+    let name = matches.value_of("source").unwrap_or_default();
+    let source = config.tile_source(name)?;
+    let filter = TileFilter {
+        bbox: matches.value_of("bbox").map(parse_bbox).transpose()?,
+        zoom_range: matches.value_of("zoom").map(parse_zoom_range).transpose()?,
+    };
+    let path = matches.value_of_os("file").map(PathBuf::from).unwrap_or_default();
+
+    let count = export(name, source, &filter, &path)?;
+    println!("exported {} tiles of {:?} to {:?}", count, name, path);
+    Ok(())
+}
+
+/// Runs the `cache import` command with its argument matches.
+pub fn run_import<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<(), String> {
+    let name = matches.value_of("source").unwrap_or_default();
+    let source = config.tile_source(name)?;
+    let path = matches.value_of_os("file").map(PathBuf::from).unwrap_or_default();
+
+    let count = import(source, &path)?;
+    println!("imported {} tiles of {:?} from {:?}", count, name, path);
+    Ok(())
+}
+
+/// Writes the cached tiles of `source` that pass the filter to a new MBTiles file at `path`.
+/// Returns the number of exported tiles.
+pub fn export(name: &str, source: &TileSource, filter: &TileFilter, path: &Path) -> Result<usize, String> {
+    let dir = source.cache_directory()
+        .ok_or_else(|| format!("tile source {:?} is not downloaded and has no cache", name))?;
+    check_no_times(source)?;
+
+    let mut tiles = vec![];
+    collect_tiles(source, dir, &mut tiles)?;
+    tiles.retain(|&(tile_coord, _)| filter.contains(tile_coord));
+    tiles.sort_by_key(|&(t, _)| (t.zoom, t.x, t.y));
+
+    let mut mbtiles = MbTiles::create(path)?;
+    let count = mbtiles.write_tiles(tiles.iter().filter_map(|(tile_coord, tile_path)| {
+        match fs::read(tile_path) {
+            Ok(data) => Some((*tile_coord, data)),
+            Err(e) => {
+                warn!("could not read {:?}, {}", tile_path, e);
+                None
+            },
+        }
+    }))?;
+
+    let format = match source.extension() {
+        "jpeg" => "jpg",
+        extension => extension,
+    };
+    let layer_type = if source.overlay().is_some() { "overlay" } else { "baselayer" };
+
+    let mut metadata = vec![
+        ("name", name.to_string()),
+        ("format", format.to_string()),
+        ("type", layer_type.to_string()),
+    ];
+    if let Some(min_zoom) = tiles.iter().map(|(t, _)| t.zoom).min() {
+        metadata.push(("minzoom", min_zoom.to_string()));
+    }
+    if let Some(max_zoom) = tiles.iter().map(|(t, _)| t.zoom).max() {
+        metadata.push(("maxzoom", max_zoom.to_string()));
+    }
+    if let Some(bbox) = filter.bbox.or_else(|| source.bounds()) {
+        metadata.push(("bounds", format!("{},{},{},{}", bbox.west, bbox.south, bbox.east, bbox.north)));
+    }
+    let info = source.info();
+    for &(key, ref value) in &[("attribution", &info.attribution), ("description", &info.description)] {
+        if let Some(value) = value {
+            metadata.push((key, value.clone()));
+        }
+    }
+
+    for (key, value) in &metadata {
+        mbtiles.set_metadata(key, value)?;
+    }
+
+    Ok(count)
+}
+
+/// Collects the tiles in the directory layout of `source` with their XYZ coordinates. Files that
+/// do not belong to the layout are ignored.
+fn collect_tiles(source: &TileSource, dir: &Path, tiles: &mut Vec<(TileCoord, PathBuf)>) -> Result<(), String> {
+    let read_dir = |dir: &Path| {
+        fs::read_dir(dir)
+            .map_err(|e| format!("could not read {:?}, {}", dir, e))
+            .map(|entries| entries.filter_map(|e| e.ok()))
+    };
+    let parse_name = |path: &Path, suffix: bool| {
+        let name = if suffix { path.file_stem() } else { path.file_name() };
+        name.and_then(|n| n.to_str())
+            .and_then(|n| n.split('@').next())
+            .and_then(|n| n.parse::<i64>().ok())
+    };
+
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for zoom_entry in read_dir(dir)? {
+        let zoom = match parse_name(&zoom_entry.path(), false) {
+            Some(zoom) if zoom >= 0 && zoom <= i64::from(MAX_ZOOM) => zoom as u32,
+            _ => continue,
+        };
+
+        for x_entry in read_dir(&zoom_entry.path())? {
+            let x = match parse_name(&x_entry.path(), false) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            for tile_entry in read_dir(&x_entry.path())? {
+                let tile_path = tile_entry.path();
+                let y = match parse_name(&tile_path, true) {
+                    Some(y) => y,
+                    None => continue,
+                };
+
+                // The directory uses the numbering of the source's scheme.
+                let scheme_coord = TileCoord { zoom, x: x as i32, y: y as i32 };
+                if i64::from(scheme_coord.x) != x || i64::from(scheme_coord.y) != y || !scheme_coord.is_valid() {
+                    continue;
+                }
+                let tile_coord = source.scheme().convert(scheme_coord);
+
+                // Also rejects other file extensions, resolutions and metadata files.
+                if source.local_tile_path(tile_coord) == tile_path {
+                    tiles.push((tile_coord, tile_path));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// MBTiles files have no time dimension, so the time steps of a source cannot be packed into one.
+fn check_no_times(source: &TileSource) -> Result<(), String> {
+    if source.times().is_empty() {
+        Ok(())
+    } else {
+        Err("tile sources with a time dimension cannot be exported or imported".to_string())
+    }
+}
+
+/// Unpacks the tiles of the MBTiles file at `path` into the cache directory of `source`.
+/// Existing tiles are replaced. Returns the number of imported tiles.
+pub fn import(source: &TileSource, path: &Path) -> Result<usize, String> {
+    if source.cache_directory().is_none() {
+        return Err("only tile sources that are downloaded can import tiles".to_string());
+    }
+    check_no_times(source)?;
+
+    let mbtiles = MbTiles::open(path)?;
+    let mut count = 0;
+    mbtiles.for_each_tile(|tile_coord, data| {
+        if !source.contains_tile(tile_coord) {
+            return Ok(());
+        }
+
+        let tile_path = source.local_tile_path(tile_coord);
+        if let Some(dir) = tile_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("could not create {:?}, {}", dir, e))?;
+        }
+        fs::write(&tile_path, data).map_err(|e| format!("could not write {:?}, {}", tile_path, e))?;
+
+        // The HTTP metadata of a replaced tile does not match the new image.
+        let _ = fs::remove_file(metadata_path(&tile_path));
+
+        count += 1;
+        Ok(())
+    })?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use cache_archive::*;
+    use test_util::TempDir;
+    use tile_source::TileScheme;
+    use url_template::UrlTemplate;
+
+    fn source(dir: &Path, scheme: TileScheme) -> TileSource {
+        let url_template = UrlTemplate::new("https://tile.example.com/{z}/{x}/{y}.png").unwrap();
+        TileSource::new(0, url_template, dir, "png".to_string(), 0, 19).with_scheme(scheme)
+    }
+
+    #[test]
+    fn export_and_import() {
+        let dir = TempDir::new("deltamap_test_cache_archive");
+        let tms_source = source(&dir.join("tms"), TileScheme::Tms);
+
+        let tiles = [TileCoord::new(1, 0, 0), TileCoord::new(1, 1, 1), TileCoord::new(2, 3, 0)];
+        for tile_coord in &tiles {
+            let path = tms_source.local_tile_path(*tile_coord);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("{:?}", tile_coord)).unwrap();
+            fs::write(metadata_path(&path), "fetched = 0").unwrap();
+        }
+        fs::write(dir.join("tms/1/0/notes.txt"), "not a tile").unwrap();
+
+        // The eastern hemisphere at zoom level 1 and 2
+        let filter = TileFilter {
+            bbox: Some(BoundingBox::new(1.0, -80.0, 179.0, 80.0)),
+            zoom_range: Some((1, 2)),
+        };
+        let mbtiles_path = dir.join("export.mbtiles");
+        assert_eq!(export("test", &tms_source, &filter, &mbtiles_path), Ok(2));
+        assert!(export("test", &tms_source, &filter, &mbtiles_path).is_err());
+
+        let mbtiles = MbTiles::open(&mbtiles_path).unwrap();
+        assert_eq!(mbtiles.read_tile(TileCoord::new(2, 3, 0)), Ok(Some(b"TileCoord { zoom: 2, x: 3, y: 0 }".to_vec())));
+        assert_eq!(mbtiles.read_tile(TileCoord::new(1, 0, 0)), Ok(None));
+        let metadata = mbtiles.metadata().unwrap();
+        assert_eq!(metadata.format, Some("png".to_string()));
+        assert_eq!((metadata.min_zoom, metadata.max_zoom), (Some(1), Some(2)));
+
+        let xyz_source = source(&dir.join("xyz"), TileScheme::Xyz);
+        assert_eq!(import(&xyz_source, &mbtiles_path), Ok(2));
+        assert_eq!(
+            fs::read(xyz_source.local_tile_path(TileCoord::new(1, 1, 1))).unwrap(),
+            b"TileCoord { zoom: 1, x: 1, y: 1 }".to_vec(),
+        );
+        assert!(dir.join("xyz/2/3/0.png").is_file());
+        assert!(!xyz_source.local_tile_path(TileCoord::new(1, 0, 0)).exists());
+
+        let time_source = source(&dir.join("time"), TileScheme::Xyz)
+            .with_times(vec!["2024-05-01".to_string(), "2024-05-02".to_string()]);
+        assert!(export("test", &time_source, &TileFilter::default(), &dir.join("time.mbtiles")).is_err());
+        assert!(import(&time_source, &mbtiles_path).is_err());
+        assert!(!dir.join("time").exists());
+    }
+}
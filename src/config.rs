@@ -1,16 +1,40 @@
 use clap;
+use coord::BoundingBox;
 use directories::ProjectDirs;
+use disk_cache::CacheLimit;
+use humantime;
+use mbtiles::MbTiles;
+use prefetch::PrefetchSettings;
 use query::QueryArgs;
+use regex::{Captures, Regex};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use std::collections::HashMap;
+use std::env;
 use session::Session;
+use size;
+use source_import::{self, ImportedSource};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use tile_source::TileSource;
+use std::time::{Duration, UNIX_EPOCH};
+use tile_loader::DownloadSettings;
+use tile_source::{DownloadLimits, OverlaySettings, SourceInfo, TileScheme, TileSource};
 use toml::Value;
+use toml::value::Table;
+use url_template::{BBoxCrs, UrlTemplate, WmsParams};
 
 static DEFAULT_CONFIG: &'static str = "";
 static DEFAULT_TILE_SOURCES: &'static str = include_str!("../default_tile_sources.toml");
+static DEFAULT_USER_AGENT: &str = concat!(
+    "DeltaMap/", env!("CARGO_PKG_VERSION"), " (+https://github.com/b-r-u/deltamap)"
+);
+
+/// Keys of tile sources that only apply to sources that download their tiles.
+static REMOTE_ONLY_KEYS: &[&str] = &[
+    "url_template", "url", "layers", "styles", "format", "crs", "subdomains", "headers", "max_age",
+    "times", "time_range", "download_workers", "max_in_flight", "max_cache_size",
+];
 
 lazy_static! {
     static ref PROJ_DIRS: Option<ProjectDirs> = ProjectDirs::from("", "", "DeltaMap");
@@ -25,14 +49,26 @@ fn proj_dirs_result() -> Result<&'static ProjectDirs, String> {
 pub struct Config {
     config_file_path: Option<PathBuf>,
     tile_sources_file_path: Option<PathBuf>,
+    /// TileJSON and imagery XML files that sources were imported from
+    imported_file_paths: Vec<PathBuf>,
     tile_cache_dir: PathBuf,
+    /// Maximum size of the whole tile cache directory in bytes
+    max_cache_size: Option<u64>,
+    /// Memory budget of the decoded tiles in bytes
+    memory_cache_size: Option<usize>,
     sources: Vec<(String, TileSource)>,
+    /// Names of the tile sources that could not be parsed and the reasons
+    invalid_sources: Vec<(String, String)>,
+    skip_invalid_sources: bool,
     pbf_path: Option<PathBuf>,
     search_patterns: Vec<String>,
     keyval: Vec<(String, String)>,
     keyvalregex: Vec<(String, String)>,
     fps: f64,
+    user_agent: HeaderValue,
     use_network: bool,
+    download: DownloadSettings,
+    prefetch: PrefetchSettings,
     async: bool,
     open_last_session: bool,
 }
@@ -41,11 +77,22 @@ impl Config {
     //TODO use builder pattern to create config
 
     pub fn from_arg_matches<'a>(matches: &clap::ArgMatches<'a>) -> Result<Config, String> {
+        Config::load(matches, false)
+    }
+
+    /// Like `from_arg_matches`, but tile sources that cannot be parsed are skipped instead of
+    /// failing. They are returned by `invalid_sources`.
+    pub fn from_arg_matches_lenient<'a>(matches: &clap::ArgMatches<'a>) -> Result<Config, String> {
+        Config::load(matches, true)
+    }
+
+    fn load<'a>(matches: &clap::ArgMatches<'a>, skip_invalid_sources: bool) -> Result<Config, String> {
         let mut config = if let Some(config_path) = matches.value_of_os("config") {
             Config::from_toml_file(config_path)?
         } else {
             Config::find_or_create()?
         };
+        config.skip_invalid_sources = skip_invalid_sources;
 
         if let Some(tile_sources_path) = matches.value_of_os("tile-sources") {
             config.add_tile_sources_from_file(tile_sources_path)?;
@@ -203,6 +250,10 @@ impl Config {
                     }
                 };
 
+                let max_cache_size = parse_cache_size(table.get("max_cache_size"), "max_cache_size")?;
+                let memory_cache_size = parse_cache_size(table.get("memory_cache_size"), "memory_cache_size")?
+                    .map(|size| size.min(usize::MAX as u64) as usize);
+
                 let pbf_path = {
                     match table.get("pbf_file") {
                         Some(&Value::String(ref pbf_file)) => {
@@ -235,6 +286,17 @@ impl Config {
                     }
                 };
 
+                let user_agent = {
+                    match table.get("user_agent") {
+                        Some(Value::String(user_agent)) => {
+                            HeaderValue::from_str(user_agent)
+                                .map_err(|e| format!("user_agent is not a valid header value: {}", e))?
+                        },
+                        Some(_) => return Err("user_agent has to be a string.".to_string()),
+                        None => HeaderValue::from_static(DEFAULT_USER_AGENT),
+                    }
+                };
+
                 let use_network = {
                     match table.get("use_network") {
                         Some(&Value::Boolean(x)) => x,
@@ -243,6 +305,9 @@ impl Config {
                     }
                 };
 
+                let download = parse_download_settings(table)?;
+                let prefetch = parse_prefetch_settings(table)?;
+
                 let async = {
                     match table.get("async") {
                         Some(&Value::Boolean(x)) => x,
@@ -263,14 +328,22 @@ impl Config {
                     Config {
                         config_file_path: config_path.map(|p| PathBuf::from(p.as_ref())),
                         tile_sources_file_path: None,
+                        imported_file_paths: vec![],
                         tile_cache_dir,
+                        max_cache_size,
+                        memory_cache_size,
                         sources: vec![],
+                        invalid_sources: vec![],
+                        skip_invalid_sources: false,
                         pbf_path,
                         search_patterns: vec![],
                         keyval: vec![],
                         keyvalregex: vec![],
                         fps,
+                        user_agent,
                         use_network,
+                        download,
+                        prefetch,
                         async,
                         open_last_session,
                     }
@@ -299,78 +372,51 @@ impl Config {
     {
         match toml_str.parse::<Value>() {
             Ok(Value::Table(ref table)) => {
-                let sources_array = table.get("tile_sources")
-                    .ok_or_else(|| "missing \"tile_sources\" table".to_string())?
-                    .as_array()
-                    .ok_or_else(|| "\"tile_sources\" has to be an array.".to_string())?;
-
-                for (id, source) in sources_array.iter().enumerate() {
-                    let name = source.get("name")
-                        .ok_or_else(|| "tile_source is missing \"name\" entry.".to_string())?
-                        .as_str()
-                        .ok_or_else(|| "\"name\" has to be a string".to_string())?;
-
-                    let min_zoom = source.get("min_zoom")
-                        .unwrap_or_else(|| &Value::Integer(0))
-                        .as_integer()
-                        .ok_or_else(|| "min_zoom has to be an integer".to_string())
-                        .and_then(|m| {
-                            if m < 0 || m > 30 {
-                                Err(format!("min_zoom = {} is out of bounds, has to be in interval [0, 30]", m))
-                            } else {
-                                Ok(m)
-                            }
-                        })?;
-
-                    let max_zoom = source.get("max_zoom")
-                        .ok_or_else(|| format!("source {:?} is missing \"max_zoom\" entry", name))?
-                        .as_integer()
-                        .ok_or_else(|| "max_zoom has to be an integer".to_string())
-                        .and_then(|m| {
-                            if m < 0 || m > 30 {
-                                Err(format!("max_zoom = {} is out of bounds, has to be in interval [0, 30]", m))
-                            } else {
-                                Ok(m)
-                            }
-                        })?;
+                // A file may consist only of imports.
+                let sources_array = match table.get("tile_sources") {
+                    Some(Value::Array(array)) => array.as_slice(),
+                    Some(_) => return Err("\"tile_sources\" has to be an array.".to_string()),
+                    None if table.contains_key("import") => &[],
+                    None => return Err("missing \"tile_sources\" table".to_string()),
+                };
 
-                    if min_zoom > max_zoom {
-                        warn!("min_zoom ({}) and max_zoom ({}) allow no valid tiles", min_zoom, max_zoom);
-                    } else if min_zoom == max_zoom {
-                        warn!("min_zoom ({}) and max_zoom ({}) allow only one zoom level", min_zoom, max_zoom);
-                    }
+                // Relative paths are resolved relative to the directory of the tile sources file.
+                let base_dir = file_path.as_ref()
+                    .and_then(|p| p.as_ref().parent())
+                    .map(PathBuf::from)
+                    .unwrap_or_default();
+
+                let secrets = match table.get("secrets_file") {
+                    Some(Value::String(secrets_file)) => read_secrets_file(base_dir.join(secrets_file))?,
+                    Some(_) => return Err("secrets_file has to be a string.".to_string()),
+                    None => HashMap::new(),
+                };
 
-                    let url_template = source.get("url_template")
-                        .ok_or_else(|| format!("source {:?} is missing \"url_template\" entry", name))?
-                        .as_str()
-                        .ok_or_else(|| "url_template has to be a string".to_string())?;
-
-                    let extension = source.get("extension")
-                        .ok_or_else(|| format!("source {:?} is missing \"extension\" entry", name))?
-                        .as_str()
-                        .ok_or_else(|| "extension has to be a string".to_string())?;
-
-                    //TODO reduce allowed strings to a reasonable subset of valid UTF-8 strings
-                    // that can also be used as a directory name or introduce a dir_name key with
-                    // more restrictions.
-                    if name.contains('/') || name.contains('\\') {
-                        return Err(format!("source name ({:?}) must not contain slashes (\"/\" or \"\\\")", name));
+                for (index, source) in sources_array.iter().enumerate() {
+                    let id = self.sources.len() as u32;
+                    match self.parse_tile_source(id, source, &secrets, &base_dir) {
+                        Ok(named_source) => self.sources.push(named_source),
+                        Err(err) if self.skip_invalid_sources => {
+                            let name = source.get("name")
+                                .and_then(|n| n.as_str())
+                                .map(|n| n.to_string())
+                                .unwrap_or_else(|| format!("tile source {}", index + 1));
+                            self.invalid_sources.push((name, err));
+                        },
+                        Err(err) => return Err(err),
                     }
+                }
 
-                    let mut path = PathBuf::from(&self.tile_cache_dir);
-                    path.push(name);
-
-                    self.sources.push((
-                        name.to_string(),
-                        TileSource::new(
-                            id as u32,
-                            url_template.to_string(),
-                            path,
-                            extension.to_string(),
-                            min_zoom as u32,
-                            max_zoom as u32,
-                        )?,
-                    ));
+                match table.get("import") {
+                    Some(Value::Array(paths)) => {
+                        for path in paths {
+                            let path = path.as_str()
+                                .ok_or_else(|| "import has to be an array of strings".to_string())?;
+                            self.import_tile_sources(&base_dir.join(path), &secrets)?;
+                        }
+                    },
+                    Some(_) => return Err("import has to be an array of strings".to_string()),
+                    None => {},
                 }
 
                 self.tile_sources_file_path = file_path.map(|p| PathBuf::from(p.as_ref()));
@@ -381,7 +427,255 @@ impl Config {
         }
     }
 
+    /// Parses one entry of the `tile_sources` array and returns the name and the source.
+    fn parse_tile_source(
+        &self,
+        id: u32,
+        source: &Value,
+        secrets: &HashMap<String, String>,
+        base_dir: &Path,
+    ) -> Result<(String, TileSource), String> {
+        let name = source.get("name")
+            .ok_or_else(|| "tile_source is missing \"name\" entry.".to_string())?
+            .as_str()
+            .ok_or_else(|| "\"name\" has to be a string".to_string())?;
+
+        // The name is also the name of the cache directory, so it must not point to the cache
+        // directory itself or its parent.
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(format!("source name ({:?}) must not be empty, \".\" or \"..\"", name));
+        }
+
+        //TODO reduce allowed strings to a reasonable subset of valid UTF-8 strings
+        // that can also be used as a directory name or introduce a dir_name key with
+        // more restrictions.
+        if name.contains('/') || name.contains('\\') {
+            return Err(format!("source name ({:?}) must not contain slashes (\"/\" or \"\\\")", name));
+        }
+
+        // Sources are identified by name, e.g. in sessions, and share the cache directory of
+        // that name.
+        if self.sources.iter().any(|(n, _)| n == name) {
+            return Err(format!("there is already a tile source named {:?}", name));
+        }
+
+        if let Some(Value::String(kind)) = source.get("kind") {
+            if kind == "mbtiles" || kind == "directory" {
+                if let Some(key) = REMOTE_ONLY_KEYS.iter().find(|key| source.get(*key).is_some()) {
+                    return Err(format!("source {:?}: {:?} is not supported for kind = {:?}", name, key, kind));
+                }
+                let tile_source = local_source(id, name, source, secrets, base_dir)?;
+                let tile_source = apply_common_options(tile_source, source)?;
+                return Ok((name.to_string(), tile_source));
+            }
+        }
+
+        let min_zoom = parse_zoom(source, "min_zoom")?.unwrap_or(0);
+        let max_zoom = parse_zoom(source, "max_zoom")?
+            .ok_or_else(|| format!("source {:?} is missing \"max_zoom\" entry", name))?;
+        check_zoom_range(min_zoom, max_zoom);
+
+        let subdomains = match source.get("subdomains") {
+            Some(Value::Array(array)) => {
+                array.iter()
+                    .map(|s| {
+                        s.as_str()
+                            .map(|s| s.to_string())
+                            .ok_or_else(|| "subdomains has to be an array of strings".to_string())
+                    })
+                    .collect::<Result<Vec<String>, String>>()?
+            },
+            Some(_) => return Err("subdomains has to be an array of strings".to_string()),
+            None => vec![],
+        };
+
+        let scheme = parse_scheme(source)?;
+
+        let tile_size = parse_tile_size(source)?;
+
+        let crs = match source.get("crs") {
+            Some(Value::String(s)) => {
+                s.parse::<BBoxCrs>()
+                    .map_err(|_| format!("crs = {:?} is invalid, has to be \"EPSG:3857\" or \"EPSG:4326\"", s))?
+            },
+            Some(_) => return Err("crs has to be a string".to_string()),
+            None => BBoxCrs::Epsg3857,
+        };
+
+        let url_template = match source.get("kind").unwrap_or(&Value::String("xyz".to_string())) {
+            Value::String(kind) if kind == "xyz" => {
+                let url_template = source.get("url_template")
+                    .ok_or_else(|| format!("source {:?} is missing \"url_template\" entry", name))?
+                    .as_str()
+                    .ok_or_else(|| "url_template has to be a string".to_string())?;
+                let url_template = expand_variables(url_template, |n| variable(n, secrets))
+                    .map_err(|e| format!("source {:?}: url_template: {}", name, e))?;
+                UrlTemplate::with_subdomains(url_template, subdomains)?.with_bbox_crs(crs)
+            },
+            Value::String(kind) if kind == "wms" => {
+                if scheme != TileScheme::Xyz {
+                    return Err(format!("source {:?}: scheme is not supported for WMS sources", name));
+                }
+                let get_str = |key: &str, default: Option<&str>| -> Result<String, String> {
+                    match source.get(key) {
+                        Some(Value::String(s)) => Ok(s.to_string()),
+                        Some(_) => Err(format!("{} has to be a string", key)),
+                        None => default.map(|d| d.to_string()).ok_or_else(||
+                            format!("source {:?} is missing {:?} entry", name, key)),
+                    }
+                };
+                let url = expand_variables(&get_str("url", None)?, |n| variable(n, secrets))
+                    .map_err(|e| format!("source {:?}: url: {}", name, e))?;
+                let params = WmsParams {
+                    layers: get_str("layers", None)?,
+                    styles: get_str("styles", Some(""))?,
+                    format: get_str("format", Some("image/png"))?,
+                    crs,
+                    tile_size,
+                };
+                UrlTemplate::wms(&url, subdomains, &params)?
+            },
+            Value::String(kind) => {
+                return Err(format!("kind = {:?} is invalid, has to be \"xyz\", \"wms\", \"mbtiles\" or \"directory\"", kind));
+            },
+            _ => return Err("kind has to be a string".to_string()),
+        };
+
+        let times = parse_times(source)?;
+        match (url_template.has_time_placeholder(), times.is_empty()) {
+            (true, true) => {
+                return Err(format!("source {:?} has a {{time}} placeholder but no \"times\" or \"time_range\" entry", name));
+            },
+            (false, false) => {
+                return Err(format!("source {:?} has time steps but no {{time}} placeholder", name));
+            },
+            _ => {},
+        }
+
+        let mut headers = HeaderMap::new();
+        match source.get("headers") {
+            Some(Value::Table(table)) => {
+                for (key, value) in table {
+                    let header_name = HeaderName::from_bytes(key.as_bytes())
+                        .map_err(|e| format!("invalid header name {:?}: {}", key, e))?;
+                    let value = value.as_str()
+                        .ok_or_else(|| format!("value of header {:?} has to be a string", key))?;
+                    let value = expand_variables(value, |n| variable(n, secrets))
+                        .map_err(|e| format!("source {:?}: header {:?}: {}", name, key, e))?;
+                    let value = HeaderValue::from_str(&value)
+                        .map_err(|e| format!("invalid value for header {:?}: {}", key, e))?;
+                    headers.insert(header_name, value);
+                }
+            },
+            Some(_) => return Err("headers has to be a table".to_string()),
+            None => {},
+        }
+
+        // A source may override the global user agent.
+        if !headers.contains_key(USER_AGENT) {
+            headers.insert(USER_AGENT, self.user_agent.clone());
+        }
+
+        let extension = source.get("extension")
+            .ok_or_else(|| format!("source {:?} is missing \"extension\" entry", name))?
+            .as_str()
+            .ok_or_else(|| "extension has to be a string".to_string())?;
+
+        let mut path = PathBuf::from(&self.tile_cache_dir);
+        path.push(name);
+
+        let tile_source = TileSource::new(
+            id,
+            url_template,
+            path,
+            extension.to_string(),
+            min_zoom,
+            max_zoom,
+        )
+        .with_scheme(scheme)
+        .with_tile_size(tile_size)
+        .with_headers(headers)
+        .with_info(parse_info(source)?);
+
+        let tile_source = if times.is_empty() {
+            tile_source
+        } else {
+            tile_source.with_times(times)
+        };
+
+        let tile_source = match source.get("max_age") {
+            Some(Value::Integer(max_age)) if *max_age >= 0 => tile_source.with_max_age(*max_age as u64),
+            Some(_) => return Err("max_age has to be a non-negative integer (seconds)".to_string()),
+            None => tile_source,
+        };
+
+        let tile_source = tile_source.with_download_limits(DownloadLimits {
+            workers: parse_download_workers(source.get("download_workers"))?,
+            max_in_flight: parse_positive_integer(source.get("max_in_flight"), "max_in_flight")?,
+        });
+
+        let tile_source = match parse_cache_size(source.get("max_cache_size"), "max_cache_size")? {
+            Some(max_cache_size) => tile_source.with_max_cache_size(max_cache_size),
+            None => tile_source,
+        };
+
+        let tile_source = apply_common_options(tile_source, source)?;
+        Ok((name.to_string(), tile_source))
+    }
+
+    /// Adds the sources of a TileJSON file (`*.json`) or of a JOSM imagery or QGIS XYZ
+    /// connections file (`*.xml`). Sources that cannot be converted or parsed are skipped with a
+    /// warning and are returned by `invalid_sources`.
+    fn import_tile_sources(&mut self, path: &Path, secrets: &HashMap<String, String>) -> Result<(), String> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|e| format!("could not read {:?}, {}", path, e))?;
+
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        let sources: Vec<ImportedSource> = match extension.as_deref() {
+            Some("json") => {
+                let default_name = path.file_stem().and_then(|n| n.to_str()).unwrap_or("tilejson");
+                source_import::from_tilejson(&content, default_name)
+            },
+            Some("xml") => source_import::from_imagery_xml(&content),
+            _ => Err("has to be a TileJSON file (*.json) or an imagery XML file (*.xml)".to_string()),
+        }
+        .map_err(|e| format!("could not import {:?}, {}", path, e))?;
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for source in sources {
+            let id = self.sources.len() as u32;
+            let result = source.and_then(|source| {
+                self.parse_tile_source(id, &source, secrets, base_dir).map_err(|err| {
+                    let name = source.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+                    (name.to_string(), err)
+                })
+            });
+            match result {
+                Ok(named_source) => self.sources.push(named_source),
+                Err((name, err)) => {
+                    warn!("skip tile source {:?} of {:?}, {}", name, path, err);
+                    self.invalid_sources.push((name, err));
+                },
+            }
+        }
+
+        self.imported_file_paths.push(path.to_path_buf());
+        Ok(())
+    }
+
     fn add_tile_sources_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let is_import = match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension.eq_ignore_ascii_case("json") || extension.eq_ignore_ascii_case("xml"),
+            None => false,
+        };
+        if is_import {
+            self.import_tile_sources(path.as_ref(), &HashMap::new())?;
+            self.tile_sources_file_path = Some(path.as_ref().to_path_buf());
+            return Ok(());
+        }
+
         let mut file = File::open(&path).map_err(|e| format!("{}", e))?;
 
         let mut content = String::new();
@@ -390,6 +684,18 @@ impl Config {
         self.add_tile_sources_from_str(&content, Some(path))
     }
 
+    /// Returns the paths of the configuration files that were loaded.
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.config_file_path.iter()
+            .chain(self.tile_sources_file_path.iter())
+            .chain(self.imported_file_paths.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        // A tile sources file may also be an imported file.
+        paths.dedup();
+        paths
+    }
+
     pub fn list_paths(&self) {
         let config = match self.config_file_path.as_ref() {
             Some(path) => format!("{:?}", path),
@@ -418,10 +724,97 @@ impl Config {
         );
     }
 
+    /// Prints the name, zoom range, attribution, description and license of each tile source.
+    pub fn list_sources(&self) {
+        for (name, source) in &self.sources {
+            println!("{}", name);
+            println!("    zoom levels: {}-{}", source.min_tile_zoom(), source.max_tile_zoom());
+            if let Some(overlay) = source.overlay() {
+                println!(
+                    "    overlay:     opacity {}{}",
+                    overlay.opacity,
+                    if overlay.visible { "" } else { ", hidden" },
+                );
+            }
+
+            let info = source.info();
+            for (label, value) in &[
+                ("attribution", &info.attribution),
+                ("description", &info.description),
+                ("license", &info.license),
+            ] {
+                if let Some(value) = value {
+                    println!("    {:12} {}", format!("{}:", label), value);
+                }
+            }
+        }
+    }
+
+    pub fn tile_cache_dir(&self) -> &Path {
+        &self.tile_cache_dir
+    }
+
+    pub fn max_cache_size(&self) -> Option<u64> {
+        self.max_cache_size
+    }
+
+    pub fn memory_cache_size(&self) -> Option<usize> {
+        self.memory_cache_size
+    }
+
+    /// Returns the size limits of the tile cache, first the limits of individual sources and then
+    /// the limit of the whole cache directory.
+    pub fn cache_limits(&self) -> Vec<CacheLimit> {
+        self.sources.iter()
+            .filter_map(|(_, source)| {
+                Some(CacheLimit {
+                    path: source.cache_directory()?.to_path_buf(),
+                    tile_dirs: source.cache_tile_directories(),
+                    max_size: source.max_cache_size()?,
+                })
+            })
+            .chain(self.max_cache_size.map(|max_size| {
+                CacheLimit {
+                    path: self.tile_cache_dir.clone(),
+                    tile_dirs: self.cache_tile_directories(),
+                    max_size,
+                }
+            }))
+            .collect()
+    }
+
+    /// Returns the tile directories of all sources that are downloaded into the tile cache.
+    pub fn cache_tile_directories(&self) -> Vec<PathBuf> {
+        self.sources.iter()
+            .flat_map(|(_, source)| source.cache_tile_directories())
+            .collect()
+    }
+
     pub fn tile_sources(&self) -> &[(String, TileSource)] {
         &self.sources
     }
 
+    /// Returns the tile source with the given name.
+    pub fn tile_source(&self, name: &str) -> Result<&TileSource, String> {
+        self.sources.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, source)| source)
+            .ok_or_else(|| format!("there is no tile source named {:?}", name))
+    }
+
+    /// Returns the names of the tile sources that were skipped and the reasons why they could not
+    /// be parsed. These are imported sources and, with `from_arg_matches_lenient`, all others.
+    pub fn invalid_sources(&self) -> &[(String, String)] {
+        &self.invalid_sources
+    }
+
+    /// Choose the resolution of tiles for all sources that support high-DPI tiles.
+    pub fn set_dpi_factor(&mut self, dpi_factor: f64) {
+        for (_, source) in &mut self.sources {
+            source.set_dpi_factor(dpi_factor);
+        }
+    }
+
     pub fn pbf_path(&self) -> Option<&Path> {
         self.pbf_path.as_ref().map(|p| p.as_path())
     }
@@ -505,6 +898,14 @@ impl Config {
         self.use_network
     }
 
+    pub fn download_settings(&self) -> &DownloadSettings {
+        &self.download
+    }
+
+    pub fn prefetch_settings(&self) -> PrefetchSettings {
+        self.prefetch
+    }
+
     pub fn async(&self) -> bool {
         self.async
     }
@@ -535,6 +936,409 @@ fn create_config_file<P: AsRef<Path> + Debug>(dir_path: P, file_path: P, content
         ))
 }
 
+/// Parses an optional zoom level entry of a tile source.
+fn parse_zoom(source: &Value, key: &str) -> Result<Option<u32>, String> {
+    match source.get(key) {
+        Some(Value::Integer(m)) => {
+            if *m < 0 || *m > 30 {
+                Err(format!("{} = {} is out of bounds, has to be in interval [0, 30]", key, m))
+            } else {
+                Ok(Some(*m as u32))
+            }
+        },
+        Some(_) => Err(format!("{} has to be an integer", key)),
+        None => Ok(None),
+    }
+}
+
+fn check_zoom_range(min_zoom: u32, max_zoom: u32) {
+    if min_zoom > max_zoom {
+        warn!("min_zoom ({}) and max_zoom ({}) allow no valid tiles", min_zoom, max_zoom);
+    } else if min_zoom == max_zoom {
+        warn!("min_zoom ({}) and max_zoom ({}) allow only one zoom level", min_zoom, max_zoom);
+    }
+}
+
+fn parse_tile_size(source: &Value) -> Result<u32, String> {
+    source.get("tile_size")
+        .unwrap_or(&Value::Integer(256))
+        .as_integer()
+        .ok_or_else(|| "tile_size has to be an integer".to_string())
+        .and_then(|t| {
+            if !(1..=4096).contains(&t) {
+                Err(format!("tile_size = {} is out of bounds, has to be in interval [1, 4096]", t))
+            } else {
+                Ok(t as u32)
+            }
+        })
+}
+
+fn parse_scheme(source: &Value) -> Result<TileScheme, String> {
+    match source.get("scheme") {
+        Some(Value::String(s)) => {
+            s.parse::<TileScheme>()
+                .map_err(|_| format!("scheme = {:?} is invalid, has to be \"xyz\" or \"tms\"", s))
+        },
+        Some(_) => Err("scheme has to be a string".to_string()),
+        None => Ok(TileScheme::Xyz),
+    }
+}
+
+/// Parses the value of an optional integer entry that has to be at least one.
+fn parse_positive_integer(value: Option<&Value>, key: &str) -> Result<Option<usize>, String> {
+    match value {
+        Some(Value::Integer(i)) if *i >= 1 => Ok(Some(*i as usize)),
+        Some(_) => Err(format!("{} has to be a positive integer", key)),
+        None => Ok(None),
+    }
+}
+
+/// Parses the value of the optional number of download threads.
+fn parse_download_workers(value: Option<&Value>) -> Result<Option<usize>, String> {
+    match parse_positive_integer(value, "download_workers")? {
+        Some(workers) if workers > 64 => {
+            Err(format!("download_workers = {} is out of bounds, has to be in interval [1, 64]", workers))
+        },
+        workers => Ok(workers),
+    }
+}
+
+/// Parses the global `download_workers` entry and the `rate_limits` table, which maps host names
+/// to the maximum number of requests per second.
+fn parse_download_settings(table: &Table) -> Result<DownloadSettings, String> {
+    let mut settings = DownloadSettings::default();
+
+    if let Some(workers) = parse_download_workers(table.get("download_workers"))? {
+        settings.workers = workers;
+    }
+
+    match table.get("rate_limits") {
+        Some(Value::Table(limits)) => {
+            for (host, rate) in limits {
+                let rate = match *rate {
+                    Value::Float(f) => f,
+                    Value::Integer(i) => i as f64,
+                    _ => return Err(format!("rate limit of {:?} has to be a number", host)),
+                };
+                if rate.is_nan() || rate <= 0.0 {
+                    return Err(format!("rate limit of {:?} has to be a positive number of requests per second", host));
+                }
+                settings.rate_limits.insert(host.to_string(), rate);
+            }
+        },
+        Some(_) => return Err("rate_limits has to be a table".to_string()),
+        None => {},
+    }
+
+    Ok(settings)
+}
+
+/// Parses the `prefetch_margin` and `prefetch_zoom_levels` entries.
+fn parse_prefetch_settings(table: &Table) -> Result<PrefetchSettings, String> {
+    let mut settings = PrefetchSettings::default();
+
+    match table.get("prefetch_margin") {
+        Some(Value::Integer(margin)) if *margin >= 0 && *margin <= 4 => settings.margin = *margin as u32,
+        Some(_) => return Err("prefetch_margin has to be an integer in the interval [0, 4]".to_string()),
+        None => {},
+    }
+
+    match table.get("prefetch_zoom_levels") {
+        Some(&Value::Boolean(x)) => settings.adjacent_zoom_levels = x,
+        Some(_) => return Err("prefetch_zoom_levels has to be a boolean.".to_string()),
+        None => {},
+    }
+
+    Ok(settings)
+}
+
+/// Parses an optional size of a tile cache, either as a number of bytes or as a string with a
+/// unit like "500 MB".
+fn parse_cache_size(value: Option<&Value>, key: &str) -> Result<Option<u64>, String> {
+    match value {
+        Some(Value::Integer(i)) if *i >= 0 => Ok(Some(*i as u64)),
+        Some(Value::String(s)) => {
+            size::parse_size(s).map(Some).map_err(|e| format!("{}: {}", key, e))
+        },
+        Some(_) => Err(format!("{} has to be a non-negative integer (bytes) or a string like \"500 MB\"", key)),
+        None => Ok(None),
+    }
+}
+
+/// Parses the optional `attribution`, `description` and `license` entries.
+fn parse_info(source: &Value) -> Result<SourceInfo, String> {
+    let get = |key: &str| {
+        match source.get(key) {
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("{} has to be a string", key)),
+            None => Ok(None),
+        }
+    };
+
+    Ok(SourceInfo {
+        attribution: get("attribution")?,
+        description: get("description")?,
+        license: get("license")?,
+    })
+}
+
+/// Parses the timestamps for the `{time}` placeholder, either from a `times` array or from a
+/// `time_range = { start, end, step }` table. The start and end of a range are RFC 3339
+/// timestamps or seconds since the Unix epoch, and the generated timestamps use the same format.
+/// The step is a number of seconds or a duration like "10min". Returns an empty list if there is
+/// no time dimension.
+fn parse_times(source: &Value) -> Result<Vec<String>, String> {
+    const MAX_TIME_STEPS: u64 = 1000;
+
+    match (source.get("times"), source.get("time_range")) {
+        (Some(_), Some(_)) => Err("only one of times and time_range may be given".to_string()),
+        (Some(Value::Array(array)), None) if !array.is_empty() => {
+            array.iter()
+                .map(|t| match t {
+                    Value::String(s) => Ok(s.clone()),
+                    Value::Integer(i) => Ok(i.to_string()),
+                    _ => Err("times has to be an array of strings or integers".to_string()),
+                })
+                .collect()
+        },
+        (Some(_), None) => Err("times has to be a non-empty array of strings or integers".to_string()),
+        (None, Some(Value::Table(range))) => {
+            let parse_time = |key: &str| match range.get(key) {
+                Some(Value::Integer(secs)) if *secs >= 0 => Ok((*secs as u64, false)),
+                Some(Value::String(s)) => {
+                    humantime::parse_rfc3339_weak(s)
+                        .map_err(|e| format!("time_range.{} = {:?} is invalid, {}", key, s, e))
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).map_err(|e| e.to_string()))
+                        .map(|d| (d.as_secs(), true))
+                },
+                _ => Err(format!("time_range.{} has to be an RFC 3339 timestamp or a non-negative integer", key)),
+            };
+            let (start, rfc3339) = parse_time("start")?;
+            let (end, _) = parse_time("end")?;
+            let step = match range.get("step") {
+                Some(Value::Integer(secs)) if *secs > 0 => *secs as u64,
+                Some(Value::String(s)) => {
+                    humantime::parse_duration(s)
+                        .map_err(|e| format!("time_range.step = {:?} is invalid, {}", s, e))?
+                        .as_secs()
+                },
+                _ => return Err("time_range.step has to be a positive integer (seconds) or a duration like \"10min\"".to_string()),
+            };
+
+            if step == 0 || end < start {
+                return Err("time_range needs a step of at least one second and an end after the start".to_string());
+            }
+            if (end - start) / step >= MAX_TIME_STEPS {
+                return Err(format!("time_range has more than {} time steps", MAX_TIME_STEPS));
+            }
+
+            Ok((0..=(end - start) / step)
+                .map(|i| {
+                    let secs = start + i * step;
+                    if rfc3339 {
+                        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+                    } else {
+                        secs.to_string()
+                    }
+                })
+                .collect())
+        },
+        (None, Some(_)) => Err("time_range has to be a table with start, end and step".to_string()),
+        (None, None) => Ok(vec![]),
+    }
+}
+
+/// Applies the entries that are valid for all kinds of sources and override values from other
+/// places, e.g. the metadata of MBTiles files.
+fn apply_common_options(mut tile_source: TileSource, source: &Value) -> Result<TileSource, String> {
+    if let Some(overlay) = parse_overlay(source)? {
+        tile_source = tile_source.with_overlay(overlay);
+    }
+    if let Some(bounds) = parse_bounds(source)? {
+        tile_source = tile_source.with_bounds(bounds);
+    }
+    Ok(tile_source)
+}
+
+/// Parses the optional `bounds = [west, south, east, north]` entry.
+fn parse_bounds(source: &Value) -> Result<Option<BoundingBox>, String> {
+    let array = match source.get("bounds") {
+        Some(Value::Array(array)) => array,
+        Some(_) => return Err("bounds has to be an array".to_string()),
+        None => return Ok(None),
+    };
+
+    let values = array.iter()
+        .map(|v| {
+            match *v {
+                Value::Float(f) => Some(f),
+                Value::Integer(i) => Some(i as f64),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<f64>>>();
+
+    match values.as_deref() {
+        Some(&[west, south, east, north]) => {
+            BoundingBox::try_new(west, south, east, north)
+                .map(Some)
+                .map_err(|e| format!("bounds = {:?} are invalid, {}", array, e))
+        },
+        _ => Err("bounds has to be an array of four numbers [west, south, east, north]".to_string()),
+    }
+}
+
+/// Parses the `overlay`, `opacity` and `visible` entries. Returns `None` if the source is not an
+/// overlay.
+fn parse_overlay(source: &Value) -> Result<Option<OverlaySettings>, String> {
+    let is_overlay = match source.get("overlay") {
+        Some(Value::Boolean(b)) => *b,
+        Some(_) => return Err("overlay has to be a boolean".to_string()),
+        None => false,
+    };
+
+    if !is_overlay {
+        if source.get("opacity").is_some() || source.get("visible").is_some() {
+            return Err("opacity and visible are only allowed for sources with overlay = true".to_string());
+        }
+        return Ok(None);
+    }
+
+    let mut settings = OverlaySettings::default();
+
+    match source.get("opacity") {
+        Some(Value::Float(f)) if *f >= 0.0 && *f <= 1.0 => settings.opacity = *f as f32,
+        Some(Value::Integer(i)) if *i == 0 || *i == 1 => settings.opacity = *i as f32,
+        Some(_) => return Err("opacity has to be a number in the interval [0.0, 1.0]".to_string()),
+        None => {},
+    }
+
+    match source.get("visible") {
+        Some(Value::Boolean(b)) => settings.visible = *b,
+        Some(_) => return Err("visible has to be a boolean".to_string()),
+        None => {},
+    }
+
+    Ok(Some(settings))
+}
+
+/// Creates a tile source of kind "mbtiles" or "directory" that reads tiles from the file or
+/// directory at `path`. Zoom levels and bounds of MBTiles files are read from the metadata unless
+/// they are given in the source definition.
+fn local_source(
+    id: u32,
+    name: &str,
+    source: &Value,
+    secrets: &HashMap<String, String>,
+    base_dir: &Path,
+) -> Result<TileSource, String> {
+    let path = source.get("path")
+        .ok_or_else(|| format!("source {:?} is missing \"path\" entry", name))?
+        .as_str()
+        .ok_or_else(|| "path has to be a string".to_string())?;
+    let path = expand_variables(path, |n| variable(n, secrets))
+        .map_err(|e| format!("source {:?}: path: {}", name, e))?;
+    let path = base_dir.join(path);
+
+    if source.get("kind").and_then(|k| k.as_str()) == Some("directory") {
+        let min_zoom = parse_zoom(source, "min_zoom")?.unwrap_or(0);
+        let max_zoom = parse_zoom(source, "max_zoom")?
+            .ok_or_else(|| format!("source {:?} is missing \"max_zoom\" entry", name))?;
+        check_zoom_range(min_zoom, max_zoom);
+
+        let extension = source.get("extension")
+            .ok_or_else(|| format!("source {:?} is missing \"extension\" entry", name))?
+            .as_str()
+            .ok_or_else(|| "extension has to be a string".to_string())?;
+
+        return Ok(
+            TileSource::from_directory(id, path, extension.to_string(), min_zoom, max_zoom)
+                .with_scheme(parse_scheme(source)?)
+                .with_tile_size(parse_tile_size(source)?)
+                .with_info(parse_info(source)?)
+        );
+    }
+
+    let metadata = MbTiles::open(&path)?.metadata()?;
+
+    if metadata.format.as_deref() == Some("pbf") {
+        return Err(format!("source {:?}: vector tiles are not supported", name));
+    }
+
+    let min_zoom = parse_zoom(source, "min_zoom")?.or(metadata.min_zoom).unwrap_or(0);
+    let max_zoom = parse_zoom(source, "max_zoom")?.or(metadata.max_zoom)
+        .ok_or_else(|| format!("source {:?}: MBTiles file contains no tiles", name))?;
+    check_zoom_range(min_zoom, max_zoom);
+
+    let mut info = parse_info(source)?;
+    info.attribution = info.attribution.or(metadata.attribution);
+    info.description = info.description.or(metadata.description);
+
+    let mut tile_source = TileSource::from_mbtiles(id, path, min_zoom, max_zoom)
+        .with_tile_size(parse_tile_size(source)?)
+        .with_info(info);
+    if let Some(bounds) = metadata.bounds {
+        tile_source = tile_source.with_bounds(bounds);
+    }
+    Ok(tile_source)
+}
+
+/// Reads a TOML file with a table of secret values, e.g. API keys.
+fn read_secrets_file<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, String> {
+    let mut file = File::open(&path)
+        .map_err(|e| format!("secrets file ({:?}): {}", path.as_ref(), e))?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .map_err(|e| format!("secrets file ({:?}): {}", path.as_ref(), e))?;
+
+    match content.parse::<Value>() {
+        Ok(Value::Table(table)) => {
+            table.into_iter()
+                .map(|(k, v)| match v {
+                    Value::String(s) => Ok((k, s)),
+                    _ => Err(format!("secrets file ({:?}): {:?} has to be a string.", path.as_ref(), k)),
+                })
+                .collect()
+        },
+        Ok(_) => Err(format!("secrets file ({:?}) has invalid structure. Expected a Table as the top-level element.", path.as_ref())),
+        Err(e) => Err(format!("secrets file ({:?}): {}", path.as_ref(), e)),
+    }
+}
+
+/// Returns the value of the environment variable `name` or, if it is not set, the value of `name`
+/// in `secrets`.
+fn variable(name: &str, secrets: &HashMap<String, String>) -> Option<String> {
+    env::var(name).ok().or_else(|| secrets.get(name).cloned())
+}
+
+/// Replaces each `${NAME}` in the given string with the value that `lookup` returns for `NAME`.
+fn expand_variables<F>(s: &str, lookup: F) -> Result<String, String>
+    where F: Fn(&str) -> Option<String>
+{
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    }
+
+    let mut missing = None;
+
+    let expanded = RE.replace_all(s, |cap: &Captures| {
+        let name = &cap[1];
+        match lookup(name) {
+            Some(value) => value,
+            None => {
+                missing.get_or_insert_with(|| name.to_string());
+                String::new()
+            },
+        }
+    });
+
+    match missing {
+        Some(name) => Err(format!("variable ${{{}}} is neither set in the environment nor in a secrets file", name)),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
 pub fn read_last_session() -> Result<Session, String> {
     let session_path = {
         let config_dir = proj_dirs_result()?.config_dir();
@@ -561,10 +1365,581 @@ pub fn save_session(session: &Session) -> Result<(), String>
 #[cfg(test)]
 mod tests {
     use config::*;
+    use coord::TileCoord;
+    use test_util::TempDir;
 
     #[test]
     fn default_config() {
         let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
         config.add_tile_sources_from_str::<&str>(DEFAULT_TILE_SOURCES, None).unwrap();
     }
+
+    #[test]
+    fn subdomains() {
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://{s}.tile.example.com/{z}/{x}/{y}.png"
+            subdomains = ["a", "b", "c"]
+            extension = "png"
+        "#;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+
+        let missing = r#"
+            [[tile_sources]]
+            name = "b"
+            max_zoom = 19
+            url_template = "https://{s}.tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+        "#;
+        assert!(config.add_tile_sources_from_str::<&str>(missing, None).is_err());
+    }
+
+    #[test]
+    fn skip_invalid_sources() {
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}.png"
+            extension = "png"
+
+            [[tile_sources]]
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+
+            [[tile_sources]]
+            name = "c"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+
+            [[tile_sources]]
+            name = "c"
+            max_zoom = 18
+            url_template = "https://other.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(sources, None).is_err());
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.skip_invalid_sources = true;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        assert_eq!(config.tile_sources().len(), 1);
+        assert_eq!(config.tile_sources()[0].0, "c");
+        let names: Vec<_> = config.invalid_sources().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["a", "tile source 2", "c"]);
+        assert_eq!(config.tile_sources()[0].1.max_tile_zoom(), 19);
+    }
+
+    #[test]
+    fn source_names() {
+        for (name, valid) in &[("osm", true), ("..a", true), ("", false), (".", false), ("..", false), ("a/b", false)] {
+            let sources = format!(
+                "[[tile_sources]]\nname = {:?}\nmax_zoom = 1\nurl_template = \"{{z}}/{{x}}/{{y}}\"\nextension = \"png\"",
+                name,
+            );
+            let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+            assert_eq!(config.add_tile_sources_from_str::<&str>(&sources, None).is_ok(), *valid, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn headers() {
+        let mut config = Config::from_toml_str::<&str>("user_agent = \"test agent\"", None).unwrap();
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+
+            [[tile_sources]]
+            name = "b"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            headers = { Referer = "https://example.com/", User-Agent = "other agent" }
+        "#;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+
+        let a = config.tile_sources()[0].1.headers();
+        assert_eq!(a.get(USER_AGENT).unwrap(), "test agent");
+        assert_eq!(a.len(), 1);
+
+        let b = config.tile_sources()[1].1.headers();
+        assert_eq!(b.get(USER_AGENT).unwrap(), "other agent");
+        assert_eq!(b.get("referer").unwrap(), "https://example.com/");
+    }
+
+    #[test]
+    fn wms() {
+        let sources = r#"
+            [[tile_sources]]
+            name = "gis"
+            kind = "wms"
+            max_zoom = 19
+            url = "https://gis.example.com/wms"
+            layers = "imagery"
+            crs = "EPSG:4326"
+            extension = "png"
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        let url = config.tile_sources()[0].1.remote_tile_url(TileCoord::new(1, 1, 0)).unwrap();
+        assert!(url.starts_with("https://gis.example.com/wms?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap\
+            &LAYERS=imagery&STYLES=&FORMAT=image%2Fpng&CRS=EPSG:4326&WIDTH=256&HEIGHT=256&BBOX=0,0,"));
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(
+            &sources.replace("layers = \"imagery\"", ""), None).is_err());
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(
+            &sources.replace("wms", "wmts"), None).is_err());
+    }
+
+    #[test]
+    fn mbtiles() {
+        let dir = TempDir::new("deltamap_test_config");
+        let path = ::mbtiles::tests::create_mbtiles(
+            &dir,
+            "config.mbtiles",
+            &[("format", "png"), ("minzoom", "2"), ("maxzoom", "12"), ("bounds", "5.8,47.2,15.1,55.1")],
+            &[],
+        );
+        let sources = format!(r#"
+            [[tile_sources]]
+            name = "offline"
+            kind = "mbtiles"
+            path = {:?}
+            max_zoom = 10
+        "#, path.file_name().unwrap());
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str(&sources, Some(path.with_file_name("tile_sources.toml"))).unwrap();
+        let source = &config.tile_sources()[0].1;
+        assert_eq!(source.mbtiles_path(), Some(path.as_path()));
+        assert_eq!(source.min_tile_zoom(), 2);
+        assert_eq!(source.max_tile_zoom(), 10);
+        assert!(source.contains_tile(TileCoord::new(2, 2, 1)));
+        assert!(!source.contains_tile(TileCoord::new(2, 0, 0)));
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err());
+    }
+
+    #[test]
+    fn directory() {
+        let sources = r#"
+            [[tile_sources]]
+            name = "local"
+            kind = "directory"
+            path = "tiles"
+            scheme = "tms"
+            max_zoom = 12
+            extension = "jpg"
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str(sources, Some("/data/tile_sources.toml")).unwrap();
+        let source = &config.tile_sources()[0].1;
+        assert_eq!(source.local_tile_path(TileCoord::new(2, 1, 0)), PathBuf::from("/data/tiles/2/1/3.jpg"));
+        assert_eq!(source.remote_tile_url(TileCoord::new(2, 1, 0)), None);
+        assert!(!source.provider().is_remote());
+
+        for key_value in &["max_age = 60", "headers = { Referer = \"x\" }", "download_workers = 2", "times = [\"a\"]"] {
+            let sources = format!("{}\n{}", sources, key_value);
+            let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+            assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err(), "{}", key_value);
+        }
+
+        let sources = sources.replace("\"local\"", "\"a/b\"");
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err());
+    }
+
+    #[test]
+    fn variables() {
+        let mut variables = HashMap::new();
+        variables.insert("API_KEY".to_string(), "abc".to_string());
+        variables.insert("TOKEN".to_string(), "xyz".to_string());
+        let lookup = |name: &str| variables.get(name).cloned();
+
+        assert_eq!(expand_variables("no variables", lookup), Ok("no variables".to_string()));
+        assert_eq!(
+            expand_variables("https://example.com/{z}/{x}/{y}.png?key=${API_KEY}", lookup),
+            Ok("https://example.com/{z}/{x}/{y}.png?key=abc".to_string())
+        );
+        assert_eq!(
+            expand_variables("Bearer ${TOKEN} ${TOKEN}", lookup),
+            Ok("Bearer xyz xyz".to_string())
+        );
+        assert!(expand_variables("${MISSING}", lookup).is_err());
+    }
+
+    #[test]
+    fn secrets_file() {
+        let dir = TempDir::new("deltamap_test_secrets");
+        ::std::fs::write(dir.join("secrets.toml"), b"API_KEY = \"s3cr3t\"").unwrap();
+
+        let sources = r#"
+            secrets_file = "secrets.toml"
+
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png?key=${API_KEY}"
+            extension = "png"
+            headers = { Authorization = "Key ${API_KEY}" }
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str(sources, Some(dir.join("tile_sources.toml"))).unwrap();
+        let source = &config.tile_sources()[0].1;
+        assert_eq!(
+            source.remote_tile_url(TileCoord::new(1, 0, 1)),
+            Some("https://tile.example.com/1/0/1.png?key=s3cr3t".to_string())
+        );
+        assert_eq!(source.headers().get("authorization").unwrap(), "Key s3cr3t");
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        let err = config.add_tile_sources_from_str::<&str>(sources, None).unwrap_err();
+        assert!(err.contains("secrets.toml"));
+    }
+
+    #[test]
+    fn import() {
+        let dir = TempDir::new("deltamap_test_import");
+        ::std::fs::write(dir.join("secrets.toml"), b"API_KEY = \"s3cr3t\"").unwrap();
+        ::std::fs::write(dir.join("topo.json"), br#"{
+            "tiles": ["https://topo.example.com/{z}/{x}/{y}.png?key=${API_KEY}"],
+            "maxzoom": 16,
+            "bounds": [5.8, 47.2, 15.1, 55.1],
+            "attribution": "Topo"
+        }"#).unwrap();
+        ::std::fs::write(dir.join("imagery.xml"), br#"<imagery>
+            <entry>
+                <name>Imagery</name>
+                <url>https://{switch:a,b}.imagery.example.com/{zoom}/{x}/{y}.jpg</url>
+            </entry>
+            <entry>
+                <name>With API key</name>
+                <url>https://imagery.example.com/{zoom}/{x}/{y}.jpg?key={apikey}</url>
+            </entry>
+            <entry>
+                <name>native</name>
+                <url>https://native.example.com/{zoom}/{x}/{y}.jpg</url>
+            </entry>
+            <entry>
+                <name>..</name>
+                <url>https://parent.example.com/{zoom}/{x}/{y}.jpg</url>
+            </entry>
+        </imagery>"#).unwrap();
+
+        let sources = r#"
+            secrets_file = "secrets.toml"
+            import = ["topo.json", "imagery.xml"]
+
+            [[tile_sources]]
+            name = "native"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+        "#;
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str(sources, Some(dir.join("tile_sources.toml"))).unwrap();
+
+        let names: Vec<_> = config.tile_sources().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["native", "topo", "Imagery"]);
+        let ids: Vec<_> = config.tile_sources().iter().map(|(_, s)| s.id().value()).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+
+        let topo = &config.tile_sources()[1].1;
+        assert_eq!(
+            topo.remote_tile_url(TileCoord::new(1, 0, 1)),
+            Some("https://topo.example.com/1/0/1.png?key=s3cr3t".to_string())
+        );
+        assert_eq!(topo.max_tile_zoom(), 16);
+        assert_eq!(topo.bounds(), Some(BoundingBox::new(5.8, 47.2, 15.1, 55.1)));
+        assert_eq!(topo.info().attribution, Some("Topo".to_string()));
+
+        let imagery = &config.tile_sources()[2].1;
+        assert_eq!(imagery.extension(), "jpg");
+        assert!(imagery.remote_tile_url(TileCoord::new(2, 1, 1)).unwrap().ends_with(".imagery.example.com/2/1/1.jpg"));
+
+        assert_eq!(config.invalid_sources().len(), 3);
+        assert_eq!(config.invalid_sources()[0].0, "With API key");
+        assert_eq!(config.invalid_sources()[1].0, "native");
+        assert!(config.invalid_sources()[1].1.contains("already a tile source named"));
+        assert_eq!(config.invalid_sources()[2].0, "..");
+        assert!(config.file_paths().contains(&dir.join("imagery.xml")));
+
+        // Imported files can also be used directly as the tile sources file. There are no
+        // secrets without a tile_sources.toml file.
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_file(dir.join("topo.json")).unwrap();
+        assert!(config.tile_sources().is_empty());
+        assert!(config.invalid_sources()[0].1.contains("API_KEY"));
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_file(dir.join("imagery.xml")).unwrap();
+        assert_eq!(config.tile_sources().len(), 2);
+        assert_eq!(config.file_paths(), vec![dir.join("imagery.xml")]);
+    }
+
+    #[test]
+    fn overlay() {
+        let sources = r#"
+            [[tile_sources]]
+            name = "base"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+
+            [[tile_sources]]
+            name = "hiking"
+            max_zoom = 17
+            url_template = "https://hiking.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            overlay = true
+            opacity = 0.6
+            visible = false
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        assert_eq!(config.tile_sources()[0].1.overlay(), None);
+        assert_eq!(
+            config.tile_sources()[1].1.overlay(),
+            Some(OverlaySettings { opacity: 0.6, visible: false })
+        );
+
+        let invalid = [
+            "overlay = true\nopacity = 1.5",
+            "overlay = \"yes\"",
+            "opacity = 0.5",
+        ];
+        for entries in &invalid {
+            let sources = format!(
+                "[[tile_sources]]\nname = \"a\"\nmax_zoom = 1\nurl_template = \"{{z}}/{{x}}/{{y}}\"\nextension = \"png\"\n{}",
+                entries,
+            );
+            let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+            assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err());
+        }
+    }
+
+    #[test]
+    fn info() {
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str::<&str>(DEFAULT_TILE_SOURCES, None).unwrap();
+        let info = config.tile_sources()[0].1.info();
+        assert_eq!(info.attribution, Some("© OpenStreetMap contributors".to_string()));
+
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            attribution = 42
+        "#;
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert!(config.add_tile_sources_from_str::<&str>(sources, None).is_err());
+    }
+
+    #[test]
+    fn download_settings() {
+        let config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert_eq!(*config.download_settings(), DownloadSettings::default());
+
+        let config_str = r#"
+            download_workers = 4
+
+            [rate_limits]
+            "tile.openstreetmap.org" = 2
+            "tiles.example.com" = 0.5
+        "#;
+        let mut config = Config::from_toml_str::<&str>(config_str, None).unwrap();
+        let settings = config.download_settings();
+        assert_eq!(settings.workers, 4);
+        assert_eq!(settings.rate_limits.get("tile.openstreetmap.org"), Some(&2.0));
+        assert_eq!(settings.rate_limits.get("tiles.example.com"), Some(&0.5));
+
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            download_workers = 1
+            max_in_flight = 3
+        "#;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        let limits = config.tile_sources()[0].1.download_limits();
+        assert_eq!(limits, DownloadLimits { workers: Some(1), max_in_flight: Some(3) });
+
+        for invalid in &["download_workers = 0", "download_workers = 65", "rate_limits = 2", "[rate_limits]\na = -1"] {
+            assert!(Config::from_toml_str::<&str>(invalid, None).is_err(), "{}", invalid);
+        }
+        let sources = "[[tile_sources]]\nname = \"a\"\nmax_zoom = 1\nurl_template = \"{z}/{x}/{y}\"\nextension = \"png\"\nmax_in_flight = 0";
+        assert!(config.add_tile_sources_from_str::<&str>(sources, None).is_err());
+    }
+
+    #[test]
+    fn prefetch_settings() {
+        let config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert_eq!(config.prefetch_settings(), PrefetchSettings::default());
+
+        let config_str = "prefetch_margin = 2\nprefetch_zoom_levels = false";
+        let config = Config::from_toml_str::<&str>(config_str, None).unwrap();
+        assert_eq!(config.prefetch_settings(), PrefetchSettings { margin: 2, adjacent_zoom_levels: false });
+
+        for invalid in &["prefetch_margin = -1", "prefetch_margin = 5", "prefetch_zoom_levels = 1"] {
+            assert!(Config::from_toml_str::<&str>(invalid, None).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn times() {
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        let sources = r#"
+            [[tile_sources]]
+            name = "radar"
+            max_zoom = 8
+            url_template = "https://radar.example.com/{time}/{z}/{x}/{y}.png"
+            extension = "png"
+            time_range = { start = "2021-06-01T12:00:00Z", end = "2021-06-01T12:30:00Z", step = "10min" }
+
+            [[tile_sources]]
+            name = "satellite"
+            max_zoom = 8
+            url_template = "https://sat.example.com/{z}/{x}/{y}.jpg?t={time}"
+            extension = "jpg"
+            times = [1622548800, "latest"]
+
+            [[tile_sources]]
+            name = "unix"
+            max_zoom = 8
+            url_template = "https://sat.example.com/{z}/{x}/{y}.jpg?t={time}"
+            extension = "jpg"
+            time_range = { start = 1622548800, end = 1622549400, step = 300 }
+        "#;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+
+        let times: Vec<&[String]> = config.tile_sources().iter().map(|(_, s)| s.times()).collect();
+        assert_eq!(times, vec![
+            &[
+                "2021-06-01T12:00:00Z".to_string(),
+                "2021-06-01T12:10:00Z".to_string(),
+                "2021-06-01T12:20:00Z".to_string(),
+                "2021-06-01T12:30:00Z".to_string(),
+            ][..],
+            &["1622548800".to_string(), "latest".to_string()][..],
+            &["1622548800".to_string(), "1622549100".to_string(), "1622549400".to_string()][..],
+        ]);
+        assert_eq!(
+            config.tile_sources()[0].1.remote_tile_url(TileCoord::new(1, 0, 1)),
+            Some("https://radar.example.com/2021-06-01T12:00:00Z/1/0/1.png".to_string()),
+        );
+
+        let invalid = [
+            ("{time}/{z}/{x}/{y}", ""),
+            ("{z}/{x}/{y}", "times = [\"a\"]"),
+            ("{time}/{z}/{x}/{y}", "times = []"),
+            ("{time}/{z}/{x}/{y}", "time_range = { start = 10, end = 0, step = 1 }"),
+            ("{time}/{z}/{x}/{y}", "time_range = { start = 0, end = 10, step = 0 }"),
+            ("{time}/{z}/{x}/{y}", "time_range = { start = 0, end = 100000, step = 1 }"),
+            ("{time}/{z}/{x}/{y}", "time_range = { start = \"yesterday\", end = 10, step = 1 }"),
+        ];
+        for &(url_template, times) in &invalid {
+            let sources = format!(
+                "[[tile_sources]]\nname = \"a\"\nmax_zoom = 1\nurl_template = \"{}\"\nextension = \"png\"\n{}",
+                url_template,
+                times,
+            );
+            assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err(), "{}", sources);
+        }
+    }
+
+    #[test]
+    fn cache_size() {
+        let config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        assert_eq!(config.max_cache_size(), None);
+        assert_eq!(config.cache_limits(), vec![]);
+
+        let config_str = "tile_cache_dir = \"/tmp/tiles\"\nmax_cache_size = \"2 GB\"\nmemory_cache_size = \"256 MiB\"";
+        let mut config = Config::from_toml_str::<&str>(config_str, None).unwrap();
+        assert_eq!(config.max_cache_size(), Some(2_000_000_000));
+        assert_eq!(config.memory_cache_size(), Some(256 << 20));
+
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            max_cache_size = 1000000
+
+            [[tile_sources]]
+            name = "b"
+            max_zoom = 19
+            url_template = "https://tile.example.org/{z}/{x}/{y}.png"
+            extension = "png"
+        "#;
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        assert_eq!(config.tile_sources()[0].1.max_cache_size(), Some(1_000_000));
+        assert_eq!(config.tile_sources()[1].1.max_cache_size(), None);
+        assert_eq!(config.cache_limits(), vec![
+            CacheLimit {
+                path: PathBuf::from("/tmp/tiles/a"),
+                tile_dirs: vec![PathBuf::from("/tmp/tiles/a")],
+                max_size: 1_000_000,
+            },
+            CacheLimit {
+                path: PathBuf::from("/tmp/tiles"),
+                tile_dirs: vec![PathBuf::from("/tmp/tiles/a"), PathBuf::from("/tmp/tiles/b")],
+                max_size: 2_000_000_000,
+            },
+        ]);
+
+        for invalid in &["max_cache_size = -1", "max_cache_size = \"lots\"", "max_cache_size = true", "memory_cache_size = 1.5"] {
+            assert!(Config::from_toml_str::<&str>(invalid, None).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn bounds() {
+        let sources = r#"
+            [[tile_sources]]
+            name = "a"
+            max_zoom = 19
+            url_template = "https://tile.example.com/{z}/{x}/{y}.png"
+            extension = "png"
+            bounds = [5.8, 47.2, 15, 55.1]
+        "#;
+
+        let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+        config.add_tile_sources_from_str::<&str>(sources, None).unwrap();
+        let source = &config.tile_sources()[0].1;
+        assert_eq!(source.bounds(), Some(BoundingBox::new(5.8, 47.2, 15.0, 55.1)));
+        assert!(source.contains_tile(TileCoord::new(4, 8, 5)));
+        assert!(!source.contains_tile(TileCoord::new(4, 0, 0)));
+
+        let invalid = ["[1, 2, 3]", "[15.1, 47.2, 5.8, 55.1]", "[0, -91, 1, 0]", "[\"a\", 0, 1, 1]", "\"5,6,7,8\""];
+        for bounds in &invalid {
+            let sources = format!(
+                "[[tile_sources]]\nname = \"a\"\nmax_zoom = 1\nurl_template = \"{{z}}/{{x}}/{{y}}\"\nextension = \"png\"\nbounds = {}",
+                bounds,
+            );
+            let mut config = Config::from_toml_str::<&str>(DEFAULT_CONFIG, None).unwrap();
+            assert!(config.add_tile_sources_from_str::<&str>(&sources, None).is_err(), "{}", bounds);
+        }
+    }
 }
@@ -310,6 +310,16 @@ impl TileCoord {
         TileCoord { zoom: self.zoom, x: self.x + 1, y: self.y + 1 }.latlon_rad_north_west()
     }
 
+    /// Returns the tile coordinate with a flipped y-axis. This converts between the XYZ
+    /// numbering (y counts from the north) and the TMS numbering (y counts from the south).
+    pub fn flip_y(&self) -> TileCoord {
+        TileCoord {
+            zoom: self.zoom,
+            x: self.x,
+            y: Self::get_zoom_level_tiles(self.zoom) - 1 - self.y,
+        }
+    }
+
     // Return the MapCoord of the center of the current tile.
     pub fn map_coord_center(&self) -> MapCoord {
         let inv_zoom_factor = f64::powi(2.0, -(self.zoom as i32));
@@ -479,12 +489,50 @@ impl Iterator for TileChildrenIter {
     }
 }
 
-//TODO include width and height of view rect to determine visibility
+/// A rectangular area that is bounded by two meridians and two parallels. Values are in degrees.
+/// Areas that cross the antimeridian are not supported (`west` has to be less than `east`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    pub fn new(west: f64, south: f64, east: f64, north: f64) -> Self {
+        BoundingBox { west, south, east, north }
+    }
+
+    /// Creates a bounding box after checking that the coordinates are in range and that west is
+    /// less than east and south is less than north.
+    pub fn try_new(west: f64, south: f64, east: f64, north: f64) -> Result<Self, String> {
+        if !(west >= -180.0 && east <= 180.0 && south >= -90.0 && north <= 90.0) {
+            Err("coordinates are out of range".to_string())
+        } else if west >= east || south >= north {
+            Err("has to be [west, south, east, north] with west < east and south < north".to_string())
+        } else {
+            Ok(BoundingBox { west, south, east, north })
+        }
+    }
+
+    /// Returns true if the given tile overlaps with this area.
+    pub fn intersects_tile(&self, tile_coord: TileCoord) -> bool {
+        let nw = tile_coord.latlon_rad_north_west().to_degrees();
+        let se = tile_coord.latlon_rad_south_east().to_degrees();
+
+        nw.lon < self.east && se.lon > self.west &&
+        se.lat < self.north && nw.lat > self.south
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct View {
     pub source_id: TileSourceId,
     pub zoom: u32,
     pub center: MapCoord,
+    /// Distance from the center to the farthest visible point in map coordinates.
+    pub radius: f64,
 }
 
 #[cfg(test)]
@@ -523,6 +571,25 @@ mod tests {
         TileCoord { zoom, x, y }
     }
 
+    #[test]
+    fn flip_y() {
+        assert_eq!(tc(0, 0, 0).flip_y(), tc(0, 0, 0));
+        assert_eq!(tc(1, 1, 0).flip_y(), tc(1, 1, 1));
+        assert_eq!(tc(3, 2, 1).flip_y(), tc(3, 2, 6));
+        assert_eq!(tc(3, 2, 1).flip_y().flip_y(), tc(3, 2, 1));
+    }
+
+    #[test]
+    fn bounding_box() {
+        let bbox = BoundingBox::new(5.8, 47.2, 15.1, 55.1);
+        assert!(bbox.intersects_tile(TileCoord::new(0, 0, 0)));
+        assert!(bbox.intersects_tile(TileCoord::new(1, 1, 0)));
+        assert!(!bbox.intersects_tile(TileCoord::new(1, 0, 0)));
+        assert!(!bbox.intersects_tile(TileCoord::new(1, 1, 1)));
+        assert!(bbox.intersects_tile(TileCoord::new(6, 34, 21)));
+        assert!(!bbox.intersects_tile(TileCoord::new(6, 34, 25)));
+    }
+
     #[test]
     fn nearest_valid() {
         assert_eq!(tc(0, 0, 0).nearest_valid(), tc(0, 0, 0));
@@ -0,0 +1,462 @@
+//! Keeps the tile cache on disk within a size limit by removing the tiles that were not accessed
+//! for the longest time.
+
+use cache_archive;
+use clap;
+use config::Config;
+use http_cache::METADATA_SUFFIX;
+use size::{format_size, parse_size};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+/// Time between two runs of the background eviction
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The cached tiles of one or all sources that must not grow beyond `max_size` bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheLimit {
+    /// Directory that is shown in messages
+    pub path: PathBuf,
+    /// Tile directories of the sources whose tiles count towards the limit
+    pub tile_dirs: Vec<PathBuf>,
+    pub max_size: u64,
+}
+
+/// Number of tiles and their total size in bytes, including their metadata files.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheUsage {
+    pub tiles: u64,
+    pub bytes: u64,
+    /// Size of metadata files whose tile does not exist. These are not counted as tiles.
+    pub orphaned_bytes: u64,
+}
+
+impl CacheUsage {
+    fn add(&mut self, entry: &CacheEntry) {
+        if entry.orphaned {
+            self.orphaned_bytes += entry.size;
+        } else {
+            self.tiles += 1;
+            self.bytes += entry.size;
+        }
+    }
+}
+
+impl fmt::Display for CacheUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tiles ({})", self.tiles, format_size(self.bytes))?;
+        if self.orphaned_bytes > 0 {
+            write!(f, " and {} of orphaned metadata", format_size(self.orphaned_bytes))?;
+        }
+        Ok(())
+    }
+}
+
+/// A cached tile together with its metadata file, or a metadata file without a tile.
+#[derive(Clone, Debug, PartialEq)]
+struct CacheEntry {
+    path: PathBuf,
+    metadata_path: Option<PathBuf>,
+    /// The entry is a metadata file without a tile.
+    orphaned: bool,
+    size: u64,
+    /// Time of the last access or modification, whichever is later. Access times are only
+    /// updated lazily by most file systems, so this is an approximation.
+    last_access: SystemTime,
+}
+
+/// Returns all cached tiles in the tile directories `dirs`. Only files in the tile layout
+/// `{z}/{x}/{y}[@Nx].<ext>` and their metadata files are considered, everything else is left
+/// alone. A missing directory is treated as an empty cache.
+fn scan(dirs: &[PathBuf]) -> io::Result<Vec<CacheEntry>> {
+    let mut files = vec![];
+    for dir in unique_dirs(dirs) {
+        match collect_tile_files(dir, 0, &mut files) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            result => result?,
+        }
+    }
+
+    let (meta_files, tile_files): (Vec<_>, Vec<_>) = files.into_iter()
+        .partition(|(path, _)| path.to_string_lossy().ends_with(METADATA_SUFFIX));
+
+    let mut entries: Vec<CacheEntry> = tile_files.into_iter()
+        .map(|(path, metadata)| {
+            let last_access = metadata.accessed().ok().into_iter()
+                .chain(metadata.modified().ok())
+                .max()
+                .unwrap_or(UNIX_EPOCH);
+            CacheEntry {
+                path,
+                metadata_path: None,
+                orphaned: false,
+                size: metadata.len(),
+                last_access,
+            }
+        })
+        .collect();
+    let index: HashMap<PathBuf, usize> = entries.iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.path.clone(), i))
+        .collect();
+
+    let mut orphans = vec![];
+    for (path, metadata) in meta_files {
+        let lossy = path.to_string_lossy();
+        let tile_path = PathBuf::from(&lossy[..lossy.len() - METADATA_SUFFIX.len()]);
+        match index.get(&tile_path) {
+            Some(&i) => {
+                let entry = &mut entries[i];
+                entry.metadata_path = Some(path);
+                entry.size += metadata.len();
+            },
+            None => {
+                // Metadata without a tile is of no use and is removed first.
+                orphans.push(CacheEntry {
+                    path,
+                    metadata_path: None,
+                    orphaned: true,
+                    size: metadata.len(),
+                    last_access: UNIX_EPOCH,
+                });
+            },
+        }
+    }
+
+    entries.extend(orphans);
+    Ok(entries)
+}
+
+/// Returns the directories without duplicates and without directories that are inside of
+/// another one, so that no file is counted twice.
+fn unique_dirs(dirs: &[PathBuf]) -> Vec<&Path> {
+    let mut unique: Vec<&Path> = vec![];
+    for dir in dirs {
+        if !dirs.iter().any(|other| other != dir && dir.starts_with(other)) &&
+            !unique.contains(&dir.as_path())
+        {
+            unique.push(dir);
+        }
+    }
+    unique
+}
+
+/// Collects the tiles and metadata files below `dir`, which is `depth` levels below a tile
+/// directory. Only the `{z}` and `{x}` directory levels are entered.
+fn collect_tile_files(dir: &Path, depth: usize, files: &mut Vec<(PathBuf, fs::Metadata)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let metadata = entry.metadata()?;
+        if depth < 2 {
+            if metadata.is_dir() && is_number(name) {
+                collect_tile_files(&entry.path(), depth + 1, files)?;
+            }
+        } else if metadata.is_file() && is_tile_file_name(name) {
+            files.push((entry.path(), metadata));
+        }
+    }
+    Ok(())
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Returns true for file names like `5.png`, `5@2x.png` or `5.png.meta`.
+fn is_tile_file_name(name: &str) -> bool {
+    let name = name.strip_suffix(METADATA_SUFFIX).unwrap_or(name);
+    let (stem, extension) = match name.rfind('.') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => return false,
+    };
+    if extension.is_empty() || !extension.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return false;
+    }
+    match stem.find('@') {
+        Some(pos) => {
+            let scale = &stem[pos + 1..];
+            is_number(&stem[..pos]) && scale.ends_with('x') && is_number(&scale[..scale.len() - 1])
+        },
+        None => is_number(stem),
+    }
+}
+
+/// Returns the entries that have to be removed to shrink the cache to `max_size` bytes, least
+/// recently accessed first.
+fn select_evictions(mut entries: Vec<CacheEntry>, max_size: u64) -> Vec<CacheEntry> {
+    let mut size: u64 = entries.iter().map(|e| e.size).sum();
+    entries.sort_by(|a, b| a.last_access.cmp(&b.last_access).then_with(|| a.path.cmp(&b.path)));
+    entries.into_iter()
+        .take_while(|entry| {
+            if size > max_size {
+                size -= entry.size;
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+/// Returns the number of tiles and the size of the cache in the tile directories `dirs`.
+pub fn usage(dirs: &[PathBuf]) -> io::Result<CacheUsage> {
+    let mut usage = CacheUsage::default();
+    for entry in scan(dirs)? {
+        usage.add(&entry);
+    }
+    Ok(usage)
+}
+
+/// Removes the least recently accessed tiles and their metadata until the cache in the tile
+/// directories `dirs` is at most `max_size` bytes large. Returns what has been removed.
+pub fn trim(dirs: &[PathBuf], max_size: u64) -> io::Result<CacheUsage> {
+    let mut removed = CacheUsage::default();
+
+    for entry in select_evictions(scan(dirs)?, max_size) {
+        for path in Some(&entry.path).into_iter().chain(entry.metadata_path.as_ref()) {
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() != io::ErrorKind::NotFound => return Err(io::Error::new(
+                    e.kind(),
+                    format!("could not remove {:?}, {}", path, e),
+                )),
+                _ => {},
+            }
+        }
+        removed.add(&entry);
+
+        // Remove the {x} and {z} directories if they became empty, but keep the tile directory.
+        for parent in entry.path.ancestors().skip(1).take(2) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes all tiles and their metadata from the tile directories `dirs`.
+pub fn clear(dirs: &[PathBuf]) -> io::Result<CacheUsage> {
+    trim(dirs, 0)
+}
+
+/// Enforces the limits on a background thread, once at startup and then regularly. The limits
+/// are read before each run, so they can be replaced while the thread is running.
+pub fn spawn_eviction(limits: Arc<Mutex<Vec<CacheLimit>>>) {
+    thread::spawn(move || {
+        loop {
+            let current = match limits.lock() {
+                Ok(limits) => limits.clone(),
+                Err(_) => return,
+            };
+            for limit in &current {
+                match trim(&limit.tile_dirs, limit.max_size) {
+                    Ok(ref removed) if *removed != CacheUsage::default() => {
+                        info!("evicted {} from {:?}", removed, limit.path);
+                    },
+                    Ok(_) => {},
+                    Err(e) => warn!("could not evict tiles from {:?}, {}", limit.path, e),
+                }
+            }
+            thread::sleep(EVICTION_INTERVAL);
+        }
+    });
+}
+
+/// Runs the `cache` command with its argument matches.
+pub fn run_command<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<(), String> {
+    match matches.subcommand() {
+        ("usage", _) => print_usage(config),
+        ("trim", Some(matches)) => {
+            let max_size = matches.value_of("max-size").map(parse_size).transpose()?;
+            let limits = match (source_cache(config, matches)?, max_size) {
+                (Some((path, tile_dirs)), Some(max_size)) => vec![CacheLimit { path, tile_dirs, max_size }],
+                (None, Some(max_size)) => vec![CacheLimit {
+                    path: config.tile_cache_dir().to_path_buf(),
+                    tile_dirs: config.cache_tile_directories(),
+                    max_size,
+                }],
+                (Some((path, _)), None) => {
+                    let limits: Vec<_> = config.cache_limits().into_iter()
+                        .filter(|limit| limit.path == path)
+                        .collect();
+                    if limits.is_empty() {
+                        return Err("the source has no max_cache_size, use --max-size".to_string());
+                    }
+                    limits
+                },
+                (None, None) => config.cache_limits(),
+            };
+
+            if limits.is_empty() {
+                return Err("no max_cache_size is configured, use --max-size".to_string());
+            }
+
+            for limit in limits {
+                let removed = trim(&limit.tile_dirs, limit.max_size)
+                    .map_err(|e| format!("could not trim {:?}, {}", limit.path, e))?;
+                println!("removed {} from {:?}", removed, limit.path);
+            }
+            Ok(())
+        },
+        ("clear", Some(matches)) => {
+            let (path, tile_dirs) = source_cache(config, matches)?
+                .unwrap_or_else(|| (config.tile_cache_dir().to_path_buf(), config.cache_tile_directories()));
+            let removed = clear(&tile_dirs).map_err(|e| format!("could not clear {:?}, {}", path, e))?;
+            println!("removed {} from {:?}", removed, path);
+            Ok(())
+        },
+        ("export", Some(matches)) => cache_archive::run_export(config, matches),
+        ("import", Some(matches)) => cache_archive::run_import(config, matches),
+        _ => Err("missing cache command, use \"usage\", \"trim\", \"clear\", \"export\" or \"import\"".to_string()),
+    }
+}
+
+/// Returns the cache directory and the tile directories of the source that is given with
+/// `--source`.
+fn source_cache<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<Option<(PathBuf, Vec<PathBuf>)>, String> {
+    let name = match matches.value_of("source") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let source = config.tile_source(name)?;
+
+    source.cache_directory()
+        .map(|path| Some((path.to_path_buf(), source.cache_tile_directories())))
+        .ok_or_else(|| format!("tile source {:?} is not downloaded and has no cache", name))
+}
+
+/// Prints the size of the cache of each source and of all sources together.
+fn print_usage(config: &Config) -> Result<(), String> {
+    let usage_of = |path: &Path, tile_dirs: &[PathBuf]| {
+        usage(tile_dirs).map_err(|e| format!("could not read {:?}, {}", path, e))
+    };
+    let limit_str = |limit: Option<u64>| limit.map(format_size).unwrap_or_else(|| "-".to_string());
+
+    println!("tile cache directory: {:?}", config.tile_cache_dir());
+    println!("{:24} {:>10} {:>10} {:>10}", "source", "tiles", "size", "limit");
+    for (name, source) in config.tile_sources() {
+        if let Some(path) = source.cache_directory() {
+            let usage = usage_of(path, &source.cache_tile_directories())?;
+            println!(
+                "{:24} {:>10} {:>10} {:>10}",
+                name, usage.tiles, format_size(usage.bytes), limit_str(source.max_cache_size()),
+            );
+        }
+    }
+
+    let total = usage_of(config.tile_cache_dir(), &config.cache_tile_directories())?;
+    println!(
+        "{:24} {:>10} {:>10} {:>10}",
+        "total", total.tiles, format_size(total.bytes), limit_str(config.max_cache_size()),
+    );
+    if total.orphaned_bytes > 0 {
+        println!("{} of metadata files without a tile", format_size(total.orphaned_bytes));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use disk_cache::*;
+    use http_cache::metadata_path;
+    use test_util::TempDir;
+
+    fn entry(path: &str, size: u64, secs: u64) -> CacheEntry {
+        CacheEntry {
+            path: PathBuf::from(path),
+            metadata_path: None,
+            orphaned: false,
+            size,
+            last_access: UNIX_EPOCH + Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn least_recently_accessed_first() {
+        let entries = vec![entry("a", 100, 30), entry("b", 100, 10), entry("c", 100, 20)];
+        let paths = |entries: Vec<CacheEntry>| {
+            entries.into_iter().map(|e| e.path.to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(paths(select_evictions(entries.clone(), 300)), Vec::<String>::new());
+        assert_eq!(paths(select_evictions(entries.clone(), 250)), vec!["b"]);
+        assert_eq!(paths(select_evictions(entries.clone(), 100)), vec!["b", "c"]);
+        assert_eq!(paths(select_evictions(entries, 0)), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn trim_directory() {
+        let dir = TempDir::new("deltamap_test_disk_cache");
+        for (path, size) in &[("1/0/0.png", 400), ("1/0/1.png", 400), ("1/1/0.png", 400)] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![0; *size]).unwrap();
+            fs::write(metadata_path(&path), vec![0; 100]).unwrap();
+        }
+        fs::write(dir.join("1/1/5.png.meta"), vec![0; 100]).unwrap();
+        let dirs = vec![dir.to_path_buf()];
+
+        let total = usage(&dirs).unwrap();
+        assert_eq!(total, CacheUsage { tiles: 3, bytes: 1500, orphaned_bytes: 100 });
+        assert_eq!(total.to_string(), "3 tiles (1.5 kB) and 100 B of orphaned metadata");
+
+        // The orphaned metadata file and one tile have to go.
+        let removed = trim(&dirs, 1000).unwrap();
+        assert_eq!(removed, CacheUsage { tiles: 1, bytes: 500, orphaned_bytes: 100 });
+        assert_eq!(usage(&dirs).unwrap(), CacheUsage { tiles: 2, bytes: 1000, orphaned_bytes: 0 });
+        assert!(!dir.join("1/1/5.png.meta").exists());
+
+        assert_eq!(clear(&dirs).unwrap(), CacheUsage { tiles: 2, bytes: 1000, orphaned_bytes: 0 });
+        assert_eq!(usage(&dirs).unwrap(), CacheUsage::default());
+        assert!(dir.is_dir());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        assert_eq!(usage(&[dir.join("missing")]).unwrap(), CacheUsage::default());
+    }
+
+    #[test]
+    fn keep_unrelated_files() {
+        let dir = TempDir::new("deltamap_test_disk_cache_unrelated");
+        let tiles = ["osm/1/0/0.png", "osm/1/0/1@2x.png", "osm/1/0/1@2x.png.meta"];
+        let unrelated = [
+            "notes.txt",
+            "osm/readme.md",
+            "osm/1/info.png",
+            "osm/1/0/backup.png",
+            "osm/1/0/0.png.bak",
+            "osm/1/0/2/3.png",
+            "osm/1/x/0.png",
+            "other/1/0/0.png",
+        ];
+        for path in tiles.iter().chain(unrelated.iter()) {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![0; 100]).unwrap();
+        }
+        let dirs = vec![dir.join("osm")];
+
+        assert_eq!(usage(&dirs).unwrap(), CacheUsage { tiles: 2, bytes: 300, orphaned_bytes: 0 });
+        assert_eq!(trim(&dirs, 200).unwrap().tiles, 1);
+        assert_eq!(clear(&dirs).unwrap().tiles, 1);
+        for path in &tiles {
+            assert!(!dir.join(path).exists(), "{}", path);
+        }
+        for path in &unrelated {
+            assert!(dir.join(path).is_file(), "{}", path);
+        }
+    }
+}
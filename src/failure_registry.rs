@@ -0,0 +1,123 @@
+//! Remembers tiles that could not be loaded to avoid requesting them over and over again.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tile::Tile;
+use tile_provider::LoadError;
+
+
+/// Delay before the first retry of a tile after a transient error.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+/// Upper bound of the delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// A failed attempt to load a tile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    /// The most recent error
+    pub error: LoadError,
+    /// Number of failed attempts in a row
+    pub attempts: u32,
+    /// Time of the next attempt or `None` if the error is permanent
+    pub retry_at: Option<Instant>,
+}
+
+/// Failed tile loads. Transient errors are retried with exponential backoff, permanent errors are
+/// never retried.
+#[derive(Debug, Default)]
+pub struct FailureRegistry {
+    failures: HashMap<Tile, Failure>,
+}
+
+impl FailureRegistry {
+    pub fn new() -> Self {
+        FailureRegistry::default()
+    }
+
+    /// Records a failed attempt to load `tile` at time `now`.
+    pub fn record_failure(&mut self, tile: Tile, error: LoadError, now: Instant) {
+        let attempts = self.failures.get(&tile).map(|f| f.attempts + 1).unwrap_or(1);
+        let retry_at = if error.is_permanent() {
+            None
+        } else {
+            Some(now + backoff(attempts))
+        };
+
+        debug!("failed to load {:?} ({} attempts), {}", tile, attempts, error);
+
+        self.failures.insert(tile, Failure { error, attempts, retry_at });
+    }
+
+    /// Forgets all failures of a tile that was loaded successfully.
+    pub fn record_success(&mut self, tile: Tile) {
+        self.failures.remove(&tile);
+    }
+
+    /// Returns true if `tile` may be requested at time `now`.
+    pub fn may_request(&self, tile: Tile, now: Instant) -> bool {
+        match self.failures.get(&tile) {
+            None => true,
+            Some(&Failure { retry_at: Some(retry_at), .. }) => now >= retry_at,
+            Some(&Failure { retry_at: None, .. }) => false,
+        }
+    }
+
+    /// Forgets the tiles that were not available, e.g. because downloading was enabled or
+    /// disabled in the meantime.
+    pub fn forget_unavailable(&mut self) {
+        self.failures.retain(|_, failure| {
+            failure.error != LoadError::Unavailable && failure.error != LoadError::Offline
+        });
+    }
+
+    /// Returns the last failure of `tile` if its most recent attempt failed.
+    pub fn failure(&self, tile: Tile) -> Option<&Failure> {
+        self.failures.get(&tile)
+    }
+}
+
+/// Returns the delay before the next attempt after `attempts` failures in a row.
+fn backoff(attempts: u32) -> Duration {
+    let factor = 1_u32 << attempts.saturating_sub(1).min(16);
+    (INITIAL_BACKOFF * factor).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use coord::TileCoord;
+    use failure_registry::*;
+    use tile_source::TileSourceId;
+
+    fn tile(x: i32) -> Tile {
+        Tile::new(TileCoord::new(4, x, 0), TileSourceId::new(0))
+    }
+
+    #[test]
+    fn backoff_steps() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(5), Duration::from_secs(32));
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = FailureRegistry::new();
+        let now = Instant::now();
+
+        registry.record_failure(tile(0), LoadError::Status(503), now);
+        assert!(!registry.may_request(tile(0), now));
+        assert!(registry.may_request(tile(0), now + Duration::from_secs(2)));
+        registry.record_failure(tile(0), LoadError::Status(503), now + Duration::from_secs(2));
+        assert_eq!(registry.failure(tile(0)).unwrap().attempts, 2);
+        assert!(!registry.may_request(tile(0), now + Duration::from_secs(5)));
+        assert!(registry.may_request(tile(0), now + Duration::from_secs(6)));
+
+        registry.record_failure(tile(1), LoadError::Status(404), now);
+        assert!(!registry.may_request(tile(1), now + Duration::from_secs(100_000)));
+
+        registry.record_success(tile(0));
+        assert!(registry.may_request(tile(0), now));
+        assert_eq!(registry.failure(tile(0)), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! Notices changes of files by polling their modification times, so no platform-specific
+//! notification API is needed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+
+/// Calls `on_change` on a separate thread whenever one of the files at `paths` is modified,
+/// created or removed. A change is only reported after the files did not change for `interval`,
+/// so a file that is written in several steps is reported once. Lists of paths that are sent over
+/// `paths_rx` replace the watched files, e.g. after the set of configuration files changed. The
+/// thread stops when `on_change` returns false.
+pub fn watch<F>(
+    mut paths: Vec<PathBuf>,
+    interval: Duration,
+    paths_rx: mpsc::Receiver<Vec<PathBuf>>,
+    mut on_change: F,
+) -> thread::JoinHandle<()>
+    where F: FnMut() -> bool + Send + 'static,
+{
+    thread::spawn(move || {
+        let states = |paths: &[PathBuf]| paths.iter().map(|p| file_state(p)).collect::<Vec<_>>();
+        let mut reported = states(&paths);
+        let mut last = reported.clone();
+
+        loop {
+            thread::sleep(interval);
+            if let Some(new_paths) = paths_rx.try_iter().last() {
+                paths = new_paths;
+                reported = states(&paths);
+                last = reported.clone();
+                continue;
+            }
+
+            let current = states(&paths);
+            if current == last && current != reported {
+                if !on_change() {
+                    return;
+                }
+                reported = current.clone();
+            }
+            last = current;
+        }
+    })
+}
+
+/// Returns the modification time and the length of a file or `None` if it does not exist.
+fn file_state(path: &Path) -> Option<(Option<SystemTime>, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok(), metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use file_watcher::*;
+    use std::sync::mpsc;
+    use test_util::TempDir;
+
+    #[test]
+    fn notice_changes() {
+        let dir = TempDir::new("deltamap_test_file_watcher");
+        let path = dir.join("config.toml");
+        fs::write(&path, "fps = 30").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let (paths_tx, paths_rx) = mpsc::channel();
+        let handle = watch(vec![path.clone()], Duration::from_millis(10), paths_rx, move || tx.send(()).is_ok());
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        fs::write(&path, "fps = 60.0").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // Files that are not watched anymore are ignored.
+        let import_path = dir.join("import.json");
+        paths_tx.send(vec![import_path.clone()]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "fps = 30").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        fs::write(&import_path, "{}").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        fs::remove_file(&path).unwrap();
+
+        // The thread stops after the receiver is gone.
+        drop(rx);
+        fs::write(&import_path, "[]").unwrap();
+        handle.join().unwrap();
+    }
+}
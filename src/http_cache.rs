@@ -0,0 +1,290 @@
+//! Metadata of cached HTTP responses. It is stored in a small file next to each cached tile and
+//! used to revalidate expired tiles with conditional requests.
+
+use reqwest::header::{CACHE_CONTROL, DATE, ETAG, EXPIRES, HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml::Value;
+use toml::value::Table;
+use toml;
+
+
+/// Suffix that is appended to the path of a tile to get the path of its metadata file.
+pub const METADATA_SUFFIX: &str = ".meta";
+
+/// Freshness lifetime in seconds of responses that have neither an explicit expiration time nor a
+/// `Last-Modified` header.
+pub const DEFAULT_MAX_AGE: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheMetadata {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Time of the last successful response in seconds since the Unix epoch
+    pub fetched: u64,
+    /// Value of the `max-age` directive in seconds or the difference between the `Expires` and
+    /// `Date` headers. `no-cache`, `no-store` and invalid `Expires` values are stored as zero.
+    pub max_age: Option<u64>,
+}
+
+impl CacheMetadata {
+    /// Creates metadata from the headers of a response that was received at `now`.
+    pub fn from_headers(headers: &HeaderMap, now: SystemTime) -> Self {
+        let get = |name| {
+            headers.get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+
+        let fetched = unix_seconds(now);
+        let expires_max_age = || {
+            let expires = get(EXPIRES)?;
+            let date = get(DATE).and_then(|d| parse_http_date(&d)).unwrap_or(fetched);
+            // An invalid date, especially "0", means that the response is already expired.
+            Some(parse_http_date(&expires).map_or(0, |expires| expires.saturating_sub(date)))
+        };
+
+        CacheMetadata {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+            fetched,
+            max_age: get(CACHE_CONTROL).and_then(|cc| parse_max_age(&cc)).or_else(expires_max_age),
+        }
+    }
+
+    /// Updates the metadata with the headers of a `304 Not Modified` response.
+    pub fn revalidated(&mut self, headers: &HeaderMap, now: SystemTime) {
+        let new = CacheMetadata::from_headers(headers, now);
+        self.fetched = new.fetched;
+        self.etag = new.etag.or_else(|| self.etag.take());
+        self.last_modified = new.last_modified.or_else(|| self.last_modified.take());
+        self.max_age = new.max_age.or(self.max_age);
+    }
+
+    /// Returns true if the response is older than its maximum age. `max_age` overrides the value
+    /// from the response. Without either, the response stays fresh for 10% of its age since the
+    /// `Last-Modified` time or for `DEFAULT_MAX_AGE` if that is unknown.
+    pub fn is_expired(&self, now: SystemTime, max_age: Option<u64>) -> bool {
+        let max_age = max_age.or(self.max_age).unwrap_or_else(|| self.heuristic_max_age());
+        self.fetched.saturating_add(max_age) < unix_seconds(now)
+    }
+
+    /// Returns the heuristic freshness lifetime of a response without an explicit expiration
+    /// time.
+    fn heuristic_max_age(&self) -> u64 {
+        match self.last_modified.as_ref().and_then(|lm| parse_http_date(lm)) {
+            Some(last_modified) => self.fetched.saturating_sub(last_modified) / 10,
+            None => DEFAULT_MAX_AGE,
+        }
+    }
+
+    /// Adds the headers of a conditional request that only succeeds if the tile has changed.
+    pub fn add_conditional_headers(&self, headers: &mut HeaderMap) {
+        if let Some(value) = self.etag.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_NONE_MATCH, value);
+        }
+        if let Some(value) = self.last_modified.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_MODIFIED_SINCE, value);
+        }
+    }
+
+    /// Reads the metadata of the tile at `tile_path`. Returns `None` if there is no valid
+    /// metadata.
+    pub fn read<P: AsRef<Path>>(tile_path: P) -> Option<CacheMetadata> {
+        let mut content = String::new();
+        File::open(metadata_path(tile_path.as_ref())).ok()?.read_to_string(&mut content).ok()?;
+        let table = match content.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            _ => return None,
+        };
+
+        let get_string = |key: &str| table.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let get_u64 = |key: &str| table.get(key).and_then(|v| v.as_integer()).map(|i| i.max(0) as u64);
+
+        Some(CacheMetadata {
+            etag: get_string("etag"),
+            last_modified: get_string("last_modified"),
+            fetched: get_u64("fetched")?,
+            max_age: get_u64("max_age"),
+        })
+    }
+
+    /// Writes the metadata of the tile at `tile_path`.
+    pub fn write<P: AsRef<Path>>(&self, tile_path: P) -> Result<(), String> {
+        let mut table = Table::new();
+        if let Some(ref etag) = self.etag {
+            table.insert("etag".to_string(), Value::String(etag.clone()));
+        }
+        if let Some(ref last_modified) = self.last_modified {
+            table.insert("last_modified".to_string(), Value::String(last_modified.clone()));
+        }
+        table.insert("fetched".to_string(), Value::Integer(self.fetched as i64));
+        if let Some(max_age) = self.max_age {
+            table.insert("max_age".to_string(), Value::Integer(max_age as i64));
+        }
+
+        let content = toml::ser::to_string(&Value::Table(table)).map_err(|e| format!("{}", e))?;
+        File::create(metadata_path(tile_path.as_ref()))
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map_err(|e| format!("{}", e))
+    }
+}
+
+/// Returns the path of the metadata file that belongs to the tile at `tile_path`.
+pub fn metadata_path(tile_path: &Path) -> PathBuf {
+    let mut path = tile_path.as_os_str().to_owned();
+    path.push(METADATA_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Returns the seconds since the Unix epoch.
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Parses the maximum age in seconds from the value of a Cache-Control header.
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+    let mut max_age = None;
+    for directive in cache_control.split(',') {
+        let directive = directive.trim().to_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return Some(0);
+        }
+        if let Some(value) = directive.strip_prefix("max-age=") {
+            max_age = value.trim_matches('"').parse().ok();
+        }
+    }
+    max_age
+}
+
+/// Parses an HTTP date in the preferred format (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`) and returns
+/// the seconds since the Unix epoch. The obsolete RFC 850 and asctime formats are not supported.
+fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 || !parts[0].ends_with(',') || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day: u64 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == parts[2])? as u64 + 1;
+    let year: u64 = parts[3].parse().ok()?;
+    let time: Vec<u64> = parts[4].split(':').map(|t| t.parse().ok()).collect::<Option<_>>()?;
+    if !(1..=31).contains(&day) || year < 1970 || time.len() != 3 || time[0] > 23 || time[1] > 59 ||
+        time[2] > 60
+    {
+        return None;
+    }
+
+    // Count the days since 1970-01-01 with years that start in March, so that the leap day is at
+    // the end of the year.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let days = 365 * y + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719_468;
+
+    Some(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use http_cache::*;
+    use std::time::Duration;
+    use test_util::TempDir;
+
+    #[test]
+    fn cache_control() {
+        assert_eq!(parse_max_age("max-age=3600"), Some(3600));
+        assert_eq!(parse_max_age("public, Max-Age=60, must-revalidate"), Some(60));
+        assert_eq!(parse_max_age("no-cache"), Some(0));
+        assert_eq!(parse_max_age("public"), None);
+        assert_eq!(parse_max_age("max-age=abc"), None);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=100"));
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1000);
+        let meta = CacheMetadata::from_headers(&headers, t0);
+        assert_eq!(meta.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(meta.fetched, 1000);
+        assert!(!meta.is_expired(t0 + Duration::from_secs(100), None));
+        assert!(meta.is_expired(t0 + Duration::from_secs(101), None));
+        assert!(!meta.is_expired(t0 + Duration::from_secs(101), Some(200)));
+
+        let mut conditional = HeaderMap::new();
+        meta.add_conditional_headers(&mut conditional);
+        assert_eq!(conditional.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+        assert!(conditional.get(IF_MODIFIED_SINCE).is_none());
+
+        // Without any freshness information the default applies
+        let meta = CacheMetadata::from_headers(&HeaderMap::new(), t0);
+        assert!(!meta.is_expired(t0 + Duration::from_secs(DEFAULT_MAX_AGE), None));
+        assert!(meta.is_expired(t0 + Duration::from_secs(DEFAULT_MAX_AGE + 1), None));
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 12:00:00 GMT"), Some(951_825_600));
+        assert_eq!(parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"), Some(1_445_412_480));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("0"), None);
+    }
+
+    #[test]
+    fn expires_header() {
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        let mut headers = HeaderMap::new();
+        headers.insert(DATE, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        headers.insert(EXPIRES, HeaderValue::from_static("Wed, 21 Oct 2015 08:28:00 GMT"));
+        assert_eq!(CacheMetadata::from_headers(&headers, t0).max_age, Some(3600));
+
+        // max-age takes precedence over Expires
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        assert_eq!(CacheMetadata::from_headers(&headers, t0).max_age, Some(60));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(EXPIRES, HeaderValue::from_static("0"));
+        assert_eq!(CacheMetadata::from_headers(&headers, t0).max_age, Some(0));
+    }
+
+    #[test]
+    fn heuristic_expiry() {
+        // A response with an ETag but no max-age that was last modified 10 days before it was
+        // fetched stays fresh for one day.
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_445_412_480 + 10 * 86_400);
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(LAST_MODIFIED, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+
+        let meta = CacheMetadata::from_headers(&headers, t0);
+        assert_eq!(meta.max_age, None);
+        assert!(!meta.is_expired(t0 + Duration::from_secs(86_400), None));
+        assert!(meta.is_expired(t0 + Duration::from_secs(86_401), None));
+        assert!(!meta.is_expired(t0 + Duration::from_secs(86_401), Some(2 * 86_400)));
+    }
+
+    #[test]
+    fn read_write() {
+        let dir = TempDir::new("deltamap_test_http_cache");
+        let tile_path = dir.join("0.png");
+        assert!(metadata_path(&tile_path).to_string_lossy().ends_with("0.png.meta"));
+
+        let meta = CacheMetadata {
+            etag: Some("W/\"1\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            fetched: 1234,
+            max_age: Some(60),
+        };
+        meta.write(&tile_path).unwrap();
+        assert_eq!(CacheMetadata::read(&tile_path), Some(meta));
+    }
+}
@@ -6,6 +6,7 @@ extern crate clap;
 extern crate directories;
 extern crate env_logger;
 extern crate glutin;
+extern crate humantime;
 extern crate image;
 #[macro_use]
 extern crate lazy_static;
@@ -16,50 +17,78 @@ extern crate num_cpus;
 extern crate osmpbf;
 extern crate regex;
 extern crate reqwest;
+extern crate rusqlite;
 extern crate scoped_threadpool;
+extern crate serde_json;
 extern crate toml;
+extern crate url;
+extern crate xml;
 
 #[macro_use]
 pub mod context;
 
 pub mod args;
 pub mod atmos_layer;
+pub mod attribution_layer;
+pub mod bitmap_font;
 pub mod buffer;
+pub mod cache_archive;
 pub mod config;
 pub mod coord;
+pub mod disk_cache;
+pub mod failure_registry;
+pub mod file_watcher;
+pub mod http_cache;
 pub mod map_view_gl;
 pub mod marker_layer;
+pub mod mbtiles;
 pub mod mercator_tile_layer;
 pub mod mercator_view;
 pub mod ortho_tile_layer;
 pub mod orthografic_view;
 pub mod path_layer;
+pub mod playback;
+pub mod prefetch;
 pub mod program;
 pub mod projection;
 pub mod projection_view;
 pub mod query;
+pub mod rate_limiter;
 pub mod search;
+pub mod seed;
 pub mod session;
+pub mod size;
+pub mod source_check;
+pub mod source_import;
 pub mod texture;
 pub mod tile;
 pub mod tile_atlas;
 pub mod tile_cache;
 pub mod tile_loader;
+pub mod tile_provider;
 pub mod tile_source;
 pub mod url_template;
+#[cfg(test)]
+pub mod test_server;
+#[cfg(test)]
+pub mod test_util;
 pub mod vertex_attrib;
 
+use config::Config;
+use disk_cache::CacheLimit;
 use coord::{LatLonDeg, ScreenCoord};
 use glutin::dpi::{LogicalPosition, LogicalSize, PhysicalPosition};
 use glutin::{ControlFlow, ElementState, Event, GlContext, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
-use map_view_gl::MapViewGl;
+use map_view_gl::{MapViewGl, TileLayer};
 use path_layer::PathElement;
+use playback::Playback;
 use search::MatchItem;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc, Mutex};
 use std::time::{Duration, Instant};
-use tile_source::TileSource;
+use tile_cache::TileCache;
+use tile_source::{OverlaySettings, TileSource, TileSourceId};
 
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -101,6 +130,7 @@ fn handle_event(
     input_state: &mut InputState,
     query_state: &mut QueryState,
     sources: &mut TileSources,
+    playback: &mut Playback,
     marker_rx: &mpsc::Receiver<HashSet<MatchItem>>,
 ) -> Action {
     trace!("{:?}", event);
@@ -306,7 +336,58 @@ fn handle_event(
                             Action::Nothing
                         }
                     },
-                    _ => Action::Nothing,
+                    VirtualKeyCode::D => {
+                        if modifiers.ctrl {
+                            map.toggle_debug_overlay();
+                            Action::Redraw
+                        } else {
+                            Action::Nothing
+                        }
+                    },
+                    VirtualKeyCode::L => {
+                        if modifiers.ctrl {
+                            playback.toggle_loop();
+                            info!("loop playback: {}", playback.is_looping());
+                        }
+                        Action::Nothing
+                    },
+                    VirtualKeyCode::Space => {
+                        playback.toggle_play(Instant::now());
+                        Action::Redraw
+                    },
+                    VirtualKeyCode::Comma => {
+                        playback.pause();
+                        sources.step_time(-1, playback);
+                        Action::Redraw
+                    },
+                    VirtualKeyCode::Period => {
+                        playback.pause();
+                        sources.step_time(1, playback);
+                        Action::Redraw
+                    },
+                    VirtualKeyCode::LBracket => {
+                        playback.change_speed(0.5);
+                        info!("playback speed: {} time steps per second", playback.speed());
+                        Action::Nothing
+                    },
+                    VirtualKeyCode::RBracket => {
+                        playback.change_speed(2.0);
+                        info!("playback speed: {} time steps per second", playback.speed());
+                        Action::Nothing
+                    },
+                    _ => {
+                        match overlay_key_index(keycode) {
+                            Some(index) if modifiers.ctrl => {
+                                sources.cycle_overlay_opacity(index);
+                                Action::Redraw
+                            },
+                            Some(index) => {
+                                sources.toggle_overlay(index);
+                                Action::Redraw
+                            },
+                            None => Action::Nothing,
+                        }
+                    },
                 }
             },
             WindowEvent::Refresh => {
@@ -327,37 +408,112 @@ fn handle_event(
     }
 }
 
+/// Returns the index of the overlay that is controlled with the given number key.
+fn overlay_key_index(keycode: VirtualKeyCode) -> Option<usize> {
+    let index = match keycode {
+        VirtualKeyCode::Key1 => 0,
+        VirtualKeyCode::Key2 => 1,
+        VirtualKeyCode::Key3 => 2,
+        VirtualKeyCode::Key4 => 3,
+        VirtualKeyCode::Key5 => 4,
+        VirtualKeyCode::Key6 => 5,
+        VirtualKeyCode::Key7 => 6,
+        VirtualKeyCode::Key8 => 7,
+        VirtualKeyCode::Key9 => 8,
+        _ => return None,
+    };
+    Some(index)
+}
+
 fn dur_to_sec(dur: Duration) -> f64 {
     dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) * 1e-9
 }
 
+fn frame_duration(fps: f64) -> Duration {
+    Duration::from_millis((1000.0 / fps - 0.5).max(0.0).floor() as u64)
+}
+
+/// Loads the configuration files again and applies the tile sources and the settings that can be
+/// changed without a restart. Tiles may not be larger than `max_tile_size`, because the tile atlas
+/// was created for the tile sizes at startup. Changes of other settings are reported.
+fn reload_config<'a>(
+    matches: &clap::ArgMatches<'a>,
+    old_config: &Config,
+    dpi_factor: f64,
+    max_tile_size: u32,
+    sources: &mut TileSources,
+    map: &mut MapViewGl,
+    cache_limits: &Mutex<Vec<CacheLimit>>,
+) -> Result<Config, String> {
+    let mut config = Config::from_arg_matches(matches)?;
+    config.set_dpi_factor(dpi_factor);
+
+    for (name, source) in config.tile_sources() {
+        if source.tile_size() > max_tile_size {
+            return Err(format!(
+                "tiles of source {:?} are larger than {} pixels, restart to use them",
+                name,
+                max_tile_size,
+            ));
+        }
+    }
+
+    sources.reload(config.tile_sources().to_vec())?;
+    map.retain_sources(&sources.ids());
+    map.set_use_network(config.use_network());
+    map.set_memory_budget(config.memory_cache_size());
+    map.set_prefetch(config.prefetch_settings());
+    if let Ok(mut limits) = cache_limits.lock() {
+        *limits = config.cache_limits();
+    }
+
+    let not_applied = [
+        ("download_workers or rate_limits", config.download_settings() != old_config.download_settings()),
+        ("tile_cache_dir", config.tile_cache_dir() != old_config.tile_cache_dir()),
+        ("async", config.async() != old_config.async()),
+    ];
+    for &(keys, changed) in &not_applied {
+        if changed {
+            warn!("a change of {} is only applied after a restart", keys);
+        }
+    }
+
+    Ok(config)
+}
+
 fn run() -> Result<(), Box<Error>> {
-    let config = {
-        let arg_matches = args::parse();
-        let config = config::Config::from_arg_matches(&arg_matches)?;
+    let arg_matches = args::parse();
+    // Sources that cannot be parsed are reported instead of preventing the check.
+    if let Some(matches) = arg_matches.subcommand_matches("check-sources") {
+        let config = Config::from_arg_matches_lenient(&arg_matches)?;
+        source_check::run_command(&config, matches)?;
+        return Ok(());
+    }
+
+    let mut config = {
+        let config = Config::from_arg_matches(&arg_matches)?;
         if arg_matches.is_present("list-paths") {
             config.list_paths();
             return Ok(());
         }
+        if arg_matches.is_present("list-sources") {
+            config.list_sources();
+            return Ok(());
+        }
+        if let Some(matches) = arg_matches.subcommand_matches("seed") {
+            seed::run_command(&config, matches)?;
+            return Ok(());
+        }
+        if let Some(matches) = arg_matches.subcommand_matches("cache") {
+            disk_cache::run_command(&config, matches)?;
+            return Ok(());
+        }
         config
     };
 
-    let mut sources = TileSources::new(config.tile_sources())
-        .ok_or_else(|| "no tile sources provided.")?;
-
-    let last_session = if config.open_last_session() {
-        config::read_last_session().ok()
-    } else {
-        None
-    };
-
-    if let Some(tile_source) = last_session.as_ref().and_then(|s| s.tile_source()) {
-        sources.switch_to_name(tile_source);
-    }
-
     let mut events_loop = glutin::EventsLoop::new();
     let builder = glutin::WindowBuilder::new()
-        .with_title(format!("DeltaMap - {}", sources.current_name()));
+        .with_title("DeltaMap");
 
     let gl_context = glutin::ContextBuilder::new();
     let gl_window = glutin::GlWindow::new(builder, gl_context, &events_loop)?;
@@ -366,6 +522,27 @@ fn run() -> Result<(), Box<Error>> {
     let _ = unsafe { gl_window.make_current() };
     let mut cx = context::Context::from_gl_window(&gl_window);
 
+    // The resolution of high-DPI tiles is chosen once at startup.
+    config.set_dpi_factor(window.get_hidpi_factor());
+
+    let mut sources = TileSources::new(config.tile_sources().to_vec())
+        .ok_or_else(|| "no tile sources provided that are not overlays.")?;
+
+    let last_session = if config.open_last_session() {
+        config::read_last_session().ok()
+    } else {
+        None
+    };
+
+    if let Some(ref session) = last_session {
+        if let Some(tile_source) = session.tile_source() {
+            sources.switch_to_name(tile_source);
+        }
+        sources.restore_overlays(&session.overlays());
+    }
+
+    window.set_title(&format!("DeltaMap - {}", sources.current_name()));
+
     let mut input_state = InputState {
         mouse_position: LogicalPosition::new(0.0, 0.0),
         mouse_pressed: false,
@@ -378,19 +555,37 @@ fn run() -> Result<(), Box<Error>> {
         incomplete_ways: vec![],
     };
 
+    let max_tile_size = sources.max_tile_size();
+
     let mut map = {
         let proxy = events_loop.create_proxy();
 
+        let tile_cache = TileCache::new(
+            move |_tile| { proxy.wakeup().unwrap(); },
+            config.use_network(),
+            config.download_settings(),
+        );
+        let tile_cache = match config.memory_cache_size() {
+            Some(max_bytes) => tile_cache.with_memory_budget(max_bytes),
+            None => tile_cache,
+        }
+        .with_prefetch(config.prefetch_settings());
+
         map_view_gl::MapViewGl::new(
             &mut cx,
             input_state.viewport_size.to_physical(input_state.dpi_factor).into(),
             input_state.dpi_factor,
-            move || { proxy.wakeup().unwrap(); },
-            config.use_network(),
+            max_tile_size,
+            tile_cache,
             config.async(),
         )
     };
 
+    // The network may be enabled later by reloading the configuration, and a cache that is too
+    // large for a reduced limit has to shrink in any case.
+    let cache_limits = Arc::new(Mutex::new(config.cache_limits()));
+    disk_cache::spawn_eviction(Arc::clone(&cache_limits));
+
     if let Some(ref session) = last_session {
         map.restore_session(session)?;
     }
@@ -423,20 +618,38 @@ fn run() -> Result<(), Box<Error>> {
         )?;
     }
 
-    let duration_per_frame = Duration::from_millis((1000.0 / config.fps() - 0.5).max(0.0).floor() as u64);
+    let (reload_tx, reload_rx) = mpsc::channel();
+    let (watch_paths_tx, watch_paths_rx) = mpsc::channel();
+    {
+        let proxy = events_loop.create_proxy();
+        let paths = config.file_paths();
+        info!("watch configuration files {:?}", paths);
+        file_watcher::watch(paths, Duration::from_secs(1), watch_paths_rx, move || {
+            reload_tx.send(()).is_ok() && proxy.wakeup().is_ok()
+        });
+    }
+
+    let mut duration_per_frame = frame_duration(config.fps());
     info!("milliseconds per frame: {}", dur_to_sec(duration_per_frame) * 1000.0);
 
     // estimated draw duration
     let mut est_draw_dur = duration_per_frame;
     let mut last_draw = Instant::now();
     let mut increase_atlas_size_possible = true;
+    let mut playback = Playback::default();
+    let mut next_step = None;
+    let (step_tx, step_rx) = mpsc::channel();
+    {
+        let proxy = events_loop.create_proxy();
+        playback::spawn_timer(step_rx, move || proxy.wakeup().is_ok());
+    }
 
     loop {
         let start_source_id = sources.current().id();
         let mut action = Action::Nothing;
 
         events_loop.run_forever(|event| {
-            let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &marker_rx);
+            let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &mut playback, &marker_rx);
             action.combine_with(a);
             ControlFlow::Break
         });
@@ -446,7 +659,7 @@ fn run() -> Result<(), Box<Error>> {
         }
 
         events_loop.poll_events(|event| {
-            let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &marker_rx);
+            let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &mut playback, &marker_rx);
             action.combine_with(a);
             if action == Action::Close {
                 return;
@@ -457,6 +670,48 @@ fn run() -> Result<(), Box<Error>> {
             break;
         }
 
+        if playback.advance(Instant::now()) {
+            if sources.step_time(1, &playback) {
+                action.combine_with(Action::Redraw);
+            } else {
+                // The last time step is reached and looping is disabled.
+                playback.pause();
+            }
+        }
+
+        // The timer wakes up the event loop when the next time step of the animation is due.
+        if playback.next_step() != next_step {
+            next_step = playback.next_step();
+            let _ = step_tx.send(next_step);
+        }
+
+        if reload_rx.try_iter().count() > 0 {
+            match reload_config(
+                &arg_matches,
+                &config,
+                input_state.dpi_factor,
+                max_tile_size,
+                &mut sources,
+                &mut map,
+                &cache_limits,
+            ) {
+                Ok(new_config) => {
+                    info!("reloaded configuration files");
+                    // Imported files may have been added or removed.
+                    let paths = new_config.file_paths();
+                    if paths != config.file_paths() {
+                        info!("watch configuration files {:?}", paths);
+                        let _ = watch_paths_tx.send(paths);
+                    }
+                    config = new_config;
+                    duration_per_frame = frame_duration(config.fps());
+                    window.set_title(&format!("DeltaMap - {}", sources.current_name()));
+                    action.combine_with(Action::Redraw);
+                },
+                Err(err) => error!("could not reload configuration: {}", err),
+            }
+        }
+
         {
             let diff = last_draw.elapsed();
             if diff + est_draw_dur * 2 < duration_per_frame {
@@ -464,7 +719,7 @@ fn run() -> Result<(), Box<Error>> {
                     std::thread::sleep(dur);
 
                     events_loop.poll_events(|event| {
-                        let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &marker_rx);
+                        let a = handle_event(&event, &mut map, &mut input_state, &mut query_state, &mut sources, &mut playback, &marker_rx);
                         action.combine_with(a);
                         if action == Action::Close {
                             return;
@@ -495,10 +750,9 @@ fn run() -> Result<(), Box<Error>> {
         if redraw {
             let draw_start = Instant::now();
 
-            if !map.map_covers_viewport() {
-                cx.clear_color((0.2, 0.2, 0.2, 1.0));
-            }
-            let draw_result = map.draw(&mut cx, sources.current());
+            // Tiles may be transparent, so always clear the background.
+            cx.clear_color((0.2, 0.2, 0.2, 1.0));
+            let draw_result = map.draw(&mut cx, &sources.layers(&playback));
 
             let draw_dur = draw_start.elapsed();
 
@@ -520,6 +774,7 @@ fn run() -> Result<(), Box<Error>> {
             }
 
             debug!("draw: {} sec (est {} sec)", dur_to_sec(draw_dur), dur_to_sec(est_draw_dur));
+            debug!("tile cache: {}", map.tile_cache_stats());
 
             est_draw_dur = if draw_dur > est_draw_dur {
                 draw_dur
@@ -534,9 +789,12 @@ fn run() -> Result<(), Box<Error>> {
         }
     }
 
+    info!("tile cache: {}", map.tile_cache_stats());
+
     if config.open_last_session() {
         let mut session = map.to_session();
         session.set_tile_source(Some(sources.current_name()));
+        session.set_overlays(&sources.overlay_settings());
         config::save_session(&session)?;
     }
 
@@ -552,45 +810,255 @@ fn main() {
     }
 }
 
-struct TileSources<'a> {
+/// The tile sources with the currently selected base map and the stack of overlays.
+struct TileSources {
     current_index: usize,
-    sources: &'a [(String, TileSource)],
+    sources: Vec<(String, TileSource)>,
+    /// Indices of overlay sources and their settings, ordered from bottom to top.
+    overlays: Vec<(usize, OverlaySettings)>,
+    /// Id for the next source that is added by `reload`
+    next_id: u32,
 }
 
-impl<'a> TileSources<'a> {
-    pub fn new(sources: &'a [(String, TileSource)]) -> Option<TileSources> {
-        if sources.is_empty() {
-            None
-        } else {
-            Some(TileSources {
-                current_index: 0,
-                sources,
-            })
+impl TileSources {
+    /// Returns `None` if there is no source that can be used as a base map.
+    pub fn new(sources: Vec<(String, TileSource)>) -> Option<TileSources> {
+        let current_index = sources.iter().position(|(_, s)| s.overlay().is_none())?;
+        let overlays = sources.iter()
+            .enumerate()
+            .filter_map(|(index, (_, s))| s.overlay().map(|o| (index, o)))
+            .collect();
+        let next_id = sources.iter().map(|(_, s)| s.id().value() + 1).max().unwrap_or(0);
+
+        Some(TileSources {
+            current_index,
+            sources,
+            overlays,
+            next_id,
+        })
+    }
+
+    /// Replaces all sources, e.g. after the configuration files changed. Sources that load the
+    /// same tiles as a source with the same name keep its id and time step, so their loaded tiles
+    /// are reused. The current base map and the overlay settings are kept for sources with the
+    /// same name.
+    pub fn reload(&mut self, mut sources: Vec<(String, TileSource)>) -> Result<(), String> {
+        for (name, source) in &mut sources {
+            let unchanged = self.sources.iter()
+                .find(|(n, s)| n == name && s.loads_same_tiles(source))
+                .map(|(_, s)| (s.id(), s.time_index()));
+            match unchanged {
+                Some((id, time_index)) => {
+                    source.set_id(id);
+                    source.set_time_index(time_index);
+                },
+                None => {
+                    source.set_id(TileSourceId::new(self.next_id));
+                    self.next_id += 1;
+                },
+            }
         }
+
+        let next_id = self.next_id;
+        let mut new_sources = TileSources::new(sources)
+            .ok_or_else(|| "no tile sources provided that are not overlays.".to_string())?;
+        new_sources.next_id = next_id;
+
+        new_sources.switch_to_name(self.current_name());
+        let overlay_settings: Vec<(String, OverlaySettings)> = self.overlay_settings().into_iter()
+            .map(|(name, settings)| (name.to_string(), settings))
+            .collect();
+        new_sources.restore_overlays(&overlay_settings);
+
+        *self = new_sources;
+        Ok(())
     }
 
     pub fn current(&self) -> &TileSource {
         &self.sources[self.current_index].1
     }
 
+    /// Returns the ids of all sources.
+    pub fn ids(&self) -> Vec<TileSourceId> {
+        self.sources.iter().map(|(_, s)| s.id()).collect()
+    }
+
     pub fn current_name(&self) -> &str {
         &self.sources[self.current_index].0
     }
 
+    /// Returns the edge length of the largest tiles of all sources.
+    pub fn max_tile_size(&self) -> u32 {
+        self.sources.iter().map(|(_, s)| s.tile_size()).max().unwrap_or(256)
+    }
+
+    /// Returns the layers to draw, starting with the base map. The upcoming time steps of the
+    /// playback are loaded in advance.
+    pub fn layers(&self, playback: &Playback) -> Vec<TileLayer<'_>> {
+        let sources = &self.sources;
+        let upcoming_times = |source: &TileSource| {
+            playback.upcoming(source.time_index(), source.times().len())
+        };
+
+        let base_source = &sources[self.current_index].1;
+        let base = TileLayer {
+            source: base_source,
+            opacity: 1.0,
+            placeholder: true,
+            upcoming_times: upcoming_times(base_source),
+        };
+
+        let overlays = self.overlays.iter()
+            .filter(|&&(_, settings)| settings.visible && settings.opacity > 0.0)
+            .map(|&(index, settings)| {
+                TileLayer {
+                    source: &sources[index].1,
+                    opacity: settings.opacity,
+                    placeholder: false,
+                    upcoming_times: upcoming_times(&sources[index].1),
+                }
+            });
+
+        ::std::iter::once(base).chain(overlays).collect()
+    }
+
+    /// Moves the base map and the visible overlays that have a time dimension `delta` time steps
+    /// forward or backward. Returns false if none of them has such a time step.
+    pub fn step_time(&mut self, delta: i32, playback: &Playback) -> bool {
+        let visible_overlays = self.overlays.iter()
+            .filter(|&&(_, settings)| settings.visible)
+            .map(|&(index, _)| index);
+        let indices: Vec<usize> = ::std::iter::once(self.current_index).chain(visible_overlays).collect();
+
+        let mut stepped = false;
+        for index in indices {
+            let source = &mut self.sources[index].1;
+            if let Some(time_index) = playback.step(source.time_index(), source.times().len(), delta) {
+                source.set_time_index(time_index);
+                stepped = true;
+            }
+        }
+        stepped
+    }
+
+    /// Returns the names and settings of all overlays, ordered from bottom to top.
+    pub fn overlay_settings(&self) -> Vec<(&str, OverlaySettings)> {
+        self.overlays.iter()
+            .map(|&(index, settings)| (self.sources[index].0.as_str(), settings))
+            .collect()
+    }
+
+    /// Applies overlay settings from a session. Named overlays are moved to the bottom of the
+    /// stack in the given order.
+    pub fn restore_overlays(&mut self, settings: &[(String, OverlaySettings)]) {
+        for &(ref name, overlay_settings) in settings.iter().rev() {
+            let found = self.overlays.iter().position(|&(index, _)| self.sources[index].0 == *name);
+            if let Some(found) = found {
+                let (index, _) = self.overlays.remove(found);
+                self.overlays.insert(0, (index, overlay_settings));
+            }
+        }
+    }
+
+    /// Shows or hides the overlay at the given position in the stack.
+    pub fn toggle_overlay(&mut self, overlay_index: usize) {
+        if let Some(&mut (_, ref mut settings)) = self.overlays.get_mut(overlay_index) {
+            settings.visible = !settings.visible;
+        }
+    }
+
+    /// Steps through the opacities 1.0, 0.75, 0.5 and 0.25 of the overlay at the given position in
+    /// the stack.
+    pub fn cycle_overlay_opacity(&mut self, overlay_index: usize) {
+        if let Some(&mut (_, ref mut settings)) = self.overlays.get_mut(overlay_index) {
+            settings.visible = true;
+            settings.opacity = if settings.opacity <= 0.25 + 1e-3 {
+                1.0
+            } else {
+                ((settings.opacity - 1e-3) * 4.0).floor() * 0.25
+            };
+        }
+    }
+
     pub fn switch_to_next(&mut self) {
-        self.current_index = (self.current_index + 1) % self.sources.len();
+        let len = self.sources.len();
+        for offset in 1..len {
+            let index = (self.current_index + offset) % len;
+            if self.sources[index].1.overlay().is_none() {
+                self.current_index = index;
+                break;
+            }
+        }
     }
 
     pub fn switch_to_prev(&mut self) {
-        self.current_index = (self.current_index + self.sources.len().saturating_sub(1)) % self.sources.len();
+        let len = self.sources.len();
+        for offset in 1..len {
+            let index = (self.current_index + len - offset) % len;
+            if self.sources[index].1.overlay().is_none() {
+                self.current_index = index;
+                break;
+            }
+        }
     }
 
     pub fn switch_to_name(&mut self, name: &str) {
-        for (index, &(ref n, _)) in self.sources.iter().enumerate() {
-            if n == name {
+        for (index, &(ref n, ref source)) in self.sources.iter().enumerate() {
+            if n == name && source.overlay().is_none() {
                 self.current_index = index;
                 break;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url_template::UrlTemplate;
+
+    fn source(id: u32, url: &str, overlay: bool) -> TileSource {
+        let url_template = UrlTemplate::new(url).unwrap();
+        let source = TileSource::new(id, url_template, "cache", "png".to_string(), 0, 19);
+        if overlay {
+            source.with_overlay(OverlaySettings::default())
+        } else {
+            source
+        }
+    }
+
+    #[test]
+    fn reload_sources() {
+        let mut sources = TileSources::new(vec![
+            ("a".to_string(), source(0, "https://a.example.com/{z}/{x}/{y}.png", false)),
+            ("b".to_string(), source(1, "https://b.example.com/{z}/{x}/{y}.png", false)),
+            ("c".to_string(), source(2, "https://c.example.com/{z}/{x}/{y}.png", true)),
+        ]).unwrap();
+        sources.switch_to_name("b");
+        sources.cycle_overlay_opacity(0);
+
+        // "a" is removed, "b" is unchanged and "c" has a new URL.
+        sources.reload(vec![
+            ("d".to_string(), source(0, "https://d.example.com/{z}/{x}/{y}.png", false)),
+            ("c".to_string(), source(1, "https://c.example.org/{z}/{x}/{y}.png", true)),
+            ("b".to_string(), source(2, "https://b.example.com/{z}/{x}/{y}.png", false)),
+        ]).unwrap();
+
+        assert_eq!(sources.current_name(), "b");
+        assert_eq!(sources.current().id(), TileSourceId::new(1));
+        let ids: Vec<_> = sources.sources.iter().map(|(_, s)| s.id().value()).collect();
+        assert_eq!(ids, vec![3, 4, 1]);
+        assert_eq!(sources.overlay_settings(), vec![("c", OverlaySettings { opacity: 0.75, visible: true })]);
+
+        // The current source falls back to the first base map if it was removed.
+        sources.reload(vec![
+            ("d".to_string(), source(0, "https://d.example.com/{z}/{x}/{y}.png", false)),
+        ]).unwrap();
+        assert_eq!(sources.current_name(), "d");
+        assert_eq!(sources.current().id(), TileSourceId::new(3));
+
+        let only_overlays = vec![("c".to_string(), source(0, "https://c.example.com/{z}/{x}/{y}.png", true))];
+        assert!(sources.reload(only_overlays).is_err());
+        assert_eq!(sources.current_name(), "d");
+    }
+}
@@ -1,25 +1,40 @@
 use atmos_layer::AtmosLayer;
+use attribution_layer::{AttributionLayer, Corner};
 use cgmath::vec2;
 use context::Context;
-use coord::{MapCoord, ScreenCoord};
+use coord::{MapCoord, ScreenCoord, View};
 use marker_layer::MarkerLayer;
 use mercator_tile_layer::MercatorTileLayer;
 use mercator_view::MercatorView;
 use ortho_tile_layer::OrthoTileLayer;
 use orthografic_view::OrthograficView;
 use path_layer::{PathElement, PathLayer};
+use prefetch::PrefetchSettings;
 use projection::Projection;
 use projection_view::ProjectionView;
 use session::Session;
 use texture::{Texture, TextureFormat};
 use tile_atlas::TileAtlas;
-use tile_cache::TileCache;
-use tile_source::TileSource;
+use tile_cache::{CacheStats, TileCache};
+use tile_source::{TileSource, TileSourceId};
 
 
 pub const MIN_TILE_ZOOM_OFFSET: f64 = -4.0;
 pub const MAX_TILE_ZOOM_OFFSET: f64 = 4.0;
 
+/// A tile source and how it is drawn.
+#[derive(Clone, Debug)]
+pub struct TileLayer<'a> {
+    pub source: &'a TileSource,
+    /// Opacity in the interval [0.0, 1.0]
+    pub opacity: f32,
+    /// Draw a placeholder for tiles that are not available. This is only useful for the bottom
+    /// layer.
+    pub placeholder: bool,
+    /// Time steps of the source that are loaded in advance, e.g. the next frames of an animation.
+    pub upcoming_times: Vec<u32>,
+}
+
 #[derive(Debug)]
 pub struct MapViewGl {
     proj_view: ProjectionView,
@@ -33,8 +48,12 @@ pub struct MapViewGl {
     path_layer: PathLayer,
     ortho_tile_layer: OrthoTileLayer,
     atmos_layer: AtmosLayer,
+    attribution_layer: AttributionLayer,
+    /// Shows statistics of the tile cache
+    debug_layer: AttributionLayer,
     show_marker: bool,
     show_atmos: bool,
+    show_debug_overlay: bool,
     last_draw_type: DrawType,
 }
 
@@ -42,6 +61,8 @@ pub struct MapViewGl {
 enum DrawType {
     Null,
     Atmos,
+    Attribution,
+    DebugOverlay,
     Markers,
     OrthoTiles,
     Path,
@@ -49,17 +70,19 @@ enum DrawType {
 }
 
 impl MapViewGl {
-    pub fn new<F>(
+    /// Creates a new map view. `max_tile_size` is the edge length in pixels of the largest tiles
+    /// that are going to be drawn.
+    pub fn new(
         cx: &mut Context,
         initial_size: (u32, u32),
         dpi_factor: f64,
-        update_func: F,
-        use_network: bool,
+        max_tile_size: u32,
+        mut tile_cache: TileCache,
         use_async: bool,
         ) -> MapViewGl
-        where F: Fn() + Sync + Send + 'static,
     {
         let tile_size = 256;
+        let slot_size = tile_size.max(max_tile_size);
 
         let proj_view = ProjectionView::Mercator(
             MercatorView::initial_view(
@@ -70,12 +93,13 @@ impl MapViewGl {
         );
 
         let atlas_size = {
-            let default_size = 2048;
+            // Make room for at least 8x8 tiles
+            let default_size = (slot_size * 8).next_power_of_two().max(2048);
             let max_size = cx.max_texture_size() as u32;
             if default_size <= max_size {
                 default_size
             } else {
-                if tile_size * 3 > max_size {
+                if slot_size * 3 > max_size {
                     error!("maximal tile size ({}) is too small", max_size);
                 }
 
@@ -83,28 +107,34 @@ impl MapViewGl {
             }
         };
 
-        let atlas_tex = Texture::empty(cx, atlas_size, atlas_size, TextureFormat::Rgb8);
+        // Overlay tiles need an alpha channel
+        let atlas_tex = Texture::empty(cx, atlas_size, atlas_size, TextureFormat::Rgba8);
         check_gl_errors!(cx);
 
-        let tile_atlas = TileAtlas::new(cx, atlas_tex, tile_size, use_async);
+        let tile_atlas = TileAtlas::new(cx, atlas_tex, slot_size, use_async);
 
         let mercator_tile_layer = MercatorTileLayer::new(cx, &tile_atlas);
         let ortho_tile_layer = OrthoTileLayer::new(cx, &tile_atlas);
         let atmos_layer = AtmosLayer::new(cx);
 
+        tile_cache.set_viewport_size(initial_size);
+
         MapViewGl {
             proj_view,
             viewport_size: initial_size,
             dpi_factor,
-            tile_cache: TileCache::new(move |_tile| update_func(), use_network),
+            tile_cache,
             tile_atlas,
             mercator_tile_layer,
             marker_layer: MarkerLayer::new(cx),
             path_layer: PathLayer::new(cx),
             ortho_tile_layer,
             atmos_layer,
+            attribution_layer: AttributionLayer::new(cx),
+            debug_layer: AttributionLayer::new(cx).with_corner(Corner::TopLeft),
             show_marker: true,
             show_atmos: false,
+            show_debug_overlay: false,
             last_draw_type: DrawType::Null,
         }
     }
@@ -117,6 +147,7 @@ impl MapViewGl {
             ProjectionView::Orthografic(ortho) => ortho.viewport_size = vec_size,
         }
         cx.set_viewport(0, 0, width, height);
+        self.tile_cache.set_viewport_size(self.viewport_size);
     }
 
     pub fn set_dpi_factor(&mut self, dpi_factor: f64) {
@@ -159,7 +190,34 @@ impl MapViewGl {
         self.show_atmos = !self.show_atmos;
     }
 
-    fn draw_mercator_tiles(&mut self, cx: &mut Context, merc: &MercatorView, source: &TileSource, snap_to_pixel: bool)
+    pub fn toggle_debug_overlay(&mut self) {
+        self.show_debug_overlay = !self.show_debug_overlay;
+    }
+
+    /// Enables or disables downloading tiles.
+    pub fn set_use_network(&mut self, use_network: bool) {
+        self.tile_cache.set_use_network(use_network);
+    }
+
+    /// Set a fixed memory budget in bytes for the decoded tiles or `None` for the default budget.
+    pub fn set_memory_budget(&mut self, max_bytes: Option<usize>) {
+        self.tile_cache.set_memory_budget(max_bytes);
+    }
+
+    pub fn set_prefetch(&mut self, settings: PrefetchSettings) {
+        self.tile_cache.set_prefetch(settings);
+    }
+
+    /// Stops loading tiles of sources other than the given ones, e.g. after a reload.
+    pub fn retain_sources(&mut self, ids: &[TileSourceId]) {
+        self.tile_cache.retain_sources(ids);
+    }
+
+    pub fn tile_cache_stats(&self) -> CacheStats {
+        self.tile_cache.stats()
+    }
+
+    fn draw_mercator_tiles(&mut self, cx: &mut Context, merc: &MercatorView, layer: &TileLayer, snap_to_pixel: bool)
         -> Result<usize, usize>
     {
         if self.last_draw_type != DrawType::Tiles {
@@ -170,7 +228,7 @@ impl MapViewGl {
         self.mercator_tile_layer.draw(
             cx,
             merc,
-            source,
+            layer,
             &mut self.tile_cache,
             &mut self.tile_atlas,
             snap_to_pixel
@@ -219,7 +277,7 @@ impl MapViewGl {
         );
     }
 
-    fn draw_ortho_tiles(&mut self, cx: &mut Context, ortho: &OrthograficView, source: &TileSource) -> Result<usize, usize> {
+    fn draw_ortho_tiles(&mut self, cx: &mut Context, ortho: &OrthograficView, layer: &TileLayer) -> Result<usize, usize> {
         if self.last_draw_type != DrawType::OrthoTiles {
             self.last_draw_type = DrawType::OrthoTiles;
             self.ortho_tile_layer.prepare_draw(cx, &self.tile_atlas);
@@ -228,7 +286,7 @@ impl MapViewGl {
         self.ortho_tile_layer.draw(
             cx,
             ortho,
-            source,
+            layer,
             &mut self.tile_cache,
             &mut self.tile_atlas,
         )
@@ -246,32 +304,115 @@ impl MapViewGl {
         )
     }
 
+    fn draw_attribution(&mut self, cx: &mut Context, layers: &[TileLayer]) {
+        let mut texts: Vec<&str> = vec![];
+        for layer in layers {
+            if let Some(ref attribution) = layer.source.info().attribution {
+                if !texts.contains(&attribution.as_str()) {
+                    texts.push(attribution);
+                }
+            }
+        }
+        // The time steps of animated layers are shown next to the attribution.
+        for layer in layers {
+            if let Some(time) = layer.source.current_time() {
+                if !texts.contains(&time) {
+                    texts.push(time);
+                }
+            }
+        }
+
+        // The font is tiny, so scale it up even on low-DPI screens.
+        let scale = (self.dpi_factor * 1.5).round().max(1.0) as u32;
+        self.attribution_layer.set_text(cx, &texts.join(" | "), scale);
+
+        if self.attribution_layer.is_empty() {
+            return;
+        }
+
+        if self.last_draw_type != DrawType::Attribution {
+            self.last_draw_type = DrawType::Attribution;
+            self.attribution_layer.prepare_draw(cx);
+        }
+
+        self.attribution_layer.draw(cx, self.viewport_size);
+    }
+
+    fn draw_debug_overlay(&mut self, cx: &mut Context) {
+        let scale = (self.dpi_factor * 1.5).round().max(1.0) as u32;
+        let text = format!("tile cache: {}", self.tile_cache.stats());
+        self.debug_layer.set_text(cx, &text, scale);
+
+        if self.last_draw_type != DrawType::DebugOverlay {
+            self.last_draw_type = DrawType::DebugOverlay;
+            self.debug_layer.prepare_draw(cx);
+        }
+
+        self.debug_layer.draw(cx, self.viewport_size);
+    }
+
+    /// Draws the given tile layers from bottom to top and the attribution of their sources.
     /// Returns `Err` when tile cache is too small for this view.
-    /// Returns the number of OpenGL draw calls, which can be decreased to `1` by increasing the
-    /// size of the tile atlas.
-    pub fn draw(&mut self, cx: &mut Context, source: &TileSource) -> Result<usize, usize> {
+    /// Returns the maximum number of OpenGL draw calls per layer, which can be decreased to `1` by
+    /// increasing the size of the tile atlas.
+    pub fn draw(&mut self, cx: &mut Context, layers: &[TileLayer]) -> Result<usize, usize> {
+        for tile in self.tile_cache.take_replaced_tiles() {
+            self.tile_atlas.invalidate_tile(tile);
+        }
+
         match self.proj_view.clone() {
             ProjectionView::Mercator(ref merc) => {
                 // only snap to pixel grid if zoom has integral value
                 let snap_to_pixel = (merc.zoom - (merc.zoom + 0.5).floor()).abs() < 1e-10;
 
-                let ret = self.draw_mercator_tiles(cx, merc, source, snap_to_pixel);
+                // Loading is prioritized for the base map, overlays are ranked after it.
+                if let Some(base) = layers.first() {
+                    self.tile_cache.set_view_location(View {
+                        source_id: base.source.id(),
+                        zoom: merc.tile_zoom(base.source.tile_size()),
+                        center: merc.center,
+                        radius: merc.map_radius(),
+                    });
+                }
+
+                let ret = combine_draw_results(layers.iter().map(|layer| {
+                    self.draw_mercator_tiles(cx, merc, layer, snap_to_pixel)
+                }));
 
                 self.draw_mercator_path(cx, merc, snap_to_pixel);
 
                 if self.show_marker && !self.marker_layer.is_empty() {
                     self.draw_mercator_marker(cx, merc, snap_to_pixel);
                 }
+                self.draw_attribution(cx, layers);
+                if self.show_debug_overlay {
+                    self.draw_debug_overlay(cx);
+                }
                 ret
             },
             ProjectionView::Orthografic(ref ortho) => {
-                let ret = self.draw_ortho_tiles(cx, ortho, source);
+                if let Some(base) = layers.first() {
+                    self.tile_cache.set_view_location(View {
+                        source_id: base.source.id(),
+                        zoom: ortho.tile_zoom(base.source.tile_size()),
+                        center: ortho.center,
+                        radius: ortho.map_radius(),
+                    });
+                }
+
+                let ret = combine_draw_results(layers.iter().map(|layer| {
+                    self.draw_ortho_tiles(cx, ortho, layer)
+                }));
                 if self.show_marker && !self.marker_layer.is_empty() {
                     self.draw_ortho_marker(cx, ortho);
                 }
                 if self.show_atmos {
                     self.draw_atmos(cx, ortho);
                 }
+                self.draw_attribution(cx, layers);
+                if self.show_debug_overlay {
+                    self.draw_debug_overlay(cx);
+                }
                 ret
             },
         }
@@ -355,8 +496,34 @@ impl MapViewGl {
             },
         };
 
-        Session {
+        let mut session = Session {
             view,
+        };
+        session.set_viewport_size(self.viewport_size);
+        session
+    }
+}
+
+/// Combines the results of drawing multiple layers into the maximum number of draw calls. The
+/// result is `Err` if any of the layers failed.
+fn combine_draw_results<I>(results: I) -> Result<usize, usize>
+    where I: Iterator<Item=Result<usize, usize>>,
+{
+    let mut draws = 0;
+    let mut failed = false;
+    for res in results {
+        match res {
+            Ok(x) => draws = draws.max(x),
+            Err(x) => {
+                draws = draws.max(x);
+                failed = true;
+            },
         }
     }
+
+    if failed {
+        Err(draws)
+    } else {
+        Ok(draws)
+    }
 }
@@ -0,0 +1,294 @@
+//! Reading and writing tiles from and to MBTiles files.
+//!
+//! An MBTiles file is an SQLite database with a `tiles` table that stores the encoded tile images
+//! and a `metadata` table with key/value pairs. See https://github.com/mapbox/mbtiles-spec
+
+use coord::{BoundingBox, TileCoord};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, ToSql, NO_PARAMS};
+use std::path::{Path, PathBuf};
+
+
+/// Highest zoom level of tiles that are read from a file or collected for an export
+pub const MAX_ZOOM: u32 = 30;
+
+
+/// An open MBTiles file.
+#[derive(Debug)]
+pub struct MbTiles {
+    path: PathBuf,
+    conn: Connection,
+}
+
+/// Information from the metadata table of an MBTiles file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MbTilesMetadata {
+    /// The image format of the tiles, e.g. "png" or "jpg"
+    pub format: Option<String>,
+    pub min_zoom: Option<u32>,
+    pub max_zoom: Option<u32>,
+    pub bounds: Option<BoundingBox>,
+    pub attribution: Option<String>,
+    pub description: Option<String>,
+}
+
+impl MbTiles {
+    /// Opens the given MBTiles file for reading.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MbTiles, String> {
+        let conn = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        ).map_err(|e| format!("could not open MBTiles file {:?}: {}", path.as_ref(), e))?;
+
+        Ok(MbTiles {
+            path: path.as_ref().to_path_buf(),
+            conn,
+        })
+    }
+
+    /// Creates a new MBTiles file with empty tables. Fails if the file already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<MbTiles, String> {
+        if path.as_ref().exists() {
+            return Err(format!("MBTiles file {:?} already exists", path.as_ref()));
+        }
+
+        let conn = Connection::open(&path)
+            .map_err(|e| format!("could not create MBTiles file {:?}: {}", path.as_ref(), e))?;
+
+        let mbtiles = MbTiles {
+            path: path.as_ref().to_path_buf(),
+            conn,
+        };
+
+        mbtiles.conn.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE UNIQUE INDEX name ON metadata (name);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+             CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);"
+        ).map_err(|e| mbtiles.error(e))?;
+
+        Ok(mbtiles)
+    }
+
+    /// Sets an entry of the metadata table.
+    pub fn set_metadata(&self, name: &str, value: &str) -> Result<(), String> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+            &[name, value],
+        )
+        .map(|_| ())
+        .map_err(|e| self.error(e))
+    }
+
+    /// Reads the metadata table. Missing zoom levels are determined from the stored tiles.
+    pub fn metadata(&self) -> Result<MbTilesMetadata, String> {
+        let mut metadata = MbTilesMetadata {
+            format: None,
+            min_zoom: None,
+            max_zoom: None,
+            bounds: None,
+            attribution: None,
+            description: None,
+        };
+
+        let mut stmt = self.conn.prepare("SELECT name, value FROM metadata")
+            .map_err(|e| self.error(e))?;
+        let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(|e| self.error(e))?;
+
+        for row in rows {
+            let (name, value) = row.map_err(|e| self.error(e))?;
+            match name.as_str() {
+                "format" => metadata.format = Some(value),
+                "minzoom" => metadata.min_zoom = value.trim().parse().ok(),
+                "maxzoom" => metadata.max_zoom = value.trim().parse().ok(),
+                "bounds" => metadata.bounds = parse_bounds(&value),
+                "attribution" => metadata.attribution = Some(value),
+                "description" => metadata.description = Some(value),
+                _ => {},
+            }
+        }
+
+        if metadata.min_zoom.is_none() || metadata.max_zoom.is_none() {
+            let (min_zoom, max_zoom) = self.conn.query_row(
+                "SELECT MIN(zoom_level), MAX(zoom_level) FROM tiles",
+                NO_PARAMS,
+                |row| Ok((row.get::<_, Option<u32>>(0)?, row.get::<_, Option<u32>>(1)?)),
+            ).map_err(|e| self.error(e))?;
+            metadata.min_zoom = metadata.min_zoom.or(min_zoom);
+            metadata.max_zoom = metadata.max_zoom.or(max_zoom);
+        }
+
+        Ok(metadata)
+    }
+
+    /// Returns the encoded image data of the given tile or `None` if the tile is not stored in the
+    /// file. Rows are flipped as MBTiles uses the TMS numbering.
+    pub fn read_tile(&self, tile_coord: TileCoord) -> Result<Option<Vec<u8>>, String> {
+        let tms_coord = tile_coord.flip_y();
+        self.conn.query_row(
+            "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+            [i64::from(tms_coord.zoom), i64::from(tms_coord.x), i64::from(tms_coord.y)],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| self.error(e))
+    }
+
+    /// Stores the encoded image data of tiles in a single transaction and returns the number of
+    /// written tiles. Existing tiles are replaced.
+    pub fn write_tiles<I>(&mut self, tiles: I) -> Result<usize, String>
+        where I: IntoIterator<Item=(TileCoord, Vec<u8>)>,
+    {
+        let path = &self.path;
+        let error = |e| format!("MBTiles file {:?}: {}", path, e);
+
+        let tx = self.conn.transaction().map_err(error)?;
+        let mut count = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)"
+            ).map_err(error)?;
+
+            for (tile_coord, data) in tiles {
+                let tms_coord = tile_coord.flip_y();
+                stmt.execute(&[
+                    &tms_coord.zoom as &dyn ToSql, &tms_coord.x, &tms_coord.y, &data,
+                ]).map_err(error)?;
+                count += 1;
+            }
+        }
+        tx.commit().map_err(error)?;
+
+        Ok(count)
+    }
+
+    /// Calls `f` with each valid tile in XYZ numbering and its encoded image data.
+    pub fn for_each_tile<F>(&self, mut f: F) -> Result<(), String>
+        where F: FnMut(TileCoord, Vec<u8>) -> Result<(), String>,
+    {
+        let mut stmt = self.conn.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+            .map_err(|e| self.error(e))?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, i32>(1)?, row.get::<_, i32>(2)?, row.get::<_, Vec<u8>>(3)?))
+        }).map_err(|e| self.error(e))?;
+
+        for row in rows {
+            let (zoom, x, y, data) = row.map_err(|e| self.error(e))?;
+            let tms_coord = TileCoord { zoom, x, y };
+            if zoom <= MAX_ZOOM && tms_coord.is_valid() {
+                f(tms_coord.flip_y(), data)?;
+            } else {
+                warn!("MBTiles file {:?}: skip invalid tile {:?}", self.path, tms_coord);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn error(&self, e: ::rusqlite::Error) -> String {
+        format!("MBTiles file {:?}: {}", self.path, e)
+    }
+}
+
+/// Parses bounds in the format "left,bottom,right,top".
+fn parse_bounds(s: &str) -> Option<BoundingBox> {
+    let values = s.split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .ok()?;
+
+    if values.len() == 4 {
+        Some(BoundingBox::new(values[0], values[1], values[2], values[3]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use mbtiles::*;
+    use test_util::TempDir;
+
+    /// Creates an MBTiles file in `dir` with the given metadata and tiles in XYZ numbering.
+    pub fn create_mbtiles(dir: &Path, file_name: &str, metadata: &[(&str, &str)], tiles: &[(TileCoord, &[u8])]) -> PathBuf {
+        let path = dir.join(file_name);
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);"
+        ).unwrap();
+
+        for &(name, value) in metadata {
+            conn.execute("INSERT INTO metadata (name, value) VALUES (?1, ?2)", &[name, value]).unwrap();
+        }
+
+        for &(coord, data) in tiles {
+            let tms = coord.flip_y();
+            conn.execute(
+                "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+                &[&tms.zoom as &dyn ToSql, &tms.x, &tms.y, &data],
+            ).unwrap();
+        }
+
+        path
+    }
+
+    #[test]
+    fn metadata() {
+        let dir = TempDir::new("deltamap_test_metadata");
+        let path = create_mbtiles(
+            &dir,
+            "metadata.mbtiles",
+            &[
+                ("format", "png"),
+                ("minzoom", "2"),
+                ("maxzoom", "14"),
+                ("bounds", "5.8, 47.2, 15.1, 55.1"),
+                ("attribution", "© Example"),
+            ],
+            &[],
+        );
+        let mbtiles = MbTiles::open(&path).unwrap();
+        assert_eq!(
+            mbtiles.metadata().unwrap(),
+            MbTilesMetadata {
+                format: Some("png".to_string()),
+                min_zoom: Some(2),
+                max_zoom: Some(14),
+                bounds: Some(BoundingBox::new(5.8, 47.2, 15.1, 55.1)),
+                attribution: Some("© Example".to_string()),
+                description: None,
+            }
+        );
+
+        let dir = TempDir::new("deltamap_test_metadata_zoom");
+        let path = create_mbtiles(
+            &dir,
+            "metadata_zoom.mbtiles",
+            &[("format", "jpg")],
+            &[(TileCoord::new(3, 0, 0), b"a"), (TileCoord::new(7, 0, 0), b"b")],
+        );
+        let metadata = MbTiles::open(&path).unwrap().metadata().unwrap();
+        assert_eq!(metadata.min_zoom, Some(3));
+        assert_eq!(metadata.max_zoom, Some(7));
+        assert_eq!(metadata.bounds, None);
+
+        assert!(MbTiles::open(dir.join("nonexistent.mbtiles")).is_err());
+    }
+
+    #[test]
+    fn read_tile() {
+        let dir = TempDir::new("deltamap_test_read_tile");
+        let path = create_mbtiles(
+            &dir,
+            "read_tile.mbtiles",
+            &[],
+            &[(TileCoord::new(2, 1, 0), b"north"), (TileCoord::new(2, 1, 3), b"south")],
+        );
+        let mbtiles = MbTiles::open(&path).unwrap();
+        assert_eq!(mbtiles.read_tile(TileCoord::new(2, 1, 0)), Ok(Some(b"north".to_vec())));
+        assert_eq!(mbtiles.read_tile(TileCoord::new(2, 1, 3)), Ok(Some(b"south".to_vec())));
+        assert_eq!(mbtiles.read_tile(TileCoord::new(2, 1, 1)), Ok(None));
+    }
+}
@@ -1,12 +1,11 @@
 use ::std::ffi::CStr;
 use buffer::{Buffer, DrawMode};
 use context::Context;
-use coord::View;
 use mercator_view::MercatorView;
-use program::Program;
+use map_view_gl::TileLayer;
+use program::{Program, UniformId};
 use tile_atlas::{TileAtlas, VisibleTilesProvider};
 use tile_cache::TileCache;
-use tile_source::TileSource;
 use vertex_attrib::VertexAttribParams;
 
 
@@ -14,6 +13,7 @@ use vertex_attrib::VertexAttribParams;
 pub struct MercatorTileLayer {
     program: Program,
     buffer: Buffer,
+    opacity_uniform: UniformId,
 }
 
 
@@ -34,6 +34,7 @@ impl MercatorTileLayer {
         ).unwrap();
 
         program.add_texture(cx, atlas.texture(), CStr::from_bytes_with_nul(b"tex_map\0").unwrap());
+        let opacity_uniform = program.get_uniform_id(cx, CStr::from_bytes_with_nul(b"opacity\0").unwrap()).unwrap();
 
         program.add_attribute(
             cx,
@@ -54,6 +55,7 @@ impl MercatorTileLayer {
         MercatorTileLayer {
             program,
             buffer,
+            opacity_uniform,
         }
     }
 
@@ -68,21 +70,22 @@ impl MercatorTileLayer {
         &mut self,
         cx: &mut Context,
         merc: &MercatorView,
-        source: &TileSource,
+        layer: &TileLayer,
         cache: &mut TileCache,
         atlas: &mut TileAtlas,
         snap_to_pixel: bool
     ) -> Result<usize, usize> {
-        cache.set_view_location(View {
-            source_id: source.id(),
-            zoom: merc.tile_zoom(),
-            center: merc.center,
-        });
+        let source = layer.source;
+        self.program.set_uniform_1f(cx, self.opacity_uniform, layer.opacity);
 
-        let visible_tiles = merc.visible_tiles(snap_to_pixel);
+        let visible_tiles = merc.visible_tiles(snap_to_pixel, source.tile_size());
+        if atlas.use_async() {
+            let tile_coords: Vec<_> = visible_tiles.iter().map(|vt| vt.tile).collect();
+            cache.prefetch(&tile_coords, source, &layer.upcoming_times);
+        }
         let mut remainder = visible_tiles.as_slice();
         let mut num_draws = 0;
-        let mut max_tiles_to_use = cache.max_tiles();
+        let mut max_tiles_to_use = cache.max_tiles(source.tile_size());
 
         loop {
             let (textured_visible_tiles, remainder_opt, used_tiles) = {
@@ -92,6 +95,7 @@ impl MercatorTileLayer {
                     max_tiles_to_use,
                     source,
                     cache,
+                    layer.placeholder,
                 )
             };
 
@@ -136,6 +136,17 @@ impl MercatorView {
         MapCoord::new(x, y)
     }
 
+    /// Returns the map coordinate of the bottom-right corner of the viewport. It is not wrapped
+    /// around in x-direction.
+    pub fn bottom_right_coord(&self) -> MapCoord {
+        let scale = f64::powf(2.0, -self.zoom) / f64::from(self.tile_size);
+
+        let x = self.center.x + 0.5 * self.viewport_size.x * scale;
+        let y = self.center.y + 0.5 * self.viewport_size.y * scale;
+
+        MapCoord::new(x, y)
+    }
+
     /// Returns the screen coordinate that corresponds to the given map coordinate.
     pub fn map_to_screen_coord(&self, map_coord: MapCoord) -> ScreenCoord {
         let scale = f64::powf(2.0, self.zoom) * f64::from(self.tile_size);
@@ -175,14 +186,21 @@ impl MercatorView {
         y_top >= 0.0 && y_bottom <= 1.0
     }
 
+    /// Returns the distance from the center to the corners of the viewport in map coordinates.
+    pub fn map_radius(&self) -> f64 {
+        let scale = f64::powf(2.0, -self.zoom) / f64::from(self.tile_size);
+        0.5 * self.viewport_size.x.hypot(self.viewport_size.y) * scale
+    }
+
     /// Returns the screen coordinate of the top-left corner of a tile.
     pub fn tile_screen_position(&self, tile: &TileCoord) -> ScreenCoord {
         self.map_to_screen_coord(tile.map_coord_north_west())
     }
 
-    /// Returns a `Vec` of all tiles that are visible in the current viewport.
-    pub fn visible_tiles(&self, snap_to_pixel: bool) -> Vec<VisibleTile> {
-        let uzoom = self.tile_zoom();
+    /// Returns a `Vec` of all tiles that are visible in the current viewport. `source_tile_size`
+    /// is the edge length of the tile images in pixels.
+    pub fn visible_tiles(&self, snap_to_pixel: bool, source_tile_size: u32) -> Vec<VisibleTile> {
+        let uzoom = self.tile_zoom(source_tile_size);
         let top_left_tile = self.top_left_coord().on_tile_at_zoom(uzoom);
         let mut top_left_tile_screen_coord = self.tile_screen_position(&top_left_tile);
         let tile_screen_size = f64::powf(2.0, self.zoom - f64::from(uzoom)) *
@@ -223,9 +241,11 @@ impl MercatorView {
         visible_tiles
     }
 
-    /// Returns the tile zoom value that is used for rendering with the current zoom.
-    pub fn tile_zoom(&self) -> u32 {
-        (self.zoom + self.tile_zoom_offset).floor().max(0.0) as u32
+    /// Returns the tile zoom value that is used for rendering with the current zoom. Tiles that
+    /// are larger than `tile_size` are taken from a lower zoom level.
+    pub fn tile_zoom(&self, source_tile_size: u32) -> u32 {
+        let size_offset = (f64::from(self.tile_size) / f64::from(source_tile_size)).log2();
+        (self.zoom + self.tile_zoom_offset + size_offset).floor().max(0.0) as u32
     }
 
     /// Change zoom value by `zoom_delta` and zoom to a position given in screen coordinates.
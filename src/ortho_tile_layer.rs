@@ -1,13 +1,13 @@
 use buffer::{Buffer, DrawMode};
 use cgmath::Transform;
 use context::Context;
-use coord::{LatLonRad, ScreenCoord, View};
+use coord::{LatLonRad, ScreenCoord};
 use orthografic_view::OrthograficView;
-use program::Program;
+use map_view_gl::TileLayer;
+use program::{Program, UniformId};
 use std::ffi::CStr;
 use tile_atlas::{TileAtlas, VisibleTilesProvider};
 use tile_cache::TileCache;
-use tile_source::TileSource;
 use vertex_attrib::VertexAttribParams;
 
 
@@ -15,6 +15,7 @@ use vertex_attrib::VertexAttribParams;
 pub struct OrthoTileLayer {
     program: Program,
     buffer: Buffer,
+    opacity_uniform: UniformId,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -57,6 +58,7 @@ impl OrthoTileLayer {
         ).unwrap();
 
         program.add_texture(cx, atlas.texture(), CStr::from_bytes_with_nul(b"tex_map\0").unwrap());
+        let opacity_uniform = program.get_uniform_id(cx, CStr::from_bytes_with_nul(b"opacity\0").unwrap()).unwrap();
 
         program.add_attribute(
             cx,
@@ -77,6 +79,7 @@ impl OrthoTileLayer {
         OrthoTileLayer {
             program,
             buffer,
+            opacity_uniform,
         }
     }
 
@@ -91,23 +94,24 @@ impl OrthoTileLayer {
         &mut self,
         cx: &mut Context,
         ortho: &OrthograficView,
-        source: &TileSource,
+        layer: &TileLayer,
         cache: &mut TileCache,
         tile_atlas: &mut TileAtlas,
     ) -> Result<usize, usize> {
         //TODO Add distance function to TileCache that takes topology of the sphere into account.
-        cache.set_view_location(View {
-            source_id: source.id(),
-            zoom: ortho.tile_zoom(),
-            center: ortho.center,
-        });
+        let source = layer.source;
+        self.program.set_uniform_1f(cx, self.opacity_uniform, layer.opacity);
 
         let transform = ortho.transformation_matrix();
 
-        let visible_tiles = ortho.visible_tiles();
+        let visible_tiles = ortho.visible_tiles(source.tile_size());
+        if tile_atlas.use_async() {
+            let tile_coords: Vec<_> = visible_tiles.iter().map(|vt| vt.tile).collect();
+            cache.prefetch(&tile_coords, source, &layer.upcoming_times);
+        }
         let mut remainder = visible_tiles.as_slice();
         let mut num_draws = 0;
-        let mut max_tiles_to_use = cache.max_tiles();
+        let mut max_tiles_to_use = cache.max_tiles(source.tile_size());
 
         loop {
             let (textured_visible_tiles, remainder_opt, used_tiles) = {
@@ -117,6 +121,7 @@ impl OrthoTileLayer {
                     max_tiles_to_use,
                     source,
                     cache,
+                    layer.placeholder,
                 )
             };
 
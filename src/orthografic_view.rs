@@ -243,6 +243,17 @@ impl OrthograficView {
         Projection::Orthografic
     }
 
+    /// Returns an estimate of the distance from the center to the farthest visible point in map
+    /// coordinates. The whole map is considered to be visible if the edge of the globe is on the
+    /// screen.
+    pub fn map_radius(&self) -> f64 {
+        if self.diameter_physical_pixels() < self.viewport_size.x.hypot(self.viewport_size.y) {
+            1.0
+        } else {
+            MercatorView::from_orthografic_view(self).map_radius().min(1.0)
+        }
+    }
+
     /// Returns true if the rendering covers the whole viewport.
     pub fn covers_viewport(&self) -> bool {
         let sphere_diameter = 2.0f64.powf(self.zoom) *
@@ -253,16 +264,19 @@ impl OrthograficView {
         self.viewport_size.x.hypot(self.viewport_size.y) < sphere_diameter * 0.9
     }
 
-    /// Returns the tile zoom value that is used for rendering with the current zoom.
+    /// Returns the tile zoom value that is used for rendering with the current zoom. Tiles that
+    /// are larger than `tile_size` are taken from a lower zoom level.
     //TODO Insert real implementation. Add TileCoord parameter -> lower resolution at the poles
-    pub fn tile_zoom(&self) -> u32 {
-        (self.zoom + self.tile_zoom_offset).floor().max(0.0) as u32
+    pub fn tile_zoom(&self, source_tile_size: u32) -> u32 {
+        let size_offset = (f64::from(self.tile_size) / f64::from(source_tile_size)).log2();
+        (self.zoom + self.tile_zoom_offset + size_offset).floor().max(0.0) as u32
     }
 
     //TODO Return the transformation matrix that is used here to avoid redundant calculation.
-    /// Returns a `Vec` of all tiles that are visible in the current viewport.
-    pub fn visible_tiles(&self) -> Vec<VisibleTile> {
-        let uzoom = self.tile_zoom();
+    /// Returns a `Vec` of all tiles that are visible in the current viewport. `source_tile_size`
+    /// is the edge length of the tile images in pixels.
+    pub fn visible_tiles(&self, source_tile_size: u32) -> Vec<VisibleTile> {
+        let uzoom = self.tile_zoom(source_tile_size);
 
         match uzoom {
             0 => return vec![TileCoord::new(0, 0, 0).into()],
@@ -0,0 +1,233 @@
+//! Plays the time steps of tile sources with a time dimension as an animation.
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Number of time steps after the current one that are loaded in advance while playing
+const PRELOAD_STEPS: usize = 3;
+/// Slowest playback speed in time steps per second
+const MIN_SPEED: f64 = 0.25;
+/// Fastest playback speed in time steps per second
+const MAX_SPEED: f64 = 16.0;
+
+/// State of the playback controls. The time steps themselves are selected in each `TileSource`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playback {
+    playing: bool,
+    /// Continue with the first time step after the last one.
+    looping: bool,
+    /// Time steps per second
+    speed: f64,
+    /// When the last time step was shown while playing
+    last_step: Instant,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Playback {
+            playing: false,
+            looping: true,
+            speed: 2.0,
+            last_step: Instant::now(),
+        }
+    }
+}
+
+impl Playback {
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    /// Returns the number of time steps per second.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Starts or pauses the animation. The next time step is shown after a full step interval.
+    pub fn toggle_play(&mut self, now: Instant) {
+        self.playing = !self.playing;
+        self.last_step = now;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn toggle_loop(&mut self) {
+        self.looping = !self.looping;
+    }
+
+    /// Multiplies the speed with `factor`. The speed is not changed if it would be slower than a
+    /// quarter or faster than 16 time steps per second.
+    pub fn change_speed(&mut self, factor: f64) {
+        let speed = self.speed * factor;
+        if (MIN_SPEED..=MAX_SPEED).contains(&speed) {
+            self.speed = speed;
+        }
+    }
+
+    fn step_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.speed)
+    }
+
+    /// Returns true if the animation should show the next time step. Steps that were missed, e.g.
+    /// because drawing took longer, are skipped instead of being caught up on.
+    pub fn advance(&mut self, now: Instant) -> bool {
+        if self.playing && now.duration_since(self.last_step) >= self.step_interval() {
+            self.last_step = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns when the next time step is due or `None` if the animation is paused.
+    pub fn next_step(&self) -> Option<Instant> {
+        if self.playing {
+            Some(self.last_step + self.step_interval())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index of the time step that is `delta` steps away from `index` for a source
+    /// with `len` time steps. Returns `None` if there is no such time step because the source
+    /// has no time dimension or because the end is reached and looping is disabled.
+    pub fn step(&self, index: u32, len: usize, delta: i32) -> Option<u32> {
+        if len == 0 {
+            return None;
+        }
+
+        let target = i64::from(index) + i64::from(delta);
+        if target >= 0 && target < len as i64 {
+            Some(target as u32)
+        } else if self.looping {
+            Some(target.rem_euclid(len as i64) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the time steps after `index` that should be loaded in advance. While the animation
+    /// is paused, only the next time step is loaded.
+    pub fn upcoming(&self, index: u32, len: usize) -> Vec<u32> {
+        let count = if self.playing { PRELOAD_STEPS } else { 1 };
+        let mut upcoming = vec![];
+        let mut current = index;
+        while upcoming.len() < count {
+            match self.step(current, len, 1) {
+                Some(next) if next != index => {
+                    upcoming.push(next);
+                    current = next;
+                },
+                _ => break,
+            }
+        }
+        upcoming
+    }
+}
+
+/// Calls `on_step` on a separate thread when the next time step of the animation is due, so the
+/// event loop can keep waiting for events while playing. Each time that is sent over `step_rx`
+/// replaces the time of the next step and `None` stops the timer until a new time arrives. The
+/// thread stops when `on_step` returns false or when the sender is dropped.
+pub fn spawn_timer<F>(step_rx: Receiver<Option<Instant>>, mut on_step: F) -> thread::JoinHandle<()>
+    where F: FnMut() -> bool + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut next_step: Option<Instant> = None;
+        loop {
+            let received = match next_step {
+                Some(time) => step_rx.recv_timeout(time.saturating_duration_since(Instant::now())),
+                None => step_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(time) => next_step = time,
+                Err(RecvTimeoutError::Timeout) => {
+                    next_step = None;
+                    if !on_step() {
+                        return;
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use playback::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn steps_and_upcoming() {
+        let mut playback = Playback::default();
+        assert_eq!(playback.step(0, 0, 1), None);
+        assert_eq!(playback.step(1, 4, 1), Some(2));
+        assert_eq!(playback.step(3, 4, 1), Some(0));
+        assert_eq!(playback.step(0, 4, -1), Some(3));
+        assert_eq!(playback.upcoming(2, 4), vec![3]);
+
+        playback.toggle_play(Instant::now());
+        assert_eq!(playback.upcoming(2, 4), vec![3, 0, 1]);
+        assert_eq!(playback.upcoming(0, 2), vec![1]);
+
+        playback.toggle_loop();
+        assert_eq!(playback.step(3, 4, 1), None);
+        assert_eq!(playback.step(0, 4, -1), None);
+        assert_eq!(playback.upcoming(2, 4), vec![3]);
+        assert!(playback.upcoming(0, 1).is_empty());
+    }
+
+    #[test]
+    fn timing() {
+        let start = Instant::now();
+        let mut playback = Playback::default();
+        assert!(!playback.advance(start + Duration::from_secs(10)));
+        assert_eq!(playback.next_step(), None);
+
+        playback.toggle_play(start);
+        assert!(!playback.advance(start + Duration::from_millis(100)));
+        assert_eq!(playback.next_step(), Some(start + Duration::from_millis(500)));
+        assert!(playback.advance(start + Duration::from_millis(600)));
+        assert!(!playback.advance(start + Duration::from_millis(700)));
+
+        playback.change_speed(2.0);
+        assert_eq!(playback.speed(), 4.0);
+        playback.change_speed(8.0);
+        assert_eq!(playback.speed(), 4.0);
+        for _ in 0..8 {
+            playback.change_speed(0.5);
+        }
+        assert_eq!(playback.speed(), 0.25);
+    }
+
+    #[test]
+    fn timer() {
+        let (tx, rx) = mpsc::channel();
+        let (step_tx, step_rx) = mpsc::channel();
+        let handle = spawn_timer(step_rx, move || tx.send(Instant::now()).is_ok());
+
+        let step = Instant::now() + Duration::from_millis(50);
+        step_tx.send(Some(step)).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap() >= step);
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        // A paused animation does not wake up the event loop.
+        step_tx.send(Some(Instant::now() + Duration::from_millis(50))).unwrap();
+        step_tx.send(None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        drop(step_tx);
+        handle.join().unwrap();
+    }
+}
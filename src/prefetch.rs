@@ -0,0 +1,91 @@
+//! Chooses tiles that are likely to become visible soon, so they can be loaded in advance.
+
+use coord::TileCoord;
+use std::collections::HashSet;
+
+
+/// Which tiles are loaded in addition to the visible tiles.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PrefetchSettings {
+    /// Width of the ring of tiles around the visible tiles
+    pub margin: u32,
+    /// Also load the parents and children of the visible tiles for zooming out and in.
+    pub adjacent_zoom_levels: bool,
+}
+
+impl Default for PrefetchSettings {
+    fn default() -> Self {
+        PrefetchSettings {
+            margin: 1,
+            adjacent_zoom_levels: true,
+        }
+    }
+}
+
+/// Returns the tiles that should be prefetched for the given visible tiles. Visible tiles are not
+/// included.
+pub fn prefetch_tiles(visible: &[TileCoord], settings: &PrefetchSettings) -> Vec<TileCoord> {
+    let mut seen: HashSet<TileCoord> = visible.iter().cloned().collect();
+    let mut tiles = vec![];
+    let margin = settings.margin as i32;
+
+    for tile_coord in visible {
+        for dy in -margin..=margin {
+            for dx in -margin..=margin {
+                // The x coordinate wraps around the antimeridian.
+                let neighbor = TileCoord::new(tile_coord.zoom, tile_coord.x + dx, tile_coord.y + dy);
+                if neighbor.is_valid() && seen.insert(neighbor) {
+                    tiles.push(neighbor);
+                }
+            }
+        }
+    }
+
+    if settings.adjacent_zoom_levels {
+        for tile_coord in visible {
+            if let Some((parent, _)) = tile_coord.parent(1) {
+                if seen.insert(parent) {
+                    tiles.push(parent);
+                }
+            }
+            for &(child, _) in &tile_coord.children() {
+                if seen.insert(child) {
+                    tiles.push(child);
+                }
+            }
+        }
+    }
+
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use prefetch::*;
+
+    #[test]
+    fn ring_and_adjacent_zoom_levels() {
+        let visible = [TileCoord::new(3, 0, 0), TileCoord::new(3, 1, 0)];
+        let ring_only = PrefetchSettings { margin: 1, adjacent_zoom_levels: false };
+
+        let mut tiles = prefetch_tiles(&visible, &ring_only);
+        tiles.sort_by_key(|t| (t.zoom, t.y, t.x));
+        assert_eq!(tiles, vec![
+            TileCoord::new(3, 2, 0),
+            TileCoord::new(3, 7, 0),
+            TileCoord::new(3, 0, 1),
+            TileCoord::new(3, 1, 1),
+            TileCoord::new(3, 2, 1),
+            TileCoord::new(3, 7, 1),
+        ]);
+
+        let tiles = prefetch_tiles(&visible, &PrefetchSettings::default());
+        assert_eq!(tiles.len(), 6 + 1 + 8);
+        assert!(tiles.contains(&TileCoord::new(2, 0, 0)));
+        assert!(tiles.contains(&TileCoord::new(4, 3, 1)));
+        assert!(!tiles.iter().any(|t| visible.contains(t)));
+
+        let nothing = PrefetchSettings { margin: 0, adjacent_zoom_levels: false };
+        assert_eq!(prefetch_tiles(&visible, &nothing), vec![]);
+    }
+}
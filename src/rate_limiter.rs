@@ -0,0 +1,111 @@
+//! Limits the rate of requests to tile servers to stay within their usage policies.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Spaces out requests to the same host. A limit for a host name also applies to all of its
+/// subdomains, which share the same budget.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Minimum time between two requests for each host name
+    intervals: HashMap<String, Duration>,
+    /// Earliest time of the next request for each host name
+    next_request: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter from the maximum number of requests per second for each host name.
+    pub fn new(limits: &HashMap<String, f64>) -> Self {
+        let intervals = limits.iter()
+            .filter(|&(_, &rate)| rate > 0.0)
+            .map(|(host, &rate)| {
+                let nanos = (1e9 / rate).min(u64::MAX as f64) as u64;
+                (host.to_lowercase(), Duration::from_nanos(nanos))
+            })
+            .collect();
+
+        RateLimiter {
+            intervals,
+            next_request: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the configured host name whose limit applies to `host`. If limits are configured
+    /// for several parent domains, the most specific one applies.
+    fn limited_host(&self, host: &str) -> Option<(&str, Duration)> {
+        let host = host.to_lowercase();
+        self.intervals.iter()
+            .filter(|&(name, _)| {
+                host == *name ||
+                    (host.ends_with(name.as_str()) && host[..host.len() - name.len()].ends_with('.'))
+            })
+            .max_by_key(|&(name, _)| name.len())
+            .map(|(name, &interval)| (name.as_str(), interval))
+    }
+
+    /// Reserves the next time slot for a request to `host` and returns how long to wait until
+    /// then.
+    pub fn reserve(&self, host: &str, now: Instant) -> Duration {
+        let (name, interval) = match self.limited_host(host) {
+            Some(limit) => limit,
+            None => return Duration::from_secs(0),
+        };
+
+        let mut next_request = match self.next_request.lock() {
+            Ok(next_request) => next_request,
+            Err(_) => return Duration::from_secs(0),
+        };
+
+        let slot = match next_request.get(name) {
+            Some(&next) if next > now => next,
+            _ => now,
+        };
+        next_request.insert(name.to_string(), slot + interval);
+
+        slot - now
+    }
+
+    /// Blocks the current thread until a request to `host` is allowed.
+    pub fn wait(&self, host: &str) {
+        let delay = self.reserve(host, Instant::now());
+        if delay > Duration::from_secs(0) {
+            debug!("wait {:?} before requesting {}", delay, host);
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rate_limiter::*;
+
+    #[test]
+    fn reserve() {
+        let mut limits = HashMap::new();
+        limits.insert("tile.example.com".to_string(), 4.0);
+        let limiter = RateLimiter::new(&limits);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        assert_eq!(limiter.reserve("tile.example.com", now), ms(0));
+        assert_eq!(limiter.reserve("a.tile.example.com", now), ms(250));
+        assert_eq!(limiter.reserve("B.Tile.Example.com", now + ms(100)), ms(400));
+        assert_eq!(limiter.reserve("tile.example.com", now + ms(2000)), ms(0));
+
+        // Other hosts are not limited
+        assert_eq!(limiter.reserve("example.com", now), ms(0));
+        assert_eq!(limiter.reserve("atile.example.com", now), ms(0));
+        assert_eq!(limiter.reserve("example.org", now), ms(0));
+
+        // The longest matching host name wins.
+        limits.insert("example.com".to_string(), 1.0);
+        let limiter = RateLimiter::new(&limits);
+        assert_eq!(limiter.reserve("a.tile.example.com", now), ms(0));
+        assert_eq!(limiter.reserve("b.tile.example.com", now), ms(250));
+        assert_eq!(limiter.reserve("www.example.com", now), ms(0));
+        assert_eq!(limiter.reserve("example.com", now), ms(1000));
+    }
+}
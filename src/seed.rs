@@ -0,0 +1,352 @@
+//! Downloads all tiles of an area into the tile cache, e.g. to prepare for using DeltaMap offline.
+
+use cgmath::vec2;
+use clap;
+use config::{self, Config};
+use coord::{BoundingBox, LatLonDeg, MapCoord, TileCoord};
+use mercator_view::MercatorView;
+use orthografic_view::OrthograficView;
+use projection::Projection;
+use rate_limiter::RateLimiter;
+use reqwest::Client;
+use session::Session;
+use std::sync::{Arc, mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tile_loader::DownloadSettings;
+use tile_source::TileSource;
+
+
+/// Viewport size in physical pixels that is assumed for sessions that do not store it.
+const DEFAULT_VIEWPORT_SIZE: (u32, u32) = (1920, 1080);
+
+/// Latitude limit of the Web Mercator projection in degrees
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+/// Time between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The area and zoom levels to download.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SeedArea {
+    pub bbox: BoundingBox,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+}
+
+/// Progress of a seeding run.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SeedStats {
+    /// Number of tiles in the area, counted once for each time step of the source
+    pub total: usize,
+    /// Tiles that have been downloaded in this run
+    pub downloaded: usize,
+    /// Tiles that were already in the cache
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl SeedStats {
+    pub fn done(&self) -> usize {
+        self.downloaded + self.skipped + self.failed
+    }
+}
+
+/// Runs the `seed` command with its argument matches.
+pub fn run_command<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<(), String> {
+    if !config.use_network() {
+        return Err("seeding tiles is not possible without network access".to_string());
+    }
+
+    let name = matches.value_of("source").unwrap_or_default();
+    let mut source = config.tile_source(name)?.clone();
+
+    // The window requests high-resolution tiles for DPI factors of at least 1.5.
+    if matches.is_present("hidpi") {
+        source.set_dpi_factor(2.0);
+    }
+
+    let bbox = match matches.value_of("bbox") {
+        Some(bbox) => parse_bbox(bbox)?,
+        None => session_bbox(&config::read_last_session()
+            .map_err(|e| format!("could not read last session, {}", e))?)?,
+    };
+
+    let (min_zoom, max_zoom) = parse_zoom_range(matches.value_of("zoom").unwrap_or_default())?;
+
+    let area = SeedArea { bbox, min_zoom, max_zoom };
+    println!(
+        "seed {:?} for [{}, {}, {}, {}] at zoom levels {}-{}",
+        name, bbox.west, bbox.south, bbox.east, bbox.north, min_zoom, max_zoom,
+    );
+
+    let stats = seed(&source, &area, config.download_settings(), |stats| {
+        println!(
+            "{}/{} tiles ({:.1}%), {} downloaded, {} already cached, {} failed",
+            stats.done(),
+            stats.total,
+            100.0 * stats.done() as f64 / stats.total.max(1) as f64,
+            stats.downloaded,
+            stats.skipped,
+            stats.failed,
+        );
+    })?;
+
+    if stats.failed > 0 {
+        println!("{} tiles could not be downloaded. Run the command again to retry.", stats.failed);
+    }
+
+    Ok(())
+}
+
+/// Downloads all tiles of `area` that are not in the cache yet. Tiles that already exist are
+/// skipped, so an interrupted run continues where it stopped. Sources with a time dimension are
+/// seeded for all time steps. `progress` is called regularly and once at the end.
+pub fn seed<F>(source: &TileSource, area: &SeedArea, settings: &DownloadSettings, progress: F)
+    -> Result<SeedStats, String>
+    where F: Fn(&SeedStats),
+{
+    if !source.provider().is_remote() {
+        return Err("only tile sources that are downloaded can be seeded".to_string());
+    }
+
+    let min_zoom = area.min_zoom.max(source.min_tile_zoom());
+    let max_zoom = area.max_zoom.min(source.max_tile_zoom());
+    let num_times = source.times().len().max(1) as u32;
+
+    let stats = Arc::new(Mutex::new(SeedStats {
+        total: area_tiles(area.bbox, min_zoom, max_zoom, source).count() * num_times as usize,
+        ..SeedStats::default()
+    }));
+    let rate_limiter = Arc::new(RateLimiter::new(&settings.rate_limits));
+    let client = Client::builder().build().map_err(|e| format!("could not create HTTP client, {}", e))?;
+
+    let limits = source.download_limits();
+    let num_workers = limits.workers.unwrap_or(settings.workers)
+        .min(limits.max_in_flight.unwrap_or(usize::MAX))
+        .max(1);
+
+    let (tile_tx, tile_rx) = mpsc::sync_channel::<(u32, TileCoord)>(num_workers);
+    let tile_rx = Arc::new(Mutex::new(tile_rx));
+    // Nothing is sent over this channel. It is disconnected when all workers have terminated.
+    let (running_tx, running_rx) = mpsc::channel::<()>();
+
+    for _ in 0..num_workers {
+        let tile_rx = Arc::clone(&tile_rx);
+        let running_tx = running_tx.clone();
+        let stats = Arc::clone(&stats);
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let client = client.clone();
+        let providers: Vec<_> = (0..num_times).map(|time| Arc::clone(source.provider_at(time))).collect();
+
+        thread::spawn(move || {
+            let _running_tx = running_tx;
+            while let Some((time, tile_coord)) = tile_rx.lock().ok().and_then(|rx| rx.recv().ok()) {
+                let provider = &providers[time as usize];
+                if let Some(host) = provider.remote_host(tile_coord) {
+                    rate_limiter.wait(&host);
+                }
+
+                let result = provider.load_remote(&client, tile_coord, true);
+                if let Err(ref e) = result {
+                    warn!("could not download {:?}, {}", tile_coord, e);
+                }
+
+                if let Ok(mut stats) = stats.lock() {
+                    match result {
+                        Ok(_) => stats.downloaded += 1,
+                        Err(_) => stats.failed += 1,
+                    }
+                }
+            }
+        });
+    }
+    drop(running_tx);
+
+    let mut last_report = Instant::now();
+    let mut report = |force: bool| {
+        if force || last_report.elapsed() >= PROGRESS_INTERVAL {
+            if let Ok(stats) = stats.lock() {
+                progress(&stats);
+            }
+            last_report = Instant::now();
+        }
+    };
+
+    let tiles = (0..num_times).flat_map(|time| {
+        area_tiles(area.bbox, min_zoom, max_zoom, source).map(move |tile_coord| (time, tile_coord))
+    });
+    for (time, tile_coord) in tiles {
+        if source.local_tile_path_at(tile_coord, time).is_file() {
+            if let Ok(mut stats) = stats.lock() {
+                stats.skipped += 1;
+            }
+        } else if tile_tx.send((time, tile_coord)).is_err() {
+            break;
+        }
+        report(false);
+    }
+    drop(tile_tx);
+
+    while let Err(mpsc::RecvTimeoutError::Timeout) = running_rx.recv_timeout(Duration::from_millis(50)) {
+        report(false);
+    }
+    report(true);
+
+    let stats = *stats.lock().map_err(|e| format!("{}", e))?;
+    Ok(stats)
+}
+
+/// Returns all tiles that overlap with the bounding box and that are provided by the source.
+fn area_tiles<'a>(bbox: BoundingBox, min_zoom: u32, max_zoom: u32, source: &'a TileSource)
+    -> impl Iterator<Item=TileCoord> + 'a
+{
+    let north_west = MapCoord::from(LatLonDeg::new(bbox.north.min(MAX_LATITUDE), bbox.west));
+    let south_east = MapCoord::from(LatLonDeg::new(bbox.south.max(-MAX_LATITUDE), bbox.east));
+
+    (min_zoom..=max_zoom).flat_map(move |zoom| {
+        let max_index = (1_i64 << zoom) - 1;
+        let clamp = move |i: i32| i64::from(i).max(0).min(max_index) as i32;
+        let nw = north_west.on_tile_at_zoom(zoom);
+        let se = south_east.on_tile_at_zoom(zoom);
+
+        (clamp(nw.y)..=clamp(se.y)).flat_map(move |y| {
+            (clamp(nw.x)..=clamp(se.x)).map(move |x| TileCoord::new(zoom, x, y))
+        })
+    })
+    .filter(move |&tile_coord| bbox.intersects_tile(tile_coord) && source.contains_tile(tile_coord))
+}
+
+/// Parses a bounding box in the format "west,south,east,north".
+pub fn parse_bbox(s: &str) -> Result<BoundingBox, String> {
+    let values = s.split(',')
+        .map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>();
+
+    match values.as_deref() {
+        Some(&[west, south, east, north]) => {
+            BoundingBox::try_new(west, south, east, north)
+                .map_err(|e| format!("bounding box {:?} is invalid, {}", s, e))
+        },
+        _ => Err(format!("bounding box {:?} has to be four numbers \"west,south,east,north\"", s)),
+    }
+}
+
+/// Parses a zoom range like "5-12" or a single zoom level.
+pub fn parse_zoom_range(s: &str) -> Result<(u32, u32), String> {
+    let parse = |z: &str| {
+        z.trim().parse::<u32>().ok()
+            .filter(|&z| z <= 30)
+            .ok_or_else(|| format!("zoom level {:?} has to be an integer in the interval [0, 30]", z))
+    };
+
+    let (min_zoom, max_zoom) = match s.find('-') {
+        Some(pos) => (parse(&s[..pos])?, parse(&s[pos + 1..])?),
+        None => (parse(s)?, parse(s)?),
+    };
+
+    if min_zoom > max_zoom {
+        return Err(format!("zoom range {:?} is empty", s));
+    }
+    Ok((min_zoom, max_zoom))
+}
+
+/// Returns the area that was visible in the given session.
+pub fn session_bbox(session: &Session) -> Result<BoundingBox, String> {
+    let (width, height) = session.viewport_size().unwrap_or(DEFAULT_VIEWPORT_SIZE);
+    let viewport_size = vec2(f64::from(width), f64::from(height));
+
+    let merc = match session.projection() {
+        Some(Projection::Orthografic) => {
+            MercatorView::from_orthografic_view(
+                &OrthograficView::from_toml_table(&session.view, viewport_size, 256)?
+            )
+        },
+        _ => MercatorView::from_toml_table(&session.view, viewport_size, 256)?,
+    };
+
+    // The corners may be outside of the map if the view is zoomed out.
+    let top_left = merc.top_left_coord();
+    let bottom_right = merc.bottom_right_coord();
+    let north_west = MapCoord::new(top_left.x.max(0.0), top_left.y.max(0.0)).to_latlon_deg();
+    let south_east = MapCoord::new(bottom_right.x.min(1.0), bottom_right.y.min(1.0)).to_latlon_deg();
+
+    BoundingBox::try_new(north_west.lon, south_east.lat, south_east.lon, north_west.lat)
+        .map_err(|e| format!("session view is invalid, {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use seed::*;
+    use test_server::{Response, TestServer};
+    use test_util::TempDir;
+    use url_template::UrlTemplate;
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(parse_bbox("5.8,47.2, 15.1,55.1"), Ok(BoundingBox::new(5.8, 47.2, 15.1, 55.1)));
+        assert!(parse_bbox("15.1,47.2,5.8,55.1").is_err());
+        assert!(parse_bbox("1,2,3").is_err());
+
+        assert_eq!(parse_zoom_range("5-12"), Ok((5, 12)));
+        assert_eq!(parse_zoom_range("7"), Ok((7, 7)));
+        assert!(parse_zoom_range("12-5").is_err());
+        assert!(parse_zoom_range("5-31").is_err());
+        assert!(parse_zoom_range("-3").is_err());
+    }
+
+    #[test]
+    fn seed_area() {
+        let server = TestServer::start(|request| {
+            if request.path == "/2/3/1.png" {
+                Response::new(404, vec![])
+            } else {
+                Response::png(256)
+            }
+        });
+        let dir = TempDir::new("deltamap_test_seed");
+        let url_template = UrlTemplate::new(server.url("/{z}/{x}/{y}.png")).unwrap();
+        let source = TileSource::new(0, url_template, dir.path(), "png".to_string(), 1, 19);
+
+        // The eastern hemisphere north of the equator
+        let area = SeedArea {
+            bbox: BoundingBox::new(0.0, 0.0, 180.0, 90.0),
+            min_zoom: 0,
+            max_zoom: 2,
+        };
+        let settings = DownloadSettings::default();
+
+        let stats = seed(&source, &area, &settings, |_| {}).unwrap();
+        assert_eq!(stats, SeedStats { total: 5, downloaded: 4, skipped: 0, failed: 1 });
+        assert!(source.local_tile_path(TileCoord::new(2, 2, 0)).is_file());
+        assert!(!source.local_tile_path(TileCoord::new(2, 0, 0)).is_file());
+
+        // Only the missing tile is requested again
+        let stats = seed(&source, &area, &settings, |_| {}).unwrap();
+        assert_eq!(stats, SeedStats { total: 5, downloaded: 0, skipped: 4, failed: 1 });
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[test]
+    fn seed_time_steps() {
+        let server = TestServer::start(|_| Response::png(256));
+        let dir = TempDir::new("deltamap_test_seed_time");
+        let url_template = UrlTemplate::new(server.url("/{time}/{z}/{x}/{y}.png")).unwrap();
+        let source = TileSource::new(0, url_template, dir.path(), "png".to_string(), 0, 19)
+            .with_times(vec!["t1".to_string(), "t2".to_string()]);
+
+        let area = SeedArea {
+            bbox: BoundingBox::new(0.0, 0.0, 180.0, 90.0),
+            min_zoom: 0,
+            max_zoom: 1,
+        };
+        let stats = seed(&source, &area, &DownloadSettings::default(), |_| {}).unwrap();
+        assert_eq!(stats, SeedStats { total: 4, downloaded: 4, skipped: 0, failed: 0 });
+        assert!(source.local_tile_path_at(TileCoord::new(1, 1, 0), 0).is_file());
+        assert!(source.local_tile_path_at(TileCoord::new(1, 1, 0), 1).is_file());
+
+        let mut paths: Vec<_> = server.requests().iter().map(|r| r.path.clone()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/t1/0/0/0.png", "/t1/1/1/0.png", "/t2/0/0/0.png", "/t2/1/1/0.png"]);
+    }
+}
@@ -3,6 +3,7 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::str::FromStr;
+use tile_source::OverlaySettings;
 use toml::Value;
 use toml::value::Table;
 use toml;
@@ -72,6 +73,58 @@ impl Session {
         }
     }
 
+    /// Stores the overlay sources in the order they are drawn, from bottom to top.
+    pub fn set_overlays(&mut self, overlays: &[(&str, OverlaySettings)]) {
+        let array = overlays.iter().map(|&(name, settings)| {
+            let mut table = Table::new();
+            table.insert("name".to_string(), Value::String(name.to_string()));
+            table.insert("opacity".to_string(), Value::Float(f64::from(settings.opacity)));
+            table.insert("visible".to_string(), Value::Boolean(settings.visible));
+            Value::Table(table)
+        }).collect();
+
+        self.view.insert("overlays".to_string(), Value::Array(array));
+    }
+
+    /// Returns the stored overlay sources. Invalid entries are skipped.
+    pub fn overlays(&self) -> Vec<(String, OverlaySettings)> {
+        let array = match self.view.get("overlays") {
+            Some(Value::Array(array)) => array,
+            _ => return vec![],
+        };
+
+        array.iter().filter_map(|entry| {
+            let name = entry.get("name")?.as_str()?;
+            let mut settings = OverlaySettings::default();
+            if let Some(opacity) = entry.get("opacity").and_then(|o| o.as_float()) {
+                if (0.0..=1.0).contains(&opacity) {
+                    settings.opacity = opacity as f32;
+                }
+            }
+            if let Some(visible) = entry.get("visible").and_then(|v| v.as_bool()) {
+                settings.visible = visible;
+            }
+            Some((name.to_string(), settings))
+        }).collect()
+    }
+
+    /// Stores the size of the viewport in physical pixels.
+    pub fn set_viewport_size(&mut self, (width, height): (u32, u32)) {
+        self.view.insert("viewport_width".to_string(), Value::Integer(i64::from(width)));
+        self.view.insert("viewport_height".to_string(), Value::Integer(i64::from(height)));
+    }
+
+    /// Returns the size of the viewport in physical pixels.
+    pub fn viewport_size(&self) -> Option<(u32, u32)> {
+        let get = |key: &str| {
+            self.view.get(key)
+                .and_then(|v| v.as_integer())
+                .filter(|&i| i > 0 && i <= i64::from(u32::MAX))
+                .map(|i| i as u32)
+        };
+        Some((get("viewport_width")?, get("viewport_height")?))
+    }
+
     pub fn projection(&self) -> Option<Projection> {
         match self.view.get("projection") {
             Some(Value::String(s)) => Projection::from_str(s.as_str()).ok(),
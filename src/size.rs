@@ -0,0 +1,66 @@
+//! Parses and formats sizes in bytes, e.g. of the tile caches.
+
+/// Parses a size in bytes with an optional unit, e.g. "500 MB", "2GiB" or "1024".
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, unit) = (&s[..split], s[split..].trim());
+
+    let factor: u64 = match unit.to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("size {:?} has an unknown unit, use B, kB, MB, GB, TB, KiB, MiB, GiB or TiB", s)),
+    };
+
+    number.parse::<f64>()
+        .ok()
+        .map(|n| n * factor as f64)
+        .filter(|&bytes| bytes < u64::MAX as f64)
+        .map(|bytes| bytes.round() as u64)
+        .ok_or_else(|| format!("size {:?} has to be a non-negative number with an optional unit, e.g. \"500 MB\"", s))
+}
+
+/// Formats a size in bytes for humans, e.g. "12.3 MB".
+pub fn format_size(bytes: u64) -> String {
+    let units = ["kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = None;
+    for u in &units {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = Some(u);
+    }
+
+    match unit {
+        Some(unit) => format!("{:.1} {}", size, unit),
+        None => format!("{} B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use size::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("500 MB"), Ok(500_000_000));
+        assert_eq!(parse_size("1.5gb"), Ok(1_500_000_000));
+        assert_eq!(parse_size("2 GiB"), Ok(2 << 30));
+        assert!(parse_size("-5 MB").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+        assert!(parse_size("MB").is_err());
+
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(12_345_678), "12.3 MB");
+    }
+}
@@ -0,0 +1,351 @@
+//! Checks the configured tile sources and loads a sample tile of each one, so that mistakes in
+//! `tile_sources.toml` show up with a reason instead of as grey tiles.
+
+use clap;
+use config::{self, Config};
+use coord::{LatLonDeg, MapCoord, TileCoord};
+use image::{self, GenericImageView};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use seed::session_bbox;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tile_source::TileSource;
+use tile_provider::{redact_url, request_error};
+
+
+/// Result of loading one sample tile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleTile {
+    pub tile_coord: TileCoord,
+    /// URL of the tile or `None` if the tile was read from disk
+    pub url: Option<String>,
+    /// HTTP status code
+    pub status: Option<u16>,
+    pub content_type: Option<String>,
+    /// Width and height of the decoded image in pixels
+    pub dimensions: Option<(u32, u32)>,
+    /// Time until the tile was loaded completely or the request failed
+    pub latency: Duration,
+    /// Reason why the tile cannot be shown
+    pub error: Option<String>,
+}
+
+impl fmt::Display for SampleTile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        match self.status {
+            Some(status) => parts.push(format!("HTTP {}", status)),
+            None => parts.push("read from disk".to_string()),
+        }
+        if let Some(ref content_type) = self.content_type {
+            parts.push(content_type.clone());
+        }
+        if let Some((width, height)) = self.dimensions {
+            parts.push(format!("{}x{} pixels", width, height));
+        }
+        parts.push(format!("{} ms", self.latency.as_millis()));
+        if let Some(ref error) = self.error {
+            parts.push(format!("error: {}", error));
+        }
+
+        let t = self.tile_coord;
+        write!(f, "zoom {} ({}/{}/{}): {}", t.zoom, t.zoom, t.x, t.y, parts.join(", "))
+    }
+}
+
+/// Findings for one tile source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceReport {
+    pub name: String,
+    /// Mistakes in the configuration of the source
+    pub problems: Vec<String>,
+    pub samples: Vec<SampleTile>,
+}
+
+impl SourceReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.samples.iter().all(|s| s.error.is_none())
+    }
+}
+
+/// Runs the `check-sources` command with its argument matches. Returns an error if any source
+/// has a problem.
+pub fn run_command<'a>(config: &Config, matches: &clap::ArgMatches<'a>) -> Result<(), String> {
+    let only = matches.value_of("source");
+    if let Some(name) = only {
+        let exists = config.tile_sources().iter().any(|(n, _)| n == name) ||
+            config.invalid_sources().iter().any(|(n, _)| n == name);
+        if !exists {
+            return Err(format!("there is no tile source named {:?}", name));
+        }
+    }
+
+    let client = if config.use_network() {
+        Some(Client::builder().build().map_err(|e| format!("could not create HTTP client, {}", e))?)
+    } else {
+        println!("offline mode, sample tiles are read from the cache");
+        None
+    };
+
+    // Sources without bounds are checked where the map was looked at last.
+    let location = config::read_last_session().ok()
+        .and_then(|session| session_bbox(&session).ok())
+        .map(|bbox| LatLonDeg::new((bbox.south + bbox.north) * 0.5, (bbox.west + bbox.east) * 0.5))
+        .unwrap_or_else(|| LatLonDeg::new(0.0, 0.0));
+
+    let mut reports: Vec<SourceReport> = config.invalid_sources().iter()
+        .map(|(name, err)| SourceReport { name: name.clone(), problems: vec![err.clone()], samples: vec![] })
+        .collect();
+    reports.extend(config.tile_sources().iter().map(|(name, source)| {
+        check_source(name, source, location, client.as_ref())
+    }));
+    reports.retain(|r| only.map(|name| r.name == name).unwrap_or(true));
+
+    for report in &reports {
+        println!("{}: {}", report.name, if report.is_ok() { "ok" } else { "failed" });
+        for problem in &report.problems {
+            println!("  {}", problem);
+        }
+        for sample in &report.samples {
+            println!("  {}", sample);
+        }
+    }
+
+    let failed = reports.iter().filter(|r| !r.is_ok()).count();
+    if failed > 0 {
+        Err(format!("{} of {} tile sources failed the check", failed, reports.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates the zoom range and the tile URLs of `source` and loads a sample tile at its minimum
+/// and maximum zoom level. The tiles are downloaded with `client` or read from the cache if
+/// `client` is `None`. Downloaded tiles are not added to the cache. The samples are taken at
+/// the center of the source's bounds or at `location` if it has none.
+pub fn check_source(name: &str, source: &TileSource, location: LatLonDeg, client: Option<&Client>) -> SourceReport {
+    let mut report = SourceReport {
+        name: name.to_string(),
+        problems: vec![],
+        samples: vec![],
+    };
+
+    let (min_zoom, max_zoom) = (source.min_tile_zoom(), source.max_tile_zoom());
+    if min_zoom > max_zoom {
+        report.problems.push(format!("min_zoom ({}) is larger than max_zoom ({})", min_zoom, max_zoom));
+        return report;
+    }
+
+    let location = match source.bounds() {
+        Some(b) => LatLonDeg::new((b.south + b.north) * 0.5, (b.west + b.east) * 0.5),
+        None => location,
+    };
+    let mut zoom_levels = vec![min_zoom];
+    if max_zoom != min_zoom {
+        zoom_levels.push(max_zoom);
+    }
+
+    for zoom in zoom_levels {
+        let tile_coord = MapCoord::from(location).on_tile_at_zoom(zoom).nearest_valid();
+
+        let sample = match (source.remote_tile_url(tile_coord), client) {
+            (Some(url), Some(client)) => {
+                match Url::parse(&url) {
+                    Ok(ref u) if u.scheme() == "http" || u.scheme() == "https" => {
+                        fetch_sample(client, source, tile_coord, url)
+                    },
+                    Ok(u) => {
+                        report.problems.push(format!("URL {:?} has the unsupported scheme {:?}", redact_url(&url), u.scheme()));
+                        continue;
+                    },
+                    Err(e) => {
+                        report.problems.push(format!("URL {:?} is invalid, {}", redact_url(&url), e));
+                        continue;
+                    },
+                }
+            },
+            _ => read_sample(source, tile_coord),
+        };
+        report.samples.push(sample);
+    }
+
+    report
+}
+
+/// Downloads a tile without writing it to the cache.
+fn fetch_sample(client: &Client, source: &TileSource, tile_coord: TileCoord, url: String) -> SampleTile {
+    let start = Instant::now();
+    let mut sample = SampleTile {
+        tile_coord,
+        url: Some(url.clone()),
+        status: None,
+        content_type: None,
+        dimensions: None,
+        latency: Duration::from_secs(0),
+        error: None,
+    };
+
+    let result = client.get(&url)
+        .headers(source.headers().clone())
+        .send()
+        .and_then(|mut response| {
+            let mut buf: Vec<u8> = vec![];
+            response.copy_to(&mut buf).map(|_| (response, buf))
+        });
+    sample.latency = start.elapsed();
+
+    let (response, buf) = match result {
+        Ok(x) => x,
+        Err(e) => {
+            sample.error = Some(format!("request failed, {}", request_error(&e)));
+            return sample;
+        },
+    };
+
+    sample.status = Some(response.status().as_u16());
+    sample.content_type = response.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    if !response.status().is_success() {
+        sample.error = Some(format!("unexpected status {}", response.status()));
+        return sample;
+    }
+
+    match image::load_from_memory(&buf) {
+        Ok(img) => {
+            sample.dimensions = Some(img.dimensions());
+            sample.error = check_dimensions(source, img.dimensions());
+        },
+        Err(e) => sample.error = Some(format!("could not decode image, {}", e)),
+    }
+    sample
+}
+
+/// Reads a tile from the cache or from a local source.
+fn read_sample(source: &TileSource, tile_coord: TileCoord) -> SampleTile {
+    let start = Instant::now();
+    let img = source.provider().load_local(tile_coord);
+    let latency = start.elapsed();
+
+    let dimensions = img.map(|img| img.dimensions());
+    let error = match dimensions {
+        Some(dimensions) => check_dimensions(source, dimensions),
+        None if source.cache_directory().is_some() => Some("tile is not in the cache".to_string()),
+        None => Some("tile does not exist or could not be decoded".to_string()),
+    };
+
+    SampleTile {
+        tile_coord,
+        url: None,
+        status: None,
+        content_type: None,
+        dimensions,
+        latency,
+        error,
+    }
+}
+
+/// Returns an error if the image does not have the tile size of the source.
+fn check_dimensions(source: &TileSource, (width, height): (u32, u32)) -> Option<String> {
+    let tile_size = source.tile_size();
+    if width != tile_size || height != tile_size {
+        Some(format!("image is {}x{} pixels, but the tile size is {}", width, height, tile_size))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use source_check::*;
+    use std::fs;
+    use std::path::Path;
+    use test_server::{png_data, Response, TestServer};
+    use test_util::TempDir;
+    use url_template::UrlTemplate;
+
+    fn source(template: &str, dir: &Path, min_zoom: u32, max_zoom: u32) -> TileSource {
+        let url_template = UrlTemplate::new(template).unwrap();
+        TileSource::new(0, url_template, dir, "png".to_string(), min_zoom, max_zoom)
+    }
+
+    #[test]
+    fn check_remote_source() {
+        let dir = TempDir::new("deltamap_test_check_remote_source");
+        let server = TestServer::start(|request| {
+            match request.path.as_str() {
+                "/0/0/0.png" => Response::png(256),
+                "/3/4/4.png" => Response::png(512),
+                "/4/8/8.png" => Response::new(200, b"<html></html>".to_vec())
+                    .with_header("Content-Type", "text/html"),
+                _ => Response::new(404, vec![]),
+            }
+        });
+        let client = Client::new();
+        let location = LatLonDeg::new(-1.0, 1.0);
+
+        let ok = source(&server.url("/{z}/{x}/{y}.png"), &dir.join("ok"), 0, 0);
+        let report = check_source("ok", &ok, location, Some(&client));
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.samples.len(), 1);
+        let sample = &report.samples[0];
+        assert_eq!(sample.url, Some(server.url("/0/0/0.png")));
+        assert_eq!(sample.status, Some(200));
+        assert_eq!(sample.content_type, Some("image/png".to_string()));
+        assert_eq!(sample.dimensions, Some((256, 256)));
+        // Samples are not written to the cache.
+        assert!(!ok.local_tile_path(TileCoord::new(0, 0, 0)).exists());
+
+        let broken = source(&server.url("/{z}/{x}/{y}.png"), &dir.join("broken"), 2, 3);
+        let report = check_source("broken", &broken, location, Some(&client));
+        assert!(!report.is_ok());
+        let errors: Vec<_> = report.samples.iter().map(|s| s.error.clone()).collect();
+        assert_eq!(errors, vec![
+            Some("unexpected status 404 Not Found".to_string()),
+            Some("image is 512x512 pixels, but the tile size is 256".to_string()),
+        ]);
+
+        let html = source(&server.url("/{z}/{x}/{y}.png"), &dir.join("html"), 4, 4);
+        let report = check_source("html", &html, location, Some(&client));
+        assert_eq!(report.samples[0].content_type, Some("text/html".to_string()));
+        assert!(report.samples[0].error.as_ref().unwrap().starts_with("could not decode image"));
+    }
+
+    #[test]
+    fn check_configuration() {
+        let dir = TempDir::new("deltamap_test_check_configuration");
+        let location = LatLonDeg::new(0.0, 0.0);
+        let client = Client::new();
+
+        let empty = source("https://tile.example.com/{z}/{x}/{y}.png", &dir.join("empty"), 5, 3);
+        let report = check_source("empty", &empty, location, Some(&client));
+        assert_eq!(report.problems, vec!["min_zoom (5) is larger than max_zoom (3)".to_string()]);
+        assert!(report.samples.is_empty());
+
+        let no_scheme = source("tile.example.com/{z}/{x}/{y}.png", &dir.join("no_scheme"), 0, 0);
+        let report = check_source("no scheme", &no_scheme, location, Some(&client));
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].starts_with("URL \"tile.example.com/0/0/0.png\" is invalid"));
+    }
+
+    #[test]
+    fn check_offline() {
+        let dir = TempDir::new("deltamap_test_check_offline");
+        let server = TestServer::start(|_| Response::png(256));
+        let cached = source(&server.url("/{z}/{x}/{y}.png"), &dir.join("offline"), 0, 1);
+        let path = cached.local_tile_path(TileCoord::new(0, 0, 0));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, png_data(256)).unwrap();
+
+        let report = check_source("cached", &cached, LatLonDeg::new(10.0, 10.0), None);
+        assert_eq!(report.samples.len(), 2);
+        assert_eq!(report.samples[0].url, None);
+        assert_eq!(report.samples[0].dimensions, Some((256, 256)));
+        assert_eq!(report.samples[0].error, None);
+        assert_eq!(report.samples[1].tile_coord, TileCoord::new(1, 1, 0));
+        assert_eq!(report.samples[1].error, Some("tile is not in the cache".to_string()));
+        assert!(server.requests().is_empty());
+    }
+}
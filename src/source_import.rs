@@ -0,0 +1,518 @@
+//! Converts tile source definitions of other programs into entries of the `tile_sources` array,
+//! so they are checked and used like the sources of `tile_sources.toml`. Supported are TileJSON
+//! files, JOSM imagery XML files and XYZ connections that were exported from QGIS.
+
+use regex::Regex;
+use serde_json;
+use std::collections::HashMap;
+use toml::Value;
+use toml::value::Table;
+use xml::reader::{EventReader, XmlEvent};
+
+
+/// Highest zoom level of imagery XML entries that do not specify one
+const DEFAULT_MAX_ZOOM: i64 = 19;
+
+/// A converted tile source or the name of the source and the reason why it cannot be used.
+pub type ImportedSource = Result<Value, (String, String)>;
+
+/// Converts a TileJSON document. The document may also be an array of TileJSON objects. Sources
+/// without a name are named after `default_name`. Only the first URL of the `tiles` array is
+/// used.
+pub fn from_tilejson(json_str: &str, default_name: &str) -> Result<Vec<ImportedSource>, String> {
+    let json: serde_json::Value = serde_json::from_str(json_str)
+        .map_err(|e| format!("invalid JSON, {}", e))?;
+    let objects = match json {
+        serde_json::Value::Array(array) => array,
+        object @ serde_json::Value::Object(_) => vec![object],
+        _ => return Err("TileJSON has to be an object or an array of objects".to_string()),
+    };
+
+    let count = objects.len();
+    Ok(objects.iter().enumerate().map(|(index, object)| {
+        let name = match object.get("name").and_then(|n| n.as_str()) {
+            Some(name) => name.to_string(),
+            None if count == 1 => default_name.to_string(),
+            None => format!("{} {}", default_name, index + 1),
+        };
+        named(name, tilejson_source(object))
+    }).collect())
+}
+
+fn tilejson_source(object: &serde_json::Value) -> Result<Table, String> {
+    let get_str = |key: &str| object.get(key).and_then(|v| v.as_str());
+    let get_zoom = |key: &str, default: i64| {
+        match object.get(key) {
+            Some(v) => v.as_i64().ok_or_else(|| format!("{} has to be an integer", key)),
+            None => Ok(default),
+        }
+    };
+
+    let url = object.get("tiles")
+        .and_then(|t| t.as_array())
+        .and_then(|t| t.first())
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| "missing \"tiles\" array with at least one URL".to_string())?;
+
+    let extension = tile_extension(url);
+    if object.get("vector_layers").is_some() || extension == "pbf" || extension == "mvt" {
+        return Err("vector tiles are not supported".to_string());
+    }
+
+    let mut table = Table::new();
+    table.insert("url_template".to_string(), Value::String(check_placeholders(url)?.to_string()));
+    table.insert("extension".to_string(), Value::String(extension));
+    table.insert("min_zoom".to_string(), Value::Integer(get_zoom("minzoom", 0)?));
+    table.insert("max_zoom".to_string(), Value::Integer(get_zoom("maxzoom", 30)?));
+
+    if let Some(scheme) = get_str("scheme") {
+        table.insert("scheme".to_string(), Value::String(scheme.to_string()));
+    }
+
+    if let Some(bounds) = object.get("bounds") {
+        let bounds = bounds.as_array()
+            .and_then(|b| b.iter().map(|v| v.as_f64().map(Value::Float)).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| "bounds has to be an array of four numbers".to_string())?;
+        table.insert("bounds".to_string(), Value::Array(bounds));
+    }
+
+    if let Some(attribution) = get_str("attribution") {
+        table.insert("attribution".to_string(), Value::String(plain_text(attribution)));
+    }
+    if let Some(description) = get_str("description") {
+        table.insert("description".to_string(), Value::String(description.to_string()));
+    }
+
+    Ok(table)
+}
+
+/// Converts a JOSM imagery XML file or a QGIS XYZ connections export.
+pub fn from_imagery_xml(xml_str: &str) -> Result<Vec<ImportedSource>, String> {
+    let root = parse_xml(xml_str)?;
+
+    match root.name.as_str() {
+        "imagery" => {
+            Ok(root.children.iter()
+                .filter(|e| e.name == "entry")
+                .enumerate()
+                .map(|(index, entry)| {
+                    let name = entry.child_text("name")
+                        .or_else(|| entry.child_text("id"))
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| format!("imagery {}", index + 1));
+                    named(name, josm_source(entry))
+                })
+                .collect())
+        },
+        "qgsXYZTilesConnections" => {
+            Ok(root.children.iter()
+                .filter(|e| e.name == "xyztiles")
+                .enumerate()
+                .map(|(index, connection)| {
+                    let name = connection.attribute("name")
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| format!("connection {}", index + 1));
+                    named(name, qgis_source(connection))
+                })
+                .collect())
+        },
+        name => Err(format!(
+            "unknown root element <{}>, expected JOSM <imagery> or QGIS <qgsXYZTilesConnections>",
+            name,
+        )),
+    }
+}
+
+fn josm_source(entry: &Element) -> Result<Table, String> {
+    lazy_static! {
+        static ref SWITCH_RE: Regex = Regex::new(r"\{switch:([^}]*)\}").unwrap();
+    }
+
+    let mut url = entry.child_text("url")
+        .ok_or_else(|| "missing <url> element".to_string())?
+        .replace("{zoom}", "{z}");
+
+    let mut table = Table::new();
+
+    match entry.child_text("type").unwrap_or("tms") {
+        "tms" => {},
+        "wms" => {
+            // The bounding box placeholder is filled in the Web Mercator projection.
+            url = url.replace("{proj}", "EPSG:3857")
+                .replace("{width}", "256")
+                .replace("{height}", "256");
+            table.insert("crs".to_string(), Value::String("EPSG:3857".to_string()));
+        },
+        kind => return Err(format!("imagery type {:?} is not supported", kind)),
+    }
+
+    let subdomains = SWITCH_RE.captures(&url)
+        .map(|cap| cap[1].split(',').map(|s| Value::String(s.trim().to_string())).collect());
+    if let Some(subdomains) = subdomains {
+        url = SWITCH_RE.replace(&url, "{s}").into_owned();
+        table.insert("subdomains".to_string(), Value::Array(subdomains));
+    }
+
+    table.insert("url_template".to_string(), Value::String(check_placeholders(&url)?.to_string()));
+    table.insert("extension".to_string(), Value::String(tile_extension(&url)));
+
+    let parse_zoom = |key: &str, default: i64| {
+        match entry.child_text(key) {
+            Some(zoom) => zoom.parse::<i64>().map_err(|_| format!("<{}> has to be an integer", key)),
+            None => Ok(default),
+        }
+    };
+    table.insert("min_zoom".to_string(), Value::Integer(parse_zoom("min-zoom", 0)?));
+    table.insert("max_zoom".to_string(), Value::Integer(parse_zoom("max-zoom", DEFAULT_MAX_ZOOM)?));
+
+    if let Some(bounds) = entry.child("bounds") {
+        let get = |key: &str| {
+            bounds.attribute(key)
+                .and_then(|v| v.parse::<f64>().ok())
+                .map(Value::Float)
+                .ok_or_else(|| format!("bounds attribute {:?} has to be a number", key))
+        };
+        let bounds = vec![get("min-lon")?, get("min-lat")?, get("max-lon")?, get("max-lat")?];
+        table.insert("bounds".to_string(), Value::Array(bounds));
+    }
+
+    if let Some(attribution) = entry.child_text("attribution-text") {
+        table.insert("attribution".to_string(), Value::String(attribution.to_string()));
+    }
+
+    // Prefer the English or untranslated description.
+    let description = entry.children.iter()
+        .filter(|e| e.name == "description" && !e.text.trim().is_empty())
+        .min_by_key(|e| match e.attribute("lang") {
+            None => 0,
+            Some("en") => 1,
+            Some(_) => 2,
+        });
+    if let Some(description) = description {
+        table.insert("description".to_string(), Value::String(description.text.trim().to_string()));
+    }
+
+    if entry.child_text("overlay") == Some("true") {
+        table.insert("overlay".to_string(), Value::Boolean(true));
+    }
+
+    let mut headers = Table::new();
+    for header in entry.children.iter().filter(|e| e.name == "custom-http-header") {
+        if let (Some(name), Some(value)) = (header.attribute("header-name"), header.attribute("header-value")) {
+            headers.insert(name.to_string(), Value::String(value.to_string()));
+        }
+    }
+    if !headers.is_empty() {
+        table.insert("headers".to_string(), Value::Table(headers));
+    }
+
+    Ok(table)
+}
+
+fn qgis_source(connection: &Element) -> Result<Table, String> {
+    let url = connection.attribute("url")
+        .ok_or_else(|| "missing url attribute".to_string())?;
+
+    let mut table = Table::new();
+    table.insert("url_template".to_string(), Value::String(check_placeholders(url)?.to_string()));
+    table.insert("extension".to_string(), Value::String(tile_extension(url)));
+
+    let parse_zoom = |key: &str, default: i64| {
+        match connection.attribute(key) {
+            Some(zoom) => zoom.parse::<i64>().map_err(|_| format!("{} has to be an integer", key)),
+            None => Ok(default),
+        }
+    };
+    table.insert("min_zoom".to_string(), Value::Integer(parse_zoom("zmin", 0)?));
+    table.insert("max_zoom".to_string(), Value::Integer(parse_zoom("zmax", DEFAULT_MAX_ZOOM)?));
+
+    // Tiles for high-DPI displays have twice the edge length.
+    if connection.attribute("tilePixelRatio") == Some("2") {
+        table.insert("tile_size".to_string(), Value::Integer(512));
+    }
+
+    if let Some(referer) = connection.attribute("referer").filter(|r| !r.is_empty()) {
+        let mut headers = Table::new();
+        headers.insert("Referer".to_string(), Value::String(referer.to_string()));
+        table.insert("headers".to_string(), Value::Table(headers));
+    }
+
+    Ok(table)
+}
+
+/// Adds the name to a converted source. Slashes are replaced, because the name is also used as
+/// the name of the cache directory. Names like `..` are rejected when the source is parsed.
+fn named(name: String, source: Result<Table, String>) -> ImportedSource {
+    let name = name.replace(&['/', '\\'][..], "-");
+    match source {
+        Ok(mut table) => {
+            table.insert("name".to_string(), Value::String(name));
+            Ok(Value::Table(table))
+        },
+        Err(err) => Err((name, err)),
+    }
+}
+
+/// Returns an error if the URL contains placeholders that are not supported. Variables like
+/// `${API_KEY}` are allowed.
+fn check_placeholders(url: &str) -> Result<&str, String> {
+    lazy_static! {
+        static ref PLACEHOLDER_RE: Regex = Regex::new(r"\$?\{([^}]*)\}").unwrap();
+    }
+    const SUPPORTED: [&str; 9] = ["x", "y", "-y", "z", "s", "quadkey", "r", "bbox", "time"];
+
+    for cap in PLACEHOLDER_RE.captures_iter(url) {
+        if !cap[0].starts_with('$') && !SUPPORTED.contains(&&cap[1]) {
+            return Err(format!("placeholder {:?} is not supported", &cap[0]));
+        }
+    }
+    Ok(url)
+}
+
+/// Guesses the file extension of the tiles from the URL path or a WMS `format` parameter.
+/// Defaults to "png".
+fn tile_extension(url: &str) -> String {
+    let is_extension = |e: &str| !e.is_empty() && e.len() <= 5 && e.chars().all(|c| c.is_ascii_alphanumeric());
+
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+
+    let file_name = path.rsplit('/').next().unwrap_or("");
+    if let Some(pos) = file_name.rfind('.') {
+        let extension = file_name[pos + 1..].to_lowercase();
+        if is_extension(&extension) {
+            return extension;
+        }
+    }
+
+    for param in query.split('&') {
+        let mut key_value = param.splitn(2, '=');
+        if let (Some(key), Some(value)) = (key_value.next(), key_value.next()) {
+            let value = value.to_lowercase().replace("%2f", "/");
+            if key.eq_ignore_ascii_case("format") && value.starts_with("image/") && is_extension(&value[6..]) {
+                return value[6..].to_string();
+            }
+        }
+    }
+
+    "png".to_string()
+}
+
+/// Removes HTML tags and replaces common character entities, because attributions are drawn as
+/// plain text.
+fn plain_text(html: &str) -> String {
+    lazy_static! {
+        static ref TAG_RE: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+
+    TAG_RE.replace_all(html, "")
+        .replace("&copy;", "©")
+        .replace("&#169;", "©")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+/// An element of an XML document with its attributes, text and child elements. Namespaces are
+/// ignored.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: HashMap<String, String>,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|e| e.name == name)
+    }
+
+    /// Returns the trimmed text of the first child element with the given name if it is not
+    /// empty.
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|e| e.text.trim()).filter(|t| !t.is_empty())
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|v| v.as_str())
+    }
+}
+
+/// Parses an XML document and returns its root element.
+fn parse_xml(xml_str: &str) -> Result<Element, String> {
+    let mut stack: Vec<Element> = vec![];
+
+    for event in EventReader::from_str(xml_str) {
+        match event.map_err(|e| format!("invalid XML, {}", e))? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                    ..Element::default()
+                });
+            },
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().ok_or_else(|| "invalid XML, unexpected end tag".to_string())?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            },
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text += &text;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Err("invalid XML, missing root element".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use source_import::*;
+
+    fn get<'a>(source: &'a ImportedSource, key: &str) -> Option<&'a Value> {
+        source.as_ref().ok().and_then(|s| s.get(key))
+    }
+
+    #[test]
+    fn tilejson() {
+        let json = r#"{
+            "tilejson": "2.2.0",
+            "name": "Topo/Relief",
+            "attribution": "<a href=\"https://www.openstreetmap.org/copyright\">&copy; OpenStreetMap</a> contributors",
+            "scheme": "tms",
+            "tiles": ["https://a.tile.example.com/{z}/{x}/{y}.jpg?key=${API_KEY}", "https://b.tile.example.com/{z}/{x}/{y}.jpg"],
+            "minzoom": 2,
+            "maxzoom": 17,
+            "bounds": [5.8, 47.2, 15.1, 55.1]
+        }"#;
+        let sources = from_tilejson(json, "file").unwrap();
+        assert_eq!(sources.len(), 1);
+        let source = &sources[0];
+        assert_eq!(get(source, "name"), Some(&Value::String("Topo-Relief".to_string())));
+        assert_eq!(
+            get(source, "url_template").and_then(|v| v.as_str()),
+            Some("https://a.tile.example.com/{z}/{x}/{y}.jpg?key=${API_KEY}"),
+        );
+        assert_eq!(get(source, "extension").and_then(|v| v.as_str()), Some("jpg"));
+        assert_eq!(get(source, "scheme").and_then(|v| v.as_str()), Some("tms"));
+        assert_eq!(get(source, "min_zoom").and_then(|v| v.as_integer()), Some(2));
+        assert_eq!(get(source, "max_zoom").and_then(|v| v.as_integer()), Some(17));
+        assert_eq!(get(source, "bounds").and_then(|v| v.as_array()).map(|b| b.len()), Some(4));
+        assert_eq!(
+            get(source, "attribution").and_then(|v| v.as_str()),
+            Some("© OpenStreetMap contributors"),
+        );
+
+        let catalogue = r#"[
+            {"tiles": ["https://tile.example.com/{z}/{x}/{y}.png"]},
+            {"tiles": ["https://tile.example.com/{z}/{x}/{y}.pbf"], "vector_layers": []},
+            {"tiles": ["https://tile.example.com/{zoom}/{x}/{y}.png"]}
+        ]"#;
+        let sources = from_tilejson(catalogue, "layers").unwrap();
+        assert_eq!(get(&sources[0], "name").and_then(|v| v.as_str()), Some("layers 1"));
+        assert_eq!(get(&sources[0], "max_zoom").and_then(|v| v.as_integer()), Some(30));
+        assert_eq!(sources[1], Err(("layers 2".to_string(), "vector tiles are not supported".to_string())));
+        assert!(sources[2].is_err());
+
+        assert!(from_tilejson("\"tiles\"", "file").is_err());
+    }
+
+    #[test]
+    fn josm_imagery() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <imagery xmlns="http://josm.openstreetmap.de/maps-1.0">
+                <entry>
+                    <name>OpenStreetMap Carto</name>
+                    <type>tms</type>
+                    <url><![CDATA[https://{switch:a,b,c}.tile.example.com/{zoom}/{x}/{y}.png]]></url>
+                    <max-zoom>19</max-zoom>
+                    <attribution-text mandatory="true">© OpenStreetMap contributors</attribution-text>
+                    <description lang="de">Standardkarte</description>
+                    <description lang="en">Standard map</description>
+                    <custom-http-header header-name="Referer" header-value="https://example.com/"/>
+                </entry>
+                <entry>
+                    <name>Orthophotos</name>
+                    <type>wms</type>
+                    <url>https://wms.example.com/?SERVICE=WMS&amp;REQUEST=GetMap&amp;FORMAT=image/jpeg&amp;CRS={proj}&amp;WIDTH={width}&amp;HEIGHT={height}&amp;BBOX={bbox}</url>
+                    <bounds min-lat="47.2" min-lon="5.8" max-lat="55.1" max-lon="15.1">
+                        <shape><point lat="47.2" lon="5.8"/></shape>
+                    </bounds>
+                    <overlay>true</overlay>
+                </entry>
+                <entry>
+                    <name>Bing</name>
+                    <type>bing</type>
+                    <url>https://www.bing.com/maps</url>
+                </entry>
+            </imagery>"#;
+        let sources = from_imagery_xml(xml).unwrap();
+        assert_eq!(sources.len(), 3);
+
+        let osm = &sources[0];
+        assert_eq!(
+            get(osm, "url_template").and_then(|v| v.as_str()),
+            Some("https://{s}.tile.example.com/{z}/{x}/{y}.png"),
+        );
+        assert_eq!(get(osm, "subdomains").and_then(|v| v.as_array()).map(|s| s.len()), Some(3));
+        assert_eq!(get(osm, "min_zoom").and_then(|v| v.as_integer()), Some(0));
+        assert_eq!(get(osm, "description").and_then(|v| v.as_str()), Some("Standard map"));
+        assert_eq!(get(osm, "headers").and_then(|h| h.get("Referer")).and_then(|v| v.as_str()), Some("https://example.com/"));
+
+        let wms = &sources[1];
+        assert_eq!(
+            get(wms, "url_template").and_then(|v| v.as_str()),
+            Some("https://wms.example.com/?SERVICE=WMS&REQUEST=GetMap&FORMAT=image/jpeg&CRS=EPSG:3857&WIDTH=256&HEIGHT=256&BBOX={bbox}"),
+        );
+        assert_eq!(get(wms, "extension").and_then(|v| v.as_str()), Some("jpeg"));
+        assert_eq!(get(wms, "max_zoom").and_then(|v| v.as_integer()), Some(DEFAULT_MAX_ZOOM));
+        assert_eq!(
+            get(wms, "bounds"),
+            Some(&Value::Array(vec![Value::Float(5.8), Value::Float(47.2), Value::Float(15.1), Value::Float(55.1)])),
+        );
+        assert_eq!(get(wms, "overlay"), Some(&Value::Boolean(true)));
+
+        assert_eq!(sources[2], Err(("Bing".to_string(), "imagery type \"bing\" is not supported".to_string())));
+
+        assert!(from_imagery_xml("<imagery><entry>").is_err());
+        assert!(from_imagery_xml("<kml></kml>").is_err());
+    }
+
+    #[test]
+    fn qgis_connections() {
+        let xml = r#"<!DOCTYPE connections>
+            <qgsXYZTilesConnections version="1.0">
+                <xyztiles name="OpenStreetMap" url="https://tile.example.com/{z}/{x}/{y}.png?a=1&amp;b=2" zmin="0" zmax="19" authcfg="" username="" password="" referer="" tilePixelRatio="0"/>
+                <xyztiles name="HiDPI" url="https://hidpi.example.com/{z}/{x}/{y}@2x.webp" zmax="18" referer="https://example.com/" tilePixelRatio="2"/>
+            </qgsXYZTilesConnections>"#;
+        let sources = from_imagery_xml(xml).unwrap();
+        assert_eq!(sources.len(), 2);
+
+        let osm = &sources[0];
+        assert_eq!(get(osm, "name").and_then(|v| v.as_str()), Some("OpenStreetMap"));
+        assert_eq!(
+            get(osm, "url_template").and_then(|v| v.as_str()),
+            Some("https://tile.example.com/{z}/{x}/{y}.png?a=1&b=2"),
+        );
+        assert_eq!(get(osm, "extension").and_then(|v| v.as_str()), Some("png"));
+        assert_eq!(get(osm, "max_zoom").and_then(|v| v.as_integer()), Some(19));
+        assert_eq!(get(osm, "headers"), None);
+        assert_eq!(get(osm, "tile_size"), None);
+
+        let hidpi = &sources[1];
+        assert_eq!(get(hidpi, "extension").and_then(|v| v.as_str()), Some("webp"));
+        assert_eq!(get(hidpi, "tile_size").and_then(|v| v.as_integer()), Some(512));
+        assert_eq!(get(hidpi, "headers").and_then(|h| h.get("Referer")).and_then(|v| v.as_str()), Some("https://example.com/"));
+    }
+}
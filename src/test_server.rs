@@ -0,0 +1,162 @@
+//! A minimal HTTP server that stands in for a tile server in tests.
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+
+/// A request that was received by the `TestServer`.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub path: String,
+    /// Header names are converted to lower case.
+    pub headers: Vec<(String, String)>,
+    /// Time when the request was received
+    pub time: Instant,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// A response that is sent by the `TestServer`.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body,
+        }
+    }
+
+    /// A successful response with a PNG image of the given size.
+    pub fn png(size: u32) -> Self {
+        let mut resp = Response::new(200, png_data(size));
+        resp.headers.push(("Content-Type".to_string(), "image/png".to_string()));
+        resp
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Returns an encoded PNG image with the given edge length.
+pub fn png_data(size: u32) -> Vec<u8> {
+    let img = DynamicImage::ImageRgb8(RgbImage::new(size, size));
+    let mut buf = vec![];
+    img.write_to(&mut buf, ImageOutputFormat::PNG).unwrap();
+    buf
+}
+
+pub struct TestServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    /// Starts a server on a free local port. Every request is answered by `handler`. Connections
+    /// are handled concurrently, each on its own thread.
+    pub fn start<F>(handler: F) -> Self
+        where F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        {
+            let requests = Arc::clone(&requests);
+            let handler = Arc::new(handler);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(_) => break,
+                    };
+
+                    let requests = Arc::clone(&requests);
+                    let handler = Arc::clone(&handler);
+                    thread::spawn(move || Self::handle(stream, &*handler, &requests));
+                }
+            });
+        }
+
+        TestServer {
+            port,
+            requests,
+        }
+    }
+
+    fn handle<F>(mut stream: TcpStream, handler: &F, requests: &Mutex<Vec<Request>>)
+        where F: Fn(&Request) -> Response,
+    {
+        let request = {
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_err() {
+                return;
+            }
+            let time = Instant::now();
+            let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+
+            let mut headers = vec![];
+            loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {},
+                }
+                let l = line.trim_end();
+                if l.is_empty() {
+                    break;
+                }
+                if let Some(pos) = l.find(':') {
+                    headers.push((
+                        l[..pos].trim().to_lowercase(),
+                        l[pos + 1..].trim().to_string(),
+                    ));
+                }
+            }
+            Request { path, headers, time }
+        };
+
+        let response = handler(&request);
+        requests.lock().unwrap().push(request);
+
+        let mut head = format!(
+            "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.body.len(),
+        );
+        for (k, v) in &response.headers {
+            head += &format!("{}: {}\r\n", k, v);
+        }
+        head += "\r\n";
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(&response.body);
+    }
+
+    /// Returns the URL of the server followed by the given path.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    /// Returns all requests that have been answered so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
@@ -2,6 +2,7 @@ use ::context;
 use ::image;
 use context::{Context, TextureUnit};
 use image::GenericImageView;
+use std::borrow::Cow;
 use std::os::raw::c_void;
 
 #[derive(Clone, Debug)]
@@ -73,12 +74,10 @@ impl Texture {
         }
     }
 
+    /// Uploads the image at the given offset. Images that are neither RGB nor RGBA are converted
+    /// to RGBA first.
     pub fn sub_image(&mut self, cx: &mut Context, x: i32, y: i32, img: &image::DynamicImage) {
-        let format = match *img {
-            image::ImageRgb8(_) => TextureFormat::Rgb8,
-            image::ImageRgba8(_) => TextureFormat::Rgba8,
-            _ => return,
-        };
+        let (img, format) = uploadable_image(img);
 
         cx.set_active_texture_unit(self.texture_unit);
         unsafe {
@@ -148,3 +147,36 @@ impl TextureFormat {
         }
     }
 }
+
+/// Returns the image in a format that can be uploaded to a texture. Other images, like the
+/// grayscale tiles of hillshading overlays, are converted to RGBA.
+fn uploadable_image(img: &image::DynamicImage) -> (Cow<'_, image::DynamicImage>, TextureFormat) {
+    match *img {
+        image::ImageRgb8(_) => (Cow::Borrowed(img), TextureFormat::Rgb8),
+        image::ImageRgba8(_) => (Cow::Borrowed(img), TextureFormat::Rgba8),
+        _ => (Cow::Owned(image::ImageRgba8(img.to_rgba())), TextureFormat::Rgba8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use texture::*;
+
+    #[test]
+    fn convert_grayscale() {
+        let luma_alpha = image::ImageLumaA8(image::ImageBuffer::from_raw(2, 1, vec![10, 255, 20, 128]).unwrap());
+        let (img, format) = uploadable_image(&luma_alpha);
+        assert_eq!(format, TextureFormat::Rgba8);
+        assert_eq!(img.raw_pixels(), vec![10, 10, 10, 255, 20, 20, 20, 128]);
+
+        let luma = image::ImageLuma8(image::ImageBuffer::from_raw(1, 1, vec![7]).unwrap());
+        let (img, format) = uploadable_image(&luma);
+        assert_eq!(format, TextureFormat::Rgba8);
+        assert_eq!(img.raw_pixels(), vec![7, 7, 7, 255]);
+
+        let rgb = image::ImageRgb8(image::ImageBuffer::from_raw(1, 1, vec![1, 2, 3]).unwrap());
+        let (img, format) = uploadable_image(&rgb);
+        assert_eq!(format, TextureFormat::Rgb8);
+        assert!(match img { Cow::Borrowed(_) => true, Cow::Owned(_) => false });
+    }
+}
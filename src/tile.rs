@@ -6,6 +6,8 @@ use tile_source::TileSourceId;
 pub struct Tile {
     pub coord: TileCoord,
     pub source_id: TileSourceId,
+    /// Index of the time step for sources with a time dimension, 0 for all other sources.
+    pub time: u32,
 }
 
 impl Tile {
@@ -13,6 +15,16 @@ impl Tile {
         Tile {
             coord,
             source_id,
+            time: 0,
+        }
+    }
+
+    /// Creates a tile of the given time step of a source with a time dimension.
+    pub fn at_time(coord: TileCoord, source_id: TileSourceId, time: u32) -> Tile {
+        Tile {
+            coord,
+            source_id,
+            time,
         }
     }
 }
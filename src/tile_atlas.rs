@@ -1,6 +1,6 @@
 use context::Context;
 use coord::{SubTileCoord, TileCoord, TextureRect};
-use image;
+use image::{self, GenericImageView};
 use linked_hash_map::LinkedHashMap;
 use mercator_view;
 use orthografic_view;
@@ -15,9 +15,13 @@ use tile_source::TileSource;
 #[derive(Clone, Debug)]
 pub struct TileAtlas {
     texture: Texture,
-    tile_size: u32,
+    /// Edge length of a slot in pixels. Tiles that are smaller than a slot only occupy its
+    /// top-left corner.
+    slot_size: u32,
     slots_lru: LinkedHashMap<CacheSlot, Option<Tile>>, // LRU cache of slots
     tile_to_slot: HashMap<Tile, CacheSlot>,
+    /// Size of the image in each occupied slot.
+    slot_image_sizes: HashMap<CacheSlot, (u32, u32)>,
     use_async: bool,
 }
 
@@ -29,10 +33,22 @@ impl TileAtlas {
                 include_bytes!("../img/no_tile.png"),
             ).unwrap();
             self.texture.sub_image(cx, 0, 0, &img);
+            self.slot_image_sizes.clear();
+            self.slot_image_sizes.insert(Self::default_slot(), img.dimensions());
         }
 
-        let slots_x = self.texture.width() / self.tile_size;
-        let slots_y = self.texture.height() / self.tile_size;
+        // add tile for slot of tiles that failed to load
+        {
+            let img = image::load_from_memory(
+                include_bytes!("../img/missing_tile.png"),
+            ).unwrap();
+            let slot = Self::missing_slot();
+            self.texture.sub_image(cx, (slot.x * self.slot_size) as i32, (slot.y * self.slot_size) as i32, &img);
+            self.slot_image_sizes.insert(slot, img.dimensions());
+        }
+
+        let slots_x = self.texture.width() / self.slot_size;
+        let slots_y = self.texture.height() / self.slot_size;
         let num_slots = (slots_x * slots_y) as usize;
 
         self.slots_lru.clear();
@@ -44,17 +60,21 @@ impl TileAtlas {
             }
         }
         self.slots_lru.remove(&Self::default_slot());
+        self.slots_lru.remove(&Self::missing_slot());
 
         self.tile_to_slot.clear();
         self.tile_to_slot.reserve(num_slots);
     }
 
-    pub fn new(cx: &mut Context, tex: Texture, tile_size: u32, use_async: bool) -> Self {
+    /// Creates a new atlas. `slot_size` has to be the largest tile size that is going to be stored
+    /// in the atlas.
+    pub fn new(cx: &mut Context, tex: Texture, slot_size: u32, use_async: bool) -> Self {
         let mut atlas = TileAtlas {
             texture: tex,
-            tile_size,
+            slot_size,
             slots_lru: LinkedHashMap::new(),
             tile_to_slot: HashMap::new(),
+            slot_image_sizes: HashMap::new(),
             use_async,
         };
 
@@ -86,6 +106,20 @@ impl TileAtlas {
         CacheSlot { x: 0, y: 0 }
     }
 
+    /// Slot with the placeholder image for tiles that could not be loaded.
+    pub fn missing_slot() -> CacheSlot {
+        CacheSlot { x: 1, y: 0 }
+    }
+
+    /// Returns the slot of the placeholder image for a tile that is not available.
+    fn placeholder_slot(tile_coord: TileCoord, source: &TileSource, cache: &TileCache) -> CacheSlot {
+        if cache.failure(source.tile(tile_coord)).is_some() {
+            Self::missing_slot()
+        } else {
+            Self::default_slot()
+        }
+    }
+
     pub fn store(
         &mut self,
         cx: &mut Context,
@@ -95,7 +129,7 @@ impl TileAtlas {
         load: bool
     ) -> Option<CacheSlot> {
         let mut remove_tile = None;
-        let tile = Tile::new(tile_coord, source.id());
+        let tile = source.tile(tile_coord);
 
         let slot = match self.tile_to_slot.entry(tile) {
             Entry::Vacant(entry) => {
@@ -115,12 +149,21 @@ impl TileAtlas {
 
                     remove_tile = old_tile;
 
-                    self.texture.sub_image(
-                        cx,
-                        (slot.x * self.tile_size) as i32,
-                        (slot.y * self.tile_size) as i32,
-                        img,
-                    );
+                    let (width, height) = img.dimensions();
+                    let x = (slot.x * self.slot_size) as i32;
+                    let y = (slot.y * self.slot_size) as i32;
+
+                    if width > self.slot_size || height > self.slot_size {
+                        warn!("tile {:?} ({}x{}) is larger than the atlas slots, downscale to {}x{}",
+                              tile_coord, width, height, self.slot_size, self.slot_size);
+                        let img = img.resize(self.slot_size, self.slot_size, image::FilterType::Triangle);
+                        self.texture.sub_image(cx, x, y, &img);
+                        self.slot_image_sizes.insert(slot, img.dimensions());
+                    } else {
+                        self.texture.sub_image(cx, x, y, img);
+                        self.slot_image_sizes.insert(slot, (width, height));
+                    }
+
                     Some(*entry.insert(slot))
                 } else {
                     None
@@ -142,36 +185,47 @@ impl TileAtlas {
         slot
     }
 
+    /// Removes a tile from the atlas, so that its image is stored again on the next use.
+    pub fn invalidate_tile(&mut self, tile: Tile) {
+        if let Some(slot) = self.tile_to_slot.remove(&tile) {
+            if let Some(slot_tile) = self.slots_lru.get_mut(&slot) {
+                *slot_tile = None;
+            }
+        }
+    }
+
     /// Return 0.5 pixels in texture coordinates for both dimensions.
     pub fn texture_margins(&self) -> (f64, f64) {
         (0.5 / f64::from(self.texture.width()),
          0.5 / f64::from(self.texture.height()))
     }
 
+    /// Returns the texture rectangle of the image in the given slot.
     pub fn slot_to_texture_rect(&self, slot: CacheSlot) -> TextureRect {
-        let scale_x = f64::from(self.tile_size) / f64::from(self.texture.width());
-        let scale_y = f64::from(self.tile_size) / f64::from(self.texture.height());
+        let (width, height) = self.slot_image_sizes.get(&slot)
+            .cloned()
+            .unwrap_or((self.slot_size, self.slot_size));
+        let tex_width = f64::from(self.texture.width());
+        let tex_height = f64::from(self.texture.height());
+
+        let x1 = f64::from(slot.x * self.slot_size) / tex_width;
+        let y1 = f64::from(slot.y * self.slot_size) / tex_height;
 
         TextureRect {
-            x1: f64::from(slot.x) * scale_x,
-            y1: f64::from(slot.y) * scale_y,
-            x2: f64::from(slot.x + 1) * scale_x,
-            y2: f64::from(slot.y + 1) * scale_y,
+            x1,
+            y1,
+            x2: x1 + f64::from(width) / tex_width,
+            y2: y1 + f64::from(height) / tex_height,
         }
     }
 
     fn subslot_to_texture_rect(&self, slot: CacheSlot, sub_coord: SubTileCoord) -> TextureRect {
-        let scale_x = f64::from(self.tile_size) / (f64::from(self.texture.width()) *
-                                                   f64::from(sub_coord.size));
-        let scale_y = f64::from(self.tile_size) / (f64::from(self.texture.height()) *
-                                                   f64::from(sub_coord.size));
+        self.slot_to_texture_rect(slot).subdivide(&sub_coord)
+    }
 
-        TextureRect {
-            x1: f64::from(slot.x * sub_coord.size + sub_coord.x) * scale_x,
-            y1: f64::from(slot.y * sub_coord.size + sub_coord.y) * scale_y,
-            x2: f64::from(slot.x * sub_coord.size + sub_coord.x + 1) * scale_x,
-            y2: f64::from(slot.y * sub_coord.size + sub_coord.y + 1) * scale_y,
-        }
+    /// Returns true if tiles are loaded in the background.
+    pub fn use_async(&self) -> bool {
+        self.use_async
     }
 
     pub fn texture(&self) -> &Texture {
@@ -193,6 +247,9 @@ pub trait VisibleTilesProvider<U, T> {
     /// tiles is returned as an `Option`.
     /// The function should guarantee that no more than `max_tiles_to_use` tiles are used for texturing;
     /// the number of used tiles is returned as an `usize`.
+    /// Tiles that are not available (also not from other zoom levels) are textured with a
+    /// placeholder image if `placeholder` is true and left out otherwise. Tiles outside of the
+    /// bounds of the source never get a placeholder.
     fn textured_visible_tiles<'b>(
         &mut self,
         cx: &mut Context,
@@ -200,6 +257,7 @@ pub trait VisibleTilesProvider<U, T> {
         max_tiles_to_use: usize,
         source: &TileSource,
         cache: &mut TileCache,
+        placeholder: bool,
     ) -> (Vec<T>, Option<&'b [U]>, usize);
 }
 
@@ -212,6 +270,7 @@ for TileAtlas {
         max_tiles_to_use: usize,
         source: &TileSource,
         cache: &mut TileCache,
+        placeholder: bool,
     ) -> (Vec<mercator_view::TexturedVisibleTile>, Option<&'b [mercator_view::VisibleTile]>, usize)
     {
         let mut tvt = Vec::with_capacity(visible_tiles.len());
@@ -228,7 +287,16 @@ for TileAtlas {
                 return (tvt, Some(&visible_tiles[i..]), used_slots);
             }
 
-            if let Some(slot) = self.store(cx, vt.tile, source, cache, true) {
+            // Tiles outside of the bounds of the source are never loaded and only drawn with
+            // tiles from other zoom levels.
+            let in_bounds = source.intersects_bounds(vt.tile);
+            let slot = if in_bounds {
+                self.store(cx, vt.tile, source, cache, true)
+            } else {
+                None
+            };
+
+            if let Some(slot) = slot {
                 let tex_rect = self.slot_to_texture_rect(slot);
                 used_slots += 1;
                 tvt.push(
@@ -246,8 +314,10 @@ for TileAtlas {
                 }
 
                 // default tile
-                let mut tex_sub_rect = self.slot_to_texture_rect(Self::default_slot());
+                let placeholder_slot = Self::placeholder_slot(vt.tile, source, cache);
+                let mut tex_sub_rect = self.slot_to_texture_rect(placeholder_slot);
                 let mut tex_rect = tex_sub_rect;
+                let mut fill_missing = placeholder && in_bounds;
 
                 // look for cached tiles in lower zoom layers
                 for dist in 1..31 {
@@ -256,6 +326,7 @@ for TileAtlas {
                             used_slots += 1;
                             tex_sub_rect = self.subslot_to_texture_rect(slot, sub_coord);
                             tex_rect = self.slot_to_texture_rect(slot);
+                            fill_missing = true;
                             break;
                         }
                     } else {
@@ -278,7 +349,7 @@ for TileAtlas {
                                 tex_minmax: tex_rect.inset(inset_x, inset_y),
                             }
                         );
-                    } else {
+                    } else if fill_missing {
                         tvt.push(
                             mercator_view::TexturedVisibleTile {
                                 screen_rect: vt.rect.subdivide(&child_sub_coord),
@@ -304,6 +375,7 @@ for TileAtlas {
         max_tiles_to_use: usize,
         source: &TileSource,
         cache: &mut TileCache,
+        placeholder: bool,
     ) -> (Vec<orthografic_view::TexturedVisibleTile>, Option<&'b [orthografic_view::VisibleTile]>,
           usize)
     {
@@ -321,7 +393,16 @@ for TileAtlas {
                 return (tvt, Some(&visible_tiles[i..]), used_slots);
             }
 
-            if let Some(slot) = self.store(cx, vt.tile, source, cache, true) {
+            // Tiles outside of the bounds of the source are never loaded and only drawn with
+            // tiles from other zoom levels.
+            let in_bounds = source.intersects_bounds(vt.tile);
+            let slot = if in_bounds {
+                self.store(cx, vt.tile, source, cache, true)
+            } else {
+                None
+            };
+
+            if let Some(slot) = slot {
                 let tex_rect = self.slot_to_texture_rect(slot);
                 used_slots += 1;
                 tvt.push(
@@ -339,8 +420,10 @@ for TileAtlas {
                 }
 
                 // default tile
-                let mut tex_sub_rect = self.slot_to_texture_rect(Self::default_slot());
+                let placeholder_slot = Self::placeholder_slot(vt.tile, source, cache);
+                let mut tex_sub_rect = self.slot_to_texture_rect(placeholder_slot);
                 let mut tex_rect = tex_sub_rect;
+                let mut fill_missing = placeholder && in_bounds;
 
                 // look for cached tiles in lower zoom layers
                 for dist in 1..31 {
@@ -349,6 +432,7 @@ for TileAtlas {
                             used_slots += 1;
                             tex_sub_rect = self.subslot_to_texture_rect(slot, sub_coord);
                             tex_rect = self.slot_to_texture_rect(slot);
+                            fill_missing = true;
                             break;
                         }
                     } else {
@@ -371,7 +455,7 @@ for TileAtlas {
                                 tex_minmax: tex_rect.inset(inset_x, inset_y),
                             }
                         );
-                    } else {
+                    } else if fill_missing {
                         tvt.push(
                             orthografic_view::TexturedVisibleTile {
                                 tile_coord: child_tile,
@@ -1,19 +1,71 @@
-use image;
-use linked_hash_map::{Entry, LinkedHashMap};
+use image::{self, GenericImageView};
+use linked_hash_map::LinkedHashMap;
 use coord::{TileCoord, View};
+use failure_registry::Failure;
+use prefetch::{prefetch_tiles, PrefetchSettings};
+use size::format_size;
+use std::fmt;
 use tile::Tile;
-use tile_loader::TileLoader;
-use tile_source::TileSource;
+use tile_loader::{DownloadSettings, TileLoader};
+use tile_source::{TileSource, TileSourceId};
 
 
+/// Lower bound of the default memory budget in bytes
+const MIN_DEFAULT_BUDGET: usize = 64 << 20;
+/// The default memory budget can hold the tiles of this many viewports, e.g. for panning back and
+/// forth, zooming and overlays.
+const DEFAULT_BUDGET_VIEWPORTS: usize = 16;
+
+/// Counters of cache accesses and the current memory usage.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Requested tiles that were in the cache
+    pub hits: u64,
+    /// Requested tiles that had to be loaded
+    pub misses: u64,
+    /// Tiles that were removed to stay within the memory budget
+    pub evictions: u64,
+    pub tiles: usize,
+    /// Size of the decoded images in bytes
+    pub bytes: usize,
+    pub max_bytes: usize,
+    /// Number of threads that download tiles
+    pub download_threads: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} tiles, {} of {}, {} hits, {} misses, {} evictions, {} download threads",
+            self.tiles,
+            format_size(self.bytes as u64),
+            format_size(self.max_bytes as u64),
+            self.hits,
+            self.misses,
+            self.evictions,
+            self.download_threads,
+        )
+    }
+}
+
 pub struct TileCache {
     loader: TileLoader,
     map: LinkedHashMap<Tile, image::DynamicImage>,
-    max_tiles: usize,
+    /// Memory budget in bytes or `None` to derive it from the viewport size.
+    max_bytes: Option<usize>,
+    /// Size of the viewport in physical pixels
+    viewport_size: (u32, u32),
+    /// Total size of the decoded images in the cache
+    used_bytes: usize,
+    stats: CacheStats,
+    prefetch: PrefetchSettings,
+    /// Tiles whose image was replaced with a newer version.
+    replaced_tiles: Vec<Tile>,
 }
 
 impl TileCache {
-    pub fn new<F>(new_tile_func: F, use_network: bool) -> Self
+    pub fn new<F>(new_tile_func: F, use_network: bool, download: &DownloadSettings) -> Self
         where F: Fn(Tile) + Sync + Send + 'static,
     {
         TileCache {
@@ -22,15 +74,96 @@ impl TileCache {
                     new_tile_func(tile);
                 },
                 use_network,
+                download,
             ),
             map: LinkedHashMap::new(),
-            max_tiles: 512, //TODO set a reasonable value
+            max_bytes: None,
+            viewport_size: (0, 0),
+            used_bytes: 0,
+            stats: CacheStats::default(),
+            prefetch: PrefetchSettings::default(),
+            replaced_tiles: vec![],
         }
     }
 
-    // Return the maximum number of tiles that this cache can hold at once.
-    pub fn max_tiles(&self) -> usize {
-        self.max_tiles
+    /// Set a fixed memory budget in bytes for the decoded tile images. By default, the budget
+    /// scales with the size of the viewport.
+    pub fn with_memory_budget(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Set which tiles are loaded in advance in addition to the visible tiles.
+    pub fn with_prefetch(mut self, settings: PrefetchSettings) -> Self {
+        self.prefetch = settings;
+        self
+    }
+
+    /// Like `with_memory_budget`, but `None` restores the default budget. Tiles are evicted if
+    /// the cache is over the new budget.
+    pub fn set_memory_budget(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    pub fn set_prefetch(&mut self, settings: PrefetchSettings) {
+        self.prefetch = settings;
+    }
+
+    /// Returns the memory budget in bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.unwrap_or_else(|| default_budget(self.viewport_size))
+    }
+
+    /// Set the size of the viewport in physical pixels, which determines the default memory
+    /// budget.
+    pub fn set_viewport_size(&mut self, viewport_size: (u32, u32)) {
+        self.viewport_size = viewport_size;
+        self.evict();
+    }
+
+    /// Return the maximum number of tiles with the given edge length that this cache can hold at
+    /// once.
+    pub fn max_tiles(&self, tile_size: u32) -> usize {
+        let tile_bytes = (tile_size as usize * tile_size as usize * 4).max(1);
+        (self.max_bytes() / tile_bytes).max(1)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            tiles: self.map.len(),
+            bytes: self.used_bytes,
+            max_bytes: self.max_bytes(),
+            download_threads: self.loader.download_threads(),
+            ..self.stats
+        }
+    }
+
+    /// Inserts an image and evicts the least recently used tiles if the cache is over budget.
+    /// Returns true if an image of the same tile was replaced.
+    fn insert(&mut self, tile: Tile, img: image::DynamicImage) -> bool {
+        self.used_bytes += image_size(&img);
+        let replaced = match self.map.insert(tile, img) {
+            Some(old_img) => {
+                self.used_bytes -= image_size(&old_img);
+                true
+            },
+            None => false,
+        };
+        self.evict();
+        replaced
+    }
+
+    /// Removes the least recently used tiles until the cache is within budget. The most recent
+    /// tile is always kept.
+    fn evict(&mut self) {
+        let max_bytes = self.max_bytes();
+        while self.used_bytes > max_bytes && self.map.len() > 1 {
+            if let Some((_, img)) = self.map.pop_front() {
+                self.used_bytes -= image_size(&img);
+                self.stats.evictions += 1;
+            }
+        }
     }
 
     pub fn get_sync(
@@ -40,24 +173,17 @@ impl TileCache {
         write_to_file: bool,
         ) -> Option<&image::DynamicImage>
     {
-        let tile = Tile::new(tile_coord, source.id());
+        let tile = source.tile(tile_coord);
 
-        //TODO Return the value from get_refresh with borrowck agreeing that this is OK.
-        self.map.get_refresh(&tile);
-
-        // remove old cache entries
-        while self.map.len() + 1 > self.max_tiles {
-            self.map.pop_front();
+        if self.map.get_refresh(&tile).is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            let img = self.loader.get_sync(tile_coord, source, write_to_file)?;
+            self.insert(tile, img);
         }
 
-        match self.map.entry(tile) {
-            Entry::Occupied(entry) => {
-                Some(entry.into_mut())
-            },
-            Entry::Vacant(entry) => {
-                self.loader.get_sync(tile_coord, source, write_to_file).map(|img| entry.insert(img) as &_)
-            },
-        }
+        self.map.get(&tile)
     }
 
     pub fn get_async(
@@ -68,28 +194,43 @@ impl TileCache {
         ) -> Option<&image::DynamicImage>
     {
         while let Some((t, img)) = self.loader.async_result() {
-            // remove old cache entries
-            while self.map.len() + 1 > self.max_tiles {
-                self.map.pop_front();
+            if self.insert(t, img) {
+                self.replaced_tiles.push(t);
             }
-
-            self.map.insert(t, img);
         }
 
-        let tile = Tile::new(tile_coord, source.id());
+        let tile = source.tile(tile_coord);
 
         //TODO Return the value from get_refresh with borrowck agreeing that this is OK.
-        self.map.get_refresh(&tile);
-
-        match self.map.entry(tile) {
-            Entry::Occupied(entry) => Some(entry.into_mut()),
-            Entry::Vacant(_) => {
-                self.loader.async_request(tile_coord, source, write_to_file);
-                None
+        if self.map.get_refresh(&tile).is_some() {
+            self.stats.hits += 1;
+            self.map.get(&tile)
+        } else {
+            // A tile that is still loading is drawn in every frame, but only missed once.
+            if self.loader.async_request(tile_coord, source, write_to_file) {
+                self.stats.misses += 1;
             }
+            None
         }
     }
 
+    /// Loads the tiles around the visible tiles and of the adjacent zoom levels in the background
+    /// with a low priority. The visible tiles of the time steps in `upcoming_times` are loaded
+    /// first, so an animation can show them without waiting. Only half of the cache is used for
+    /// visible and prefetched tiles, so prefetching does not evict tiles that are still needed.
+    pub fn prefetch(&mut self, visible: &[TileCoord], source: &TileSource, upcoming_times: &[u32]) {
+        let max_tiles = (self.max_tiles(source.tile_size()) / 2).saturating_sub(visible.len());
+        let upcoming = upcoming_times.iter()
+            .flat_map(|&time| visible.iter().map(move |&tile_coord| Tile::at_time(tile_coord, source.id(), time)));
+        let around = prefetch_tiles(visible, &self.prefetch).into_iter()
+            .map(|tile_coord| source.tile(tile_coord));
+        let tiles: Vec<Tile> = upcoming.chain(around)
+            .filter(|tile| !self.map.contains_key(tile))
+            .take(max_tiles)
+            .collect();
+        self.loader.prefetch(&tiles, source, true);
+    }
+
     // Return a tile from the cache but do not use TileLoader.
     pub fn lookup(&mut self, tile: Tile) -> Option<&image::DynamicImage> {
         //TODO Return the value from get_refresh with borrowck agreeing that this is OK.
@@ -98,13 +239,47 @@ impl TileCache {
         self.map.get(&tile)
     }
 
+    /// Returns the last failure if the most recent attempt to load `tile` failed.
+    pub fn failure(&self, tile: Tile) -> Option<&Failure> {
+        self.loader.failure(tile)
+    }
+
+    /// Returns the tiles whose image was replaced since the last call, e.g. after an expired tile
+    /// was loaded again.
+    pub fn take_replaced_tiles(&mut self) -> Vec<Tile> {
+        ::std::mem::take(&mut self.replaced_tiles)
+    }
+
+    /// Enables or disables downloading tiles.
+    pub fn set_use_network(&mut self, use_network: bool) {
+        self.loader.set_use_network(use_network);
+    }
+
     pub fn set_view_location(&mut self, view: View) {
         self.loader.set_view_location(view);
     }
+
+    /// Stops loading tiles of sources other than the given ones.
+    pub fn retain_sources(&mut self, ids: &[TileSourceId]) {
+        self.loader.retain_sources(ids);
+    }
+}
+
+/// Returns the default memory budget for a viewport of the given size in physical pixels.
+fn default_budget(viewport_size: (u32, u32)) -> usize {
+    let viewport_bytes = viewport_size.0 as usize * viewport_size.1 as usize * 4;
+    (viewport_bytes * DEFAULT_BUDGET_VIEWPORTS).max(MIN_DEFAULT_BUDGET)
+}
+
+/// Returns the size of the decoded pixel data in bytes.
+fn image_size(img: &image::DynamicImage) -> usize {
+    let (width, height) = img.dimensions();
+    // All color types of decoded images use whole bytes per pixel.
+    width as usize * height as usize * img.color().bits_per_pixel() as usize / 8
 }
 
-impl ::std::fmt::Debug for TileCache {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+impl fmt::Debug for TileCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "TileCache {{ tiles: {:?} }}",
@@ -112,3 +287,77 @@ impl ::std::fmt::Debug for TileCache {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tile_cache::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tile_provider::MemoryProvider;
+    use tile_source::TileSourceId;
+
+    #[test]
+    fn memory_budget() {
+        let tile_bytes = 256 * 256 * 4;
+        let mut cache = TileCache::new(|_| {}, false, &DownloadSettings::default())
+            .with_memory_budget(tile_bytes * 3);
+        assert_eq!(cache.max_tiles(256), 3);
+        assert_eq!(cache.max_tiles(512), 1);
+
+        let tile = |x| Tile::new(TileCoord::new(4, x, 0), TileSourceId::new(0));
+        let rgba = || image::DynamicImage::new_rgba8(256, 256);
+
+        for x in 0..3 {
+            assert!(!cache.insert(tile(x), rgba()));
+        }
+        assert!(cache.lookup(tile(0)).is_some());
+
+        // Tile 1 is the least recently used one.
+        assert!(!cache.insert(tile(3), rgba()));
+        assert!(cache.lookup(tile(1)).is_none());
+        assert!(cache.lookup(tile(0)).is_some());
+
+        // Grayscale images take up less memory.
+        assert!(cache.insert(tile(3), image::DynamicImage::new_luma8(256, 256)));
+        assert!(!cache.insert(tile(4), image::DynamicImage::new_luma8(256, 256)));
+
+        let stats = cache.stats();
+        assert_eq!(stats.tiles, 4);
+        assert_eq!(stats.bytes, tile_bytes * 2 + 256 * 256 * 2);
+        assert_eq!(stats.evictions, 1);
+
+        // A smaller budget applies right away.
+        cache.set_memory_budget(Some(tile_bytes));
+        assert_eq!(cache.stats().tiles, 2);
+        cache.set_memory_budget(None);
+        assert_eq!(cache.max_bytes(), MIN_DEFAULT_BUDGET);
+    }
+
+    #[test]
+    fn count_misses_once() {
+        let provider = Arc::new(MemoryProvider::new());
+        let tile_coord = TileCoord::new(3, 1, 2);
+        provider.insert(tile_coord, image::DynamicImage::new_rgba8(256, 256));
+        let source = TileSource::from_provider(0, provider, 0, 19);
+        let mut cache = TileCache::new(|_| {}, false, &DownloadSettings::default());
+
+        let start = Instant::now();
+        while cache.get_async(tile_coord, &source, false).is_none() && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(cache.get_async(tile_coord, &source, false).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 1);
+        assert!(stats.hits >= 2);
+    }
+
+    #[test]
+    fn default_budget_scales_with_viewport() {
+        let mut cache = TileCache::new(|_| {}, false, &DownloadSettings::default());
+        assert_eq!(cache.max_bytes(), MIN_DEFAULT_BUDGET);
+        cache.set_viewport_size((3840, 2160));
+        assert_eq!(cache.max_bytes(), 3840 * 2160 * 4 * DEFAULT_BUDGET_VIEWPORTS);
+    }
+}
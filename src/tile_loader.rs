@@ -1,339 +1,851 @@
 use coord::{TileCoord, View};
+use failure_registry::{Failure, FailureRegistry};
 use image::DynamicImage;
-use image;
+use rate_limiter::RateLimiter;
 use reqwest::Client;
 use std::cmp::Ordering;
 use std::cmp;
+use std::collections::HashMap;
 use std::collections::hash_set::HashSet;
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
 use std::sync::mpsc::TryRecvError;
-use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, mpsc, Mutex};
 use std::thread;
+use std::time::Instant;
 use tile::Tile;
-use tile_source::TileSource;
+use tile_provider::{LoadError, TileProvider};
+use tile_source::{DownloadLimits, TileSource, TileSourceId};
 
 
-//TODO remember failed loading attempts
+/// Maximum number of requests that wait for a remote worker. Requests with the lowest priority
+/// are dropped if the queue is full.
+const MAX_REMOTE_QUEUE_LEN: usize = 128;
 
+/// Maximum number of pending prefetch requests. The rest of the remote queue is left for visible
+/// tiles, so prefetch requests are not dropped and sent again on every redraw.
+const MAX_PREFETCH_LEN: usize = MAX_REMOTE_QUEUE_LEN / 2;
+
+/// Queued remote requests are dropped if their zoom level differs more from the zoom of the view.
+const MAX_ZOOM_DISTANCE: u32 = 2;
+
+/// Queued remote requests are dropped if they are farther away from the center of the view than
+/// this multiple of the view radius.
+const MAX_VIEW_DISTANCE: f64 = 1.5;
+
+/// Settings for downloading tiles that apply to all sources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadSettings {
+    /// Number of threads that download the tiles of all sources without dedicated threads
+    pub workers: usize,
+    /// Maximum number of requests per second for each host name. A limit also applies to all
+    /// subdomains of the host.
+    pub rate_limits: HashMap<String, f64>,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        DownloadSettings {
+            workers: 2,
+            rate_limits: HashMap::new(),
+        }
+    }
+}
+
+/// Schedules and prioritizes requests for tiles. The tiles are loaded by the `TileProvider` of
+/// each `TileSource`.
 #[derive(Debug)]
 pub struct TileLoader {
-    client: Option<Client>,
+    /// HTTP client for synchronous requests. It is created on first use; the inner `None` means
+    /// that creating it failed.
+    client: Option<Option<Client>>,
+    rate_limiter: Arc<RateLimiter>,
     join_handle: thread::JoinHandle<()>,
     request_tx: mpsc::Sender<LoaderMessage>,
-    result_rx: mpsc::Receiver<(Tile, Option<DynamicImage>)>,
+    result_rx: mpsc::Receiver<(Tile, LoadResult)>,
     pending: HashSet<Tile>,
-    use_network: bool,
+    /// Pending tiles that were requested with `prefetch`
+    prefetching: HashSet<Tile>,
+    failures: FailureRegistry,
+    /// Shared with the worker threads, so the network mode can be changed while running.
+    use_network: Arc<AtomicBool>,
+    /// Number of running download threads
+    remote_workers: Arc<AtomicUsize>,
 }
 
 impl TileLoader {
-    pub fn new<F>(notice_func: F, use_network: bool) -> Self
+    pub fn new<F>(notice_func: F, use_network: bool, settings: &DownloadSettings) -> Self
         where F: Fn(Tile) + Sync + Send + 'static,
     {
         let (request_tx, request_rx) = mpsc::channel();
         let (result_tx, result_rx) = mpsc::channel();
+        let rate_limiter = Arc::new(RateLimiter::new(&settings.rate_limits));
+        let use_network = Arc::new(AtomicBool::new(use_network));
+        let remote_workers = Arc::new(AtomicUsize::new(0));
+
+        let join_handle = {
+            let rate_limiter = Arc::clone(&rate_limiter);
+            let use_network = Arc::clone(&use_network);
+            let remote_workers = Arc::clone(&remote_workers);
+            let workers = settings.workers;
+            thread::spawn(move || {
+                Self::work(&request_rx, &result_tx, notice_func, &use_network, workers, &rate_limiter, &remote_workers)
+            })
+        };
 
         TileLoader {
             client: None,
-            join_handle: thread::spawn(move || Self::work(&request_rx, &result_tx, notice_func, use_network)),
+            rate_limiter,
+            join_handle,
             request_tx,
             result_rx,
             pending: HashSet::new(),
+            prefetching: HashSet::new(),
+            failures: FailureRegistry::new(),
             use_network,
+            remote_workers,
         }
     }
 
     fn work<F>(
         request_rx: &mpsc::Receiver<LoaderMessage>,
-        result_tx: &mpsc::Sender<(Tile, Option<DynamicImage>)>,
+        result_tx: &mpsc::Sender<(Tile, LoadResult)>,
         notice_func: F,
-        use_network: bool,
+        use_network: &Arc<AtomicBool>,
+        num_workers: usize,
+        rate_limiter: &Arc<RateLimiter>,
+        remote_workers: &Arc<AtomicUsize>,
     )
         where F: Fn(Tile) + Sync + Send + 'static,
     {
-        let mut queue: Vec<TileRequest> = vec![];
-        let remote_queue: Arc<Mutex<Vec<TileRequest>>> = Arc::new(Mutex::new(vec![]));
+        let local_queue = Arc::new((Mutex::new(LocalQueue::default()), Condvar::new()));
+        let remote_queue = Arc::new((Mutex::new(RemoteQueue::default()), Condvar::new()));
         let mut view_opt: Option<View> = None;
 
         let arc_notice_func = Arc::new(notice_func);
 
-        let (remote_request_tx, remote_request_rx) = mpsc::channel();
-        {
-            let arc_request_rx = Arc::new(Mutex::new(remote_request_rx));
-            for id in 0..2 {
+        // Reading and decoding tiles is CPU-bound, so use all cores.
+        for _ in 0..num_cpus::get().max(1) {
+            let local_queue = Arc::clone(&local_queue);
+            let remote_queue = Arc::clone(&remote_queue);
+            let result_tx = result_tx.clone();
+            let arc_notice_func = Arc::clone(&arc_notice_func);
+            let use_network = Arc::clone(use_network);
+            thread::spawn(move || {
+                Self::work_local(&local_queue, &remote_queue, &use_network, &result_tx, &arc_notice_func)
+            });
+        }
+
+        let mut next_thread_id = 0;
+        let mut spawn_workers = |num: usize, dedicated: Option<TileSourceId>| {
+            for _ in 0..num {
                 let remote_queue = Arc::clone(&remote_queue);
-                let arc_request_rx = Arc::clone(&arc_request_rx);
+                let rate_limiter = Arc::clone(rate_limiter);
                 let result_tx = result_tx.clone();
                 let arc_notice_func = Arc::clone(&arc_notice_func);
-                thread::spawn(move || Self::work_remote(id, &remote_queue, &arc_request_rx, &result_tx, &arc_notice_func));
+                let remote_workers = Arc::clone(remote_workers);
+                let thread_id = next_thread_id;
+                remote_workers.fetch_add(1, AtomicOrdering::SeqCst);
+                thread::spawn(move || {
+                    Self::work_remote(thread_id, dedicated, &remote_queue, &rate_limiter, &result_tx, &arc_notice_func);
+                    remote_workers.fetch_sub(1, AtomicOrdering::SeqCst);
+                });
+                next_thread_id += 1;
             }
-        }
+        };
+
+        spawn_workers(num_workers, None);
+        let mut dedicated_workers: HashSet<TileSourceId> = HashSet::new();
+        // Sources that are still in use or `None` if all sources are
+        let mut live_sources: Option<HashSet<TileSourceId>> = None;
 
         'outer: while let Ok(message) = request_rx.recv() {
-            let mut need_to_sort = true;
+            let mut new_requests = vec![];
+            let mut cancelled: HashSet<Tile> = HashSet::new();
+            let mut promoted: HashSet<Tile> = HashSet::new();
+            let mut dropped = vec![];
+            let mut sources_changed = false;
+            let mut view_changed = false;
+            let mut next_message = Some(message);
 
-            match message {
-                LoaderMessage::SetView(view) => {
-                    view_opt = Some(view);
-                },
-                LoaderMessage::GetTile(request) => {
-                    queue.push(request);
+            // Handle all waiting messages at once to sort the queues only once.
+            while let Some(message) = next_message {
+                match message {
+                    LoaderMessage::SetView(view) => {
+                        view_opt = Some(view);
+                        view_changed = true;
+                    },
+                    LoaderMessage::GetTile(ref request) if !is_live(&live_sources, request.tile.source_id) => {
+                        dropped.push(request.tile);
+                    },
+                    LoaderMessage::GetTile(request) => {
+                        if let Some(num) = request.limits.workers {
+                            let remote = use_network.load(AtomicOrdering::Relaxed) && request.provider.is_remote();
+                            if remote && dedicated_workers.insert(request.tile.source_id) {
+                                spawn_workers(num, Some(request.tile.source_id));
+                            }
+                        }
+                        new_requests.push(request);
+                    },
+                    LoaderMessage::CancelPrefetch(tiles) => {
+                        cancelled.extend(tiles);
+                        for tile in cancel_prefetch(&mut new_requests, &cancelled) {
+                            if result_tx.send((tile, LoadResult::Dropped)).is_err() {
+                                break 'outer;
+                            }
+                        }
+                    },
+                    LoaderMessage::Promote(tile) => {
+                        promote(&mut new_requests, tile);
+                        promoted.insert(tile);
+                    },
+                    LoaderMessage::RetainSources(ids) => {
+                        // Dedicated workers that are spawned from now on have to see the new
+                        // sources, so they do not terminate right away.
+                        if let Ok(mut remote_queue) = remote_queue.0.lock() {
+                            remote_queue.live_sources = Some(ids.clone());
+                        }
+                        live_sources = Some(ids);
+                        sources_changed = true;
+                    },
                 }
+
+                next_message = match request_rx.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break 'outer,
+                };
             }
 
-            loop {
-                loop {
-                    let message = request_rx.try_recv();
-
-                    match message {
-                        Ok(LoaderMessage::SetView(view)) => {
-                            view_opt = Some(view);
-                            need_to_sort = true;
-                        },
-                        Ok(LoaderMessage::GetTile(request)) => {
-                            queue.push(request);
-                            need_to_sort = true;
-                        },
-                        Err(TryRecvError::Empty) => break,
-                        Err(TryRecvError::Disconnected) => break 'outer,
+            if sources_changed {
+                dropped.extend(retain_sources(&mut new_requests, &live_sources));
+                dedicated_workers.retain(|&id| is_live(&live_sources, id));
+            }
+
+            if let Ok(mut local_queue) = local_queue.0.lock() {
+                local_queue.view = view_opt;
+                if sources_changed {
+                    dropped.extend(retain_sources(&mut local_queue.requests, &live_sources));
+                }
+                dropped.extend(cancel_prefetch(&mut local_queue.requests, &cancelled));
+                for &tile in &promoted {
+                    promote(&mut local_queue.requests, tile);
+                }
+                local_queue.requests.append(&mut new_requests);
+                if let Some(view) = view_opt {
+                    local_queue.requests.sort_by(|a, b| compare_tiles(a, b, view));
+                }
+            }
+            local_queue.1.notify_all();
+
+            if sources_changed {
+                if let Ok(mut remote_queue) = remote_queue.0.lock() {
+                    dropped.extend(retain_sources(&mut remote_queue.requests, &live_sources));
+                }
+                // Let the dedicated workers of removed sources terminate.
+                remote_queue.1.notify_all();
+            }
+
+            if view_changed || !cancelled.is_empty() || !promoted.is_empty() {
+                if let Ok(mut remote_queue) = remote_queue.0.lock() {
+                    dropped.extend(cancel_prefetch(&mut remote_queue.requests, &cancelled));
+                    for &tile in &promoted {
+                        promote(&mut remote_queue.requests, tile);
                     }
+                    dropped.extend(sort_remote_queue(&mut remote_queue.requests, view_opt));
                 }
+            }
 
-                if need_to_sort {
-                    if let Some(view) = view_opt {
-                        need_to_sort = false;
+            for tile in dropped {
+                if result_tx.send((tile, LoadResult::Dropped)).is_err() {
+                    break 'outer;
+                }
+            }
+        }
+
+        // Let the workers terminate.
+        if let Ok(mut local_queue) = local_queue.0.lock() {
+            local_queue.closed = true;
+        }
+        local_queue.1.notify_all();
+        if let Ok(mut remote_queue) = remote_queue.0.lock() {
+            remote_queue.closed = true;
+        }
+        remote_queue.1.notify_all();
+    }
 
-                        queue.as_mut_slice().sort_by(|a, b| {
-                            compare_tiles(a.tile, b.tile, view)
-                        });
+    /// Reads and decodes the tiles in the local queue, the most important one first. Tiles that
+    /// are not available or expired are moved to the remote queue.
+    fn work_local<F>(
+        local_queue: &Arc<(Mutex<LocalQueue>, Condvar)>,
+        remote_queue: &Arc<(Mutex<RemoteQueue>, Condvar)>,
+        use_network: &AtomicBool,
+        result_tx: &mpsc::Sender<(Tile, LoadResult)>,
+        notice_func: &Arc<F>,
+    )
+        where F: Fn(Tile) + Sync + Send + 'static,
+    {
+        let (ref queue, ref condvar) = **local_queue;
 
-                        if let Ok(mut remote_queue) = remote_queue.lock() {
-                            remote_queue.as_mut_slice().sort_by(|a, b| {
-                                compare_tiles(a.tile, b.tile, view)
-                            });
-                        }
+        loop {
+            let (request, view_opt) = {
+                let mut guard = match queue.lock() {
+                    Ok(guard) => guard,
+                    Err(_) => return,
+                };
+                loop {
+                    if guard.closed {
+                        return;
                     }
+                    if let Some(request) = guard.requests.pop() {
+                        break (request, guard.view);
+                    }
+                    guard = match condvar.wait(guard) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
                 }
+            };
 
-                match queue.pop() {
-                    None => break,
-                    Some(request) => {
-                        match image::open(&request.path) {
-                            Ok(img) => {
-                                if result_tx.send((request.tile, Some(img))).is_err() {
-                                    break 'outer;
-                                }
-                                arc_notice_func(request.tile);
-                                continue;
-                            },
-                            Err(_) => {
-                                if use_network {
-                                    if let Ok(mut remote_queue) = remote_queue.lock() {
-                                        //TODO restrict size of remote_queue
-                                        remote_queue.push(request);
-                                        if let Some(view) = view_opt {
-                                            remote_queue.as_mut_slice().sort_by(|a, b| {
-                                                compare_tiles(a.tile, b.tile, view)
-                                            });
-                                        }
-                                        if let Err(e) = remote_request_tx.send(RemoteLoaderMessage::PopQueue) {
-                                            //TODO what now? restart worker?
-                                            error!("remote worker terminated, {}", e);
-                                        }
-                                    }
-                                } else if result_tx.send((request.tile, None)).is_err() {
-                                    break 'outer;
-                                }
-                            },
-                        }
+            let use_remote = use_network.load(AtomicOrdering::Relaxed) && request.provider.is_remote();
+
+            let load_remote = match request.provider.load_local(request.tile.coord) {
+                Some(img) => {
+                    if result_tx.send((request.tile, LoadResult::Loaded(img))).is_err() {
+                        return;
+                    }
+                    notice_func(request.tile);
+
+                    // Show the expired tile until it is revalidated.
+                    use_remote && request.provider.is_expired(request.tile.coord)
+                },
+                None if use_remote => true,
+                None => {
+                    // Tiles of remote providers may be downloaded later, so this is not permanent.
+                    let error = if request.provider.is_remote() {
+                        LoadError::Offline
+                    } else {
+                        LoadError::Unavailable
+                    };
+                    let result = LoadResult::Failed(error);
+                    if result_tx.send((request.tile, result)).is_err() {
+                        return;
+                    }
+                    notice_func(request.tile);
+                    false
+                },
+            };
+
+            if load_remote {
+                let dropped = match remote_queue.0.lock() {
+                    Ok(mut remote_queue) => {
+                        remote_queue.requests.push(request);
+                        sort_remote_queue(&mut remote_queue.requests, view_opt)
                     },
+                    // The tile is no longer pending and may be requested again.
+                    Err(_) => vec![request.tile],
+                };
+                remote_queue.1.notify_all();
+
+                for tile in dropped {
+                    if result_tx.send((tile, LoadResult::Dropped)).is_err() {
+                        return;
+                    }
                 }
             }
         }
     }
 
+    /// Downloads the tiles in the remote queue. Dedicated workers only download tiles of the
+    /// given source.
     fn work_remote<F>(
         thread_id: u32,
-        queue: &Arc<Mutex<Vec<TileRequest>>>,
-        request_rx: &Arc<Mutex<mpsc::Receiver<RemoteLoaderMessage>>>,
-        result_tx: &mpsc::Sender<(Tile, Option<DynamicImage>)>,
+        dedicated: Option<TileSourceId>,
+        queue: &Arc<(Mutex<RemoteQueue>, Condvar)>,
+        rate_limiter: &RateLimiter,
+        result_tx: &mpsc::Sender<(Tile, LoadResult)>,
         notice_func: &Arc<F>,
     )
         where F: Fn(Tile) + Sync + Send + 'static,
     {
-        let mut client_opt = None;
+        let (ref queue, ref condvar) = **queue;
+        let mut client = None;
 
         loop {
-            let message = request_rx.lock().ok().and_then(|r| r.recv().ok());
-            match message {
+            let request = {
+                let mut guard = match queue.lock() {
+                    Ok(guard) => guard,
+                    Err(_) => break,
+                };
+                loop {
+                    if guard.closed || guard.is_retired(dedicated) {
+                        break None;
+                    }
+                    if let Some(request) = guard.pop(dedicated) {
+                        break Some(request);
+                    }
+                    guard = match condvar.wait(guard) {
+                        Ok(guard) => guard,
+                        Err(_) => break None,
+                    };
+                }
+            };
+
+            let request = match request {
+                Some(request) => request,
                 None => break,
-                Some(RemoteLoaderMessage::PopQueue) => {
-                    let ele: Option<TileRequest> = queue.lock().ok().and_then(|mut q| q.pop());
+            };
 
-                    if let Some(request) = ele {
-                        if client_opt.is_none() {
-                            client_opt = Client::builder().build().ok();
-                        }
+            info!("thread {}, load {:?}", thread_id, request.tile);
 
-                        info!("thread {}, download {:?}", thread_id, request.url);
+            if let Some(host) = request.provider.remote_host(request.tile.coord) {
+                rate_limiter.wait(&host);
+            }
 
-                        if let Some(Ok(mut response)) = client_opt.as_ref().map(|c| c.get(&request.url).send()) {
-                            let mut buf: Vec<u8> = vec![];
-                            if response.copy_to(&mut buf).is_ok() {
-                                if let Ok(img) = image::load_from_memory(&buf) {
-                                    // successfully loaded tile
+            let result = match lazy_client(&mut client) {
+                Some(client) => {
+                    request.provider.load_remote(client, request.tile.coord, request.write_to_file)
+                },
+                None => Err(LoadError::Request("no HTTP client".to_string())),
+            };
 
-                                    if result_tx.send((request.tile, Some(img))).is_err() {
-                                        break;
-                                    }
+            // Allow other requests of the same source.
+            if let Ok(mut queue) = queue.lock() {
+                queue.finish(request.tile.source_id);
+            }
+            condvar.notify_all();
 
-                                    notice_func(request.tile);
+            let result = match result {
+                Ok(Some(img)) => LoadResult::Loaded(img),
+                Ok(None) => LoadResult::Unchanged,
+                Err(e) => {
+                    info!("thread {}, fail {:?}, {}", thread_id, request.tile, e);
+                    LoadResult::Failed(e)
+                },
+            };
 
-                                    if request.write_to_file {
-                                        if let Err(e) = Self::write_to_file(&request.path, &buf) {
-                                            warn!("could not write file {}, {}", request.path.display(), e);
-                                        }
-                                    }
+            let changed = !matches!(result, LoadResult::Unchanged);
 
-                                    continue;
-                                }
-                            }
-                        }
+            if result_tx.send((request.tile, result)).is_err() {
+                break;
+            }
 
-                        // failed not load tile
-                        info!("thread {}, fail {:?}", thread_id, request.url);
-                        if result_tx.send((request.tile, None)).is_err() {
-                            break;
-                        }
-                    }
-                },
+            if changed {
+                notice_func(request.tile);
             }
         }
     }
 
-    pub fn async_request(&mut self, tile_coord: TileCoord, source: &TileSource, write_to_file: bool) {
-        if tile_coord.zoom > source.max_tile_zoom() ||
-           tile_coord.zoom < source.min_tile_zoom()
+    /// Requests a visible tile. Returns true if the tile is loaded because of this call and false
+    /// if it is already pending, not part of the source or failed recently.
+    pub fn async_request(&mut self, tile_coord: TileCoord, source: &TileSource, write_to_file: bool) -> bool {
+        if !source.contains_tile(tile_coord) {
+            return false;
+        }
+
+        let tile = source.tile(tile_coord);
+
+        // The tile is visible now, so it should not wait behind other tiles.
+        if self.prefetching.remove(&tile) {
+            let _ = self.request_tx.send(LoaderMessage::Promote(tile));
+            return true;
+        }
+
+        if !self.pending.contains(&tile) &&
+            self.failures.may_request(tile, Instant::now()) &&
+            self.request_tx.send(LoaderMessage::GetTile(
+                TileRequest::new(tile, source, write_to_file, false)
+            )).is_ok()
         {
-            return;
+            self.pending.insert(tile);
+            true
+        } else {
+            false
         }
+    }
 
-        let tile = Tile::new(tile_coord, source.id());
+    /// Requests tiles of `source` that are not visible yet with a lower priority than visible
+    /// tiles. The tiles may belong to other time steps than the current one. Prefetch requests of
+    /// the same source that are still waiting and that are not part of `tiles` anymore are
+    /// cancelled, e.g. because the view has moved away. Only the first tiles are requested if the
+    /// queue of waiting requests would overflow, so `tiles` should be ordered by priority.
+    pub fn prefetch(&mut self, tiles: &[Tile], source: &TileSource, write_to_file: bool) {
+        let now = Instant::now();
+        let wanted: HashSet<Tile> = tiles.iter()
+            .cloned()
+            .filter(|tile| tile.source_id == source.id() && source.contains_tile(tile.coord))
+            .filter(|&tile| self.failures.may_request(tile, now))
+            .collect();
 
-        if !self.pending.contains(&tile) {
-            if let Some(url) = source.remote_tile_url(tile_coord) {
-                if self.request_tx.send(LoaderMessage::GetTile(
-                        TileRequest {
-                            tile,
-                            url,
-                            path: source.local_tile_path(tile_coord),
-                            write_to_file,
-                        }
-                    )).is_ok()
-                {
-                    self.pending.insert(tile);
-                }
+        let cancelled: Vec<Tile> = self.prefetching.iter()
+            .filter(|tile| tile.source_id == source.id() && !wanted.contains(tile))
+            .cloned()
+            .collect();
+        if !cancelled.is_empty() {
+            for tile in &cancelled {
+                self.prefetching.remove(tile);
+            }
+            let _ = self.request_tx.send(LoaderMessage::CancelPrefetch(cancelled));
+        }
+
+        let free = cmp::min(
+            MAX_PREFETCH_LEN.saturating_sub(self.prefetching.len()),
+            MAX_REMOTE_QUEUE_LEN.saturating_sub(self.pending.len()),
+        );
+        let new_tiles: Vec<Tile> = tiles.iter()
+            .filter(|tile| wanted.contains(tile) && !self.pending.contains(tile))
+            .cloned()
+            .collect();
+
+        for tile in new_tiles.into_iter().take(free) {
+            if !self.pending.contains(&tile) &&
+                self.request_tx.send(LoaderMessage::GetTile(
+                    TileRequest::new(tile, source, write_to_file, true)
+                )).is_ok()
+            {
+                self.pending.insert(tile);
+                self.prefetching.insert(tile);
             }
         }
     }
 
     pub fn async_result(&mut self) -> Option<(Tile, DynamicImage)> {
-        match self.result_rx.try_recv() {
-            Err(_) => None,
-            Ok((tile, None)) => {
-                self.pending.remove(&tile);
-                debug!("async_result none, pending.len: {}, {:?}", self.pending.len(), tile);
-                None
-            },
-            Ok((tile, Some(img))) => {
-                self.pending.remove(&tile);
-                debug!("async_result some, pending.len: {}, {:?}", self.pending.len(), tile);
-                Some((tile, img))
-            },
+        while let Ok((tile, result)) = self.result_rx.try_recv() {
+            self.pending.remove(&tile);
+            self.prefetching.remove(&tile);
+            match result {
+                LoadResult::Loaded(img) => {
+                    self.failures.record_success(tile);
+                    debug!("async_result some, pending.len: {}, {:?}", self.pending.len(), tile);
+                    return Some((tile, img));
+                },
+                LoadResult::Unchanged => {},
+                LoadResult::Dropped => {
+                    debug!("async_result dropped, pending.len: {}, {:?}", self.pending.len(), tile);
+                },
+                LoadResult::Failed(error) => {
+                    debug!("async_result none, pending.len: {}, {:?}", self.pending.len(), tile);
+                    self.failures.record_failure(tile, error, Instant::now());
+                },
+            }
         }
+        None
     }
 
-    pub fn get_sync(&mut self, tile: TileCoord, source: &TileSource, write_to_file: bool) -> Option<DynamicImage> {
-        if tile.zoom > source.max_tile_zoom() ||
-           tile.zoom < source.min_tile_zoom()
-        {
+    pub fn get_sync(&mut self, tile_coord: TileCoord, source: &TileSource, write_to_file: bool) -> Option<DynamicImage> {
+        if !source.contains_tile(tile_coord) {
             return None;
         }
 
-        match image::open(source.local_tile_path(tile)) {
-            Ok(img) => {
-                debug!("sync ok from path {:?}", tile);
+        let tile = source.tile(tile_coord);
+        let provider = source.provider();
+        let use_remote = self.use_network.load(AtomicOrdering::Relaxed) && provider.is_remote();
+
+        match provider.load_local(tile_coord) {
+            Some(img) => {
+                debug!("sync ok from local provider {:?}", tile_coord);
+                if use_remote &&
+                    provider.is_expired(tile_coord) &&
+                    self.failures.may_request(tile, Instant::now())
+                {
+                    // Keep the expired tile if it has not changed or the request failed.
+                    if let Some(new_img) = self.load_remote_sync(tile, provider, write_to_file) {
+                        return Some(new_img);
+                    }
+                }
                 Some(img)
             },
-            Err(_) => {
-                if self.use_network {
-                    //TODO do not try to create a client every time when it failed before
-                    if self.client.is_none() {
-                        self.client = Client::builder().build().ok();
-                    }
+            None if use_remote => {
+                if !self.failures.may_request(tile, Instant::now()) {
+                    return None;
+                }
 
-                    if let (Some(client), Some(url)) = (self.client.as_ref(), source.remote_tile_url(tile)) {
-                        if let Ok(mut response) = client.get(&url).send() {
-                            let mut buf: Vec<u8> = vec![];
-                            if response.copy_to(&mut buf).is_ok() {
-                                if let Ok(img) = image::load_from_memory(&buf) {
-                                    if write_to_file {
-                                        let path = source.local_tile_path(tile);
-                                        if let Err(e) = Self::write_to_file(&path, &buf) {
-                                            warn!("could not write file {}, {}", &path.display(), e);
-                                        }
-                                    }
-                                    debug!("sync ok from network {:?}", tile);
-                                    return Some(img);
-                                }
-                            }
-                        }
-                    }
-                    debug!("sync fail from network {:?}", tile);
-                    None
+                let img = self.load_remote_sync(tile, provider, write_to_file);
+                if img.is_some() {
+                    debug!("sync ok from network {:?}", tile_coord);
                 } else {
-                    debug!("sync fail from path {:?}", tile);
-                    None
+                    debug!("sync fail from network {:?}", tile_coord);
                 }
+                img
+            },
+            None => {
+                debug!("sync fail from local provider {:?}", tile_coord);
+                let error = if provider.is_remote() { LoadError::Offline } else { LoadError::Unavailable };
+                self.failures.record_failure(tile, error, Instant::now());
+                None
             },
         }
     }
 
+    fn load_remote_sync(&mut self, tile: Tile, provider: &Arc<dyn TileProvider>, write_to_file: bool)
+        -> Option<DynamicImage>
+    {
+        if let Some(host) = provider.remote_host(tile.coord) {
+            self.rate_limiter.wait(&host);
+        }
+
+        let result = match lazy_client(&mut self.client) {
+            Some(client) => provider.load_remote(client, tile.coord, write_to_file),
+            None => Err(LoadError::Request("no HTTP client".to_string())),
+        };
+
+        match result {
+            Ok(img) => {
+                self.failures.record_success(tile);
+                img
+            },
+            Err(error) => {
+                self.failures.record_failure(tile, error, Instant::now());
+                None
+            },
+        }
+    }
+
+    /// Returns the last failure if the most recent attempt to load `tile` failed.
+    pub fn failure(&self, tile: Tile) -> Option<&Failure> {
+        self.failures.failure(tile)
+    }
+
+    /// Returns the number of threads that download tiles, including dedicated threads.
+    pub fn download_threads(&self) -> usize {
+        self.remote_workers.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Enables or disables downloading tiles. Requests that are already waiting for a download
+    /// are not affected. Tiles that were not available are requested again after a change.
+    pub fn set_use_network(&mut self, use_network: bool) {
+        if self.use_network.swap(use_network, AtomicOrdering::Relaxed) != use_network {
+            self.failures.forget_unavailable();
+        }
+    }
+
     pub fn set_view_location(&mut self, view: View) {
         let _ = self.request_tx.send(LoaderMessage::SetView(view));
     }
 
-    fn write_to_file<P: AsRef<Path>>(path: P, img_data: &[u8]) -> ::std::io::Result<()> {
-        if let Some(dir) = path.as_ref().parent() {
-            ::std::fs::create_dir_all(dir)?;
-        }
+    /// Forgets all sources except the given ones, e.g. after the tile sources were reloaded.
+    /// Waiting requests of other sources are dropped and their dedicated download threads stop.
+    pub fn retain_sources(&mut self, ids: &[TileSourceId]) {
+        let ids = ids.iter().cloned().collect();
+        let _ = self.request_tx.send(LoaderMessage::RetainSources(ids));
+    }
+}
 
-        let mut file = File::create(path)?;
-        file.write_all(img_data)
+/// Returns the HTTP client and creates it on first use. Creating the client is not tried again if
+/// it failed.
+fn lazy_client(client: &mut Option<Option<Client>>) -> Option<&Client> {
+    if client.is_none() {
+        let new_client = Client::builder().build();
+        if let Err(ref e) = new_client {
+            error!("could not create HTTP client, {}", e);
+        }
+        *client = Some(new_client.ok());
     }
+    client.as_ref().and_then(|c| c.as_ref())
+}
+
+/// The outcome of loading a tile on one of the worker threads.
+enum LoadResult {
+    Loaded(DynamicImage),
+    /// An expired tile has not changed.
+    Unchanged,
+    Failed(LoadError),
+    /// The request was removed from the queue before it was processed.
+    Dropped,
 }
 
 #[derive(Debug)]
 struct TileRequest {
     pub tile: Tile,
-    pub url: String,
-    pub path: PathBuf,
+    pub provider: Arc<dyn TileProvider>,
+    pub limits: DownloadLimits,
     pub write_to_file: bool,
+    /// The tile is not visible yet.
+    pub prefetch: bool,
+}
+
+impl TileRequest {
+    fn new(tile: Tile, source: &TileSource, write_to_file: bool, prefetch: bool) -> Self {
+        TileRequest {
+            tile,
+            provider: Arc::clone(source.provider_at(tile.time)),
+            limits: source.download_limits(),
+            write_to_file,
+            prefetch,
+        }
+    }
+}
+
+/// Requests that wait for a local worker, sorted by priority with the most important request at
+/// the end.
+#[derive(Debug, Default)]
+struct LocalQueue {
+    requests: Vec<TileRequest>,
+    /// The most recent view, which is also used to prioritize requests that are passed on to the
+    /// remote queue
+    view: Option<View>,
+    /// Set when the loader shuts down
+    closed: bool,
+}
+
+/// Requests that wait for a remote worker and the state that is shared by all remote workers.
+#[derive(Debug, Default)]
+struct RemoteQueue {
+    /// Requests sorted by priority, the most important request is at the end.
+    requests: Vec<TileRequest>,
+    /// Number of requests that are currently downloaded for each source
+    in_flight: HashMap<TileSourceId, usize>,
+    /// Set when the loader shuts down
+    closed: bool,
+    /// Sources that are still in use or `None` if all sources are. Dedicated workers of other
+    /// sources terminate.
+    live_sources: Option<HashSet<TileSourceId>>,
+}
+
+impl RemoteQueue {
+    /// Removes and returns the request with the highest priority that a worker may download.
+    /// Workers that are dedicated to a source only take requests of that source, the other
+    /// workers only take requests of sources without dedicated workers.
+    fn pop(&mut self, dedicated: Option<TileSourceId>) -> Option<TileRequest> {
+        let index = self.requests.iter().rposition(|request| {
+            let source_id = request.tile.source_id;
+            let for_worker = match dedicated {
+                Some(id) => id == source_id,
+                None => request.limits.workers.is_none(),
+            };
+            let in_flight = self.in_flight.get(&source_id).cloned().unwrap_or(0);
+            for_worker && request.limits.max_in_flight.map(|max| in_flight < max).unwrap_or(true)
+        })?;
+
+        let request = self.requests.remove(index);
+        *self.in_flight.entry(request.tile.source_id).or_insert(0) += 1;
+        Some(request)
+    }
+
+    /// Returns true if the dedicated workers of the given source should terminate.
+    fn is_retired(&self, dedicated: Option<TileSourceId>) -> bool {
+        dedicated.map(|id| !is_live(&self.live_sources, id)).unwrap_or(false)
+    }
+
+    /// Marks a request of the given source as finished.
+    fn finish(&mut self, source_id: TileSourceId) {
+        if let Some(in_flight) = self.in_flight.get_mut(&source_id) {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
 }
 
 #[derive(Debug)]
 enum LoaderMessage {
     GetTile(TileRequest),
     SetView(View),
+    /// Remove the waiting prefetch requests of these tiles.
+    CancelPrefetch(Vec<Tile>),
+    /// A prefetched tile has become visible.
+    Promote(Tile),
+    /// Only these sources are still in use.
+    RetainSources(HashSet<TileSourceId>),
 }
 
-#[derive(Debug)]
-enum RemoteLoaderMessage {
-    PopQueue,
+/// Returns true if the source is in the set of sources that are in use. `None` stands for all
+/// sources.
+fn is_live(live_sources: &Option<HashSet<TileSourceId>>, id: TileSourceId) -> bool {
+    live_sources.as_ref().map(|live| live.contains(&id)).unwrap_or(true)
+}
+
+/// Removes the requests of sources that are not in use and returns their tiles.
+fn retain_sources(requests: &mut Vec<TileRequest>, live_sources: &Option<HashSet<TileSourceId>>) -> Vec<Tile> {
+    let mut removed = vec![];
+    requests.retain(|request| {
+        let keep = is_live(live_sources, request.tile.source_id);
+        if !keep {
+            removed.push(request.tile);
+        }
+        keep
+    });
+    removed
+}
+
+/// Removes the prefetch requests for the given tiles and returns their tiles.
+fn cancel_prefetch(requests: &mut Vec<TileRequest>, tiles: &HashSet<Tile>) -> Vec<Tile> {
+    if tiles.is_empty() {
+        return vec![];
+    }
+
+    let mut cancelled = vec![];
+    requests.retain(|request| {
+        let cancel = request.prefetch && tiles.contains(&request.tile);
+        if cancel {
+            cancelled.push(request.tile);
+        }
+        !cancel
+    });
+    cancelled
+}
+
+/// Gives the request for the given tile the priority of a visible tile.
+fn promote(requests: &mut [TileRequest], tile: Tile) {
+    if let Some(request) = requests.iter_mut().find(|request| request.tile == tile) {
+        request.prefetch = false;
+    }
+}
+
+/// Sorts the queue of remote requests by priority and removes requests that are stale for the
+/// given view or that do not fit into the queue. Returns the tiles of the removed requests.
+fn sort_remote_queue(queue: &mut Vec<TileRequest>, view_opt: Option<View>) -> Vec<Tile> {
+    let mut dropped = vec![];
+
+    if let Some(view) = view_opt {
+        queue.retain(|request| {
+            let stale = is_stale(request.tile, view);
+            if stale {
+                dropped.push(request.tile);
+            }
+            !stale
+        });
+
+        queue.as_mut_slice().sort_by(|a, b| {
+            compare_tiles(a, b, view)
+        });
+    }
+
+    // The requests with the highest priority are at the end.
+    let excess = queue.len().saturating_sub(MAX_REMOTE_QUEUE_LEN);
+    dropped.extend(queue.drain(..excess).map(|request| request.tile));
+
+    dropped
+}
+
+/// Returns true if a tile is too far away from the view to be worth loading.
+fn is_stale(tile: Tile, view: View) -> bool {
+    let zoom_diff = cmp::max(tile.coord.zoom, view.zoom) - cmp::min(tile.coord.zoom, view.zoom);
+    if zoom_diff > MAX_ZOOM_DISTANCE {
+        return true;
+    }
+
+    // Half the diagonal of the tile
+    let tile_radius = 0.5 * ::std::f64::consts::SQRT_2 * f64::powi(0.5, tile.coord.zoom as i32);
+    center_distance_sq(tile.coord, view).sqrt() > view.radius * MAX_VIEW_DISTANCE + tile_radius
+}
+
+/// Returns the squared distance between the center of a tile and the center of the view in map
+/// coordinates.
+fn center_distance_sq(tile_coord: TileCoord, view: View) -> f64 {
+    let map = tile_coord.map_coord_center();
+    let diff_x = (view.center.x - map.x).abs();
+    let diff_x = if diff_x > 0.5 { 1.0 - diff_x } else { diff_x };
+    let diff_y = view.center.y - map.y;
+    (diff_x * diff_x) + (diff_y * diff_y)
 }
 
-fn compare_tiles(a: Tile, b: Tile, view: View) -> Ordering {
+/// Orders requests by priority, the most important request is the greatest. Visible tiles come
+/// before prefetched tiles, then tiles of the source of the view, then tiles that are closer to
+/// the zoom level and the center of the view.
+fn compare_tiles(a: &TileRequest, b: &TileRequest, view: View) -> Ordering {
+    if a.prefetch != b.prefetch {
+        return if a.prefetch { Ordering::Less } else { Ordering::Greater };
+    }
+
+    let (a, b) = (a.tile, b.tile);
     let source_a = view.source_id == a.source_id;
     let source_b = view.source_id == b.source_id;
 
@@ -349,19 +861,455 @@ fn compare_tiles(a: Tile, b: Tile, view: View) -> Ordering {
             } else if zoom_diff_a > zoom_diff_b {
                 Ordering::Less
             } else {
-                let map_a = a.coord.map_coord_center();
-                let map_b = b.coord.map_coord_center();
-                let diff_xa = (view.center.x - map_a.x).abs();
-                let diff_xa = if diff_xa > 0.5 { 1.0 - diff_xa } else { diff_xa };
-                let diff_xb = (view.center.x - map_b.x).abs();
-                let diff_xb = if diff_xb > 0.5 { 1.0 - diff_xb } else { diff_xb };
-                let diff_ya = view.center.y - map_a.y;
-                let diff_yb = view.center.y - map_b.y;
-                let center_diff_a = (diff_xa * diff_xa) + (diff_ya * diff_ya);
-                let center_diff_b = (diff_xb * diff_xb) + (diff_yb * diff_yb);
+                let center_diff_a = center_distance_sq(a.coord, view);
+                let center_diff_b = center_distance_sq(b.coord, view);
 
                 center_diff_b.partial_cmp(&center_diff_a).unwrap_or(Ordering::Equal)
             }
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use coord::BoundingBox;
+    use image::RgbImage;
+    use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+    use test_server::{Response, TestServer};
+    use tile_loader::*;
+    use tile_provider::MemoryProvider;
+    use url_template::UrlTemplate;
+
+    fn test_source(server: &TestServer) -> TileSource {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("DeltaMap test"));
+        headers.insert("x-api-key", HeaderValue::from_static("secret"));
+
+        let url_template = UrlTemplate::new(server.url("/{z}/{x}/{y}.png")).unwrap();
+        TileSource::new(0, url_template, "/nonexistent", "png".to_string(), 0, 19)
+            .with_headers(headers)
+    }
+
+    #[test]
+    fn sync_request_headers() {
+        let server = TestServer::start(|_| Response::png(256));
+        let source = test_source(&server);
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+
+        assert!(loader.get_sync(TileCoord::new(2, 1, 0), &source, false).is_some());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/2/1/0.png");
+        assert_eq!(requests[0].header("user-agent"), Some("DeltaMap test"));
+        assert_eq!(requests[0].header("x-api-key"), Some("secret"));
+    }
+
+    #[test]
+    fn async_request_headers() {
+        let server = TestServer::start(|_| Response::png(256));
+        let source = test_source(&server);
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+
+        loader.async_request(TileCoord::new(3, 2, 1), &source, false);
+
+        let result = wait_for_result(&mut loader);
+        assert!(result.is_some());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/3/2/1.png");
+        assert_eq!(requests[0].header("user-agent"), Some("DeltaMap test"));
+        assert_eq!(requests[0].header("x-api-key"), Some("secret"));
+    }
+
+    #[test]
+    fn custom_provider() {
+        let provider = Arc::new(MemoryProvider::new());
+        provider.insert(TileCoord::new(2, 1, 0), DynamicImage::ImageRgb8(RgbImage::new(8, 8)));
+        let source = TileSource::from_provider(0, provider, 0, 19);
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+
+        assert!(loader.get_sync(TileCoord::new(2, 1, 0), &source, false).is_some());
+        assert!(loader.get_sync(TileCoord::new(2, 1, 1), &source, false).is_none());
+
+        loader.async_request(TileCoord::new(2, 1, 0), &source, false);
+
+        let result = wait_for_result(&mut loader);
+        assert_eq!(result.map(|(tile, _)| tile.coord), Some(TileCoord::new(2, 1, 0)));
+    }
+
+    #[test]
+    fn prefetch_time_steps() {
+        let server = TestServer::start(|_| Response::png(256));
+        let url_template = UrlTemplate::new(server.url("/{time}/{z}/{x}/{y}.png")).unwrap();
+        let source = TileSource::new(0, url_template, "/nonexistent", "png".to_string(), 0, 19)
+            .with_times(vec!["0600".to_string(), "0610".to_string()]);
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+
+        let tile = Tile::at_time(TileCoord::new(3, 2, 1), source.id(), 1);
+        loader.prefetch(&[tile], &source, false);
+
+        let result = wait_for_result(&mut loader);
+        assert_eq!(result.map(|(tile, _)| tile), Some(tile));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/0610/3/2/1.png");
+    }
+
+    #[test]
+    fn skip_tiles_outside_bounds() {
+        let server = TestServer::start(|_| Response::png(256));
+        let source = test_source(&server).with_bounds(BoundingBox::new(5.8, 47.2, 15.1, 55.1));
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+
+        assert!(loader.get_sync(TileCoord::new(4, 0, 0), &source, false).is_none());
+
+        loader.async_request(TileCoord::new(4, 15, 15), &source, false);
+        loader.async_request(TileCoord::new(4, 8, 5), &source, false);
+
+        let result = wait_for_result(&mut loader);
+        assert_eq!(result.map(|(tile, _)| tile.coord), Some(TileCoord::new(4, 8, 5)));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/4/8/5.png");
+    }
+
+    #[test]
+    fn drop_stale_requests() {
+        let provider: Arc<dyn TileProvider> = Arc::new(MemoryProvider::new());
+        let request = |zoom, x, y| TileRequest {
+            tile: Tile::new(TileCoord::new(zoom, x, y), TileSourceId::new(0)),
+            provider: Arc::clone(&provider),
+            limits: DownloadLimits::default(),
+            write_to_file: false,
+            prefetch: false,
+        };
+        let view = View {
+            source_id: TileSourceId::new(0),
+            zoom: 4,
+            center: TileCoord::new(4, 8, 8).map_coord_center(),
+            radius: 0.1,
+        };
+
+        let mut queue = vec![
+            request(4, 8, 8),
+            request(4, 0, 8),
+            request(5, 17, 17),
+            request(1, 1, 1),
+            request(4, 9, 8),
+        ];
+        let dropped = sort_remote_queue(&mut queue, Some(view));
+        let queued: Vec<_> = queue.iter().map(|r| r.tile.coord).collect();
+        assert_eq!(queued, vec![TileCoord::new(5, 17, 17), TileCoord::new(4, 9, 8), TileCoord::new(4, 8, 8)]);
+        assert_eq!(dropped.len(), 2);
+        assert!(dropped.iter().all(|t| *t == request(4, 0, 8).tile || *t == request(1, 1, 1).tile));
+
+        // Without a view only the length of the queue is limited
+        let mut queue: Vec<_> = (0..MAX_REMOTE_QUEUE_LEN as i32 + 3).map(|x| request(10, x, 0)).collect();
+        let dropped = sort_remote_queue(&mut queue, None);
+        assert_eq!(queue.len(), MAX_REMOTE_QUEUE_LEN);
+        assert_eq!(dropped, vec![request(10, 0, 0).tile, request(10, 1, 0).tile, request(10, 2, 0).tile]);
+    }
+
+    #[test]
+    fn prefetch_priority() {
+        let provider: Arc<dyn TileProvider> = Arc::new(MemoryProvider::new());
+        let request = |x, prefetch| TileRequest {
+            tile: Tile::new(TileCoord::new(4, x, 8), TileSourceId::new(0)),
+            provider: Arc::clone(&provider),
+            limits: DownloadLimits::default(),
+            write_to_file: false,
+            prefetch,
+        };
+        let view = View {
+            source_id: TileSourceId::new(0),
+            zoom: 4,
+            center: TileCoord::new(4, 8, 8).map_coord_center(),
+            radius: 0.2,
+        };
+
+        // Prefetched tiles come after all visible tiles, even if they are closer to the center.
+        let mut queue = vec![request(8, true), request(10, false), request(9, true), request(7, false)];
+        sort_remote_queue(&mut queue, Some(view));
+        let order: Vec<_> = queue.iter().rev().map(|r| (r.tile.coord.x, r.prefetch)).collect();
+        assert_eq!(order, vec![(7, false), (10, false), (8, true), (9, true)]);
+
+        let cancel: HashSet<Tile> = [request(9, true).tile, request(10, true).tile].iter().cloned().collect();
+        assert_eq!(cancel_prefetch(&mut queue, &cancel), vec![request(9, true).tile]);
+        assert_eq!(queue.len(), 3);
+
+        promote(&mut queue, request(8, true).tile);
+        sort_remote_queue(&mut queue, Some(view));
+        assert_eq!(queue.last().map(|r| (r.tile.coord.x, r.prefetch)), Some((8, false)));
+    }
+
+    #[test]
+    fn prefetch_and_promote() {
+        let provider = Arc::new(MemoryProvider::new());
+        for x in 0..4 {
+            provider.insert(TileCoord::new(3, x, 0), DynamicImage::ImageRgb8(RgbImage::new(8, 8)));
+        }
+        let source = TileSource::from_provider(0, provider, 0, 19);
+        let mut loader = TileLoader::new(|_| {}, false, &DownloadSettings::default());
+
+        let coords: Vec<_> = (0..4).map(|x| TileCoord::new(3, x, 0)).collect();
+        let tiles: Vec<_> = coords.iter().map(|&coord| source.tile(coord)).collect();
+        loader.prefetch(&tiles, &source, false);
+        // Requesting a prefetched tile does not load it twice.
+        loader.async_request(coords[0], &source, false);
+
+        let start = Instant::now();
+        let mut loaded = vec![];
+        while loaded.len() < 4 && start.elapsed() < Duration::from_secs(10) {
+            match loader.async_result() {
+                Some((tile, _)) => loaded.push(tile.coord),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        loaded.sort_by_key(|t| t.x);
+        assert_eq!(loaded, coords);
+        assert!(loader.pending.is_empty());
+        assert!(loader.prefetching.is_empty());
+
+        // Tiles that are not wanted anymore are cancelled.
+        loader.prefetch(&[source.tile(TileCoord::new(3, 5, 0))], &source, false);
+        loader.prefetch(&[], &source, false);
+        let start = Instant::now();
+        while !loader.pending.is_empty() && start.elapsed() < Duration::from_secs(10) {
+            assert!(loader.async_result().is_none());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(loader.pending.is_empty());
+    }
+
+    #[test]
+    fn prefetch_limit() {
+        let source = TileSource::from_provider(0, Arc::new(MemoryProvider::new()), 0, 19);
+        let mut loader = TileLoader::new(|_| {}, false, &DownloadSettings::default());
+
+        let tiles: Vec<_> = (0..200).map(|x| source.tile(TileCoord::new(8, x, 0))).collect();
+        loader.prefetch(&tiles, &source, false);
+        assert_eq!(loader.prefetching.len(), MAX_PREFETCH_LEN);
+        // The tiles with the highest priority come first.
+        assert!(loader.prefetching.contains(&tiles[0]));
+        assert!(!loader.prefetching.contains(&tiles[MAX_PREFETCH_LEN]));
+
+        // Requesting the same tiles again does not exceed the limit.
+        loader.prefetch(&tiles, &source, false);
+        assert!(loader.prefetching.len() <= MAX_PREFETCH_LEN);
+        assert!(loader.pending.len() <= MAX_PREFETCH_LEN);
+    }
+
+    #[test]
+    fn stop_workers_of_removed_sources() {
+        let server = TestServer::start(|_| Response::png(256));
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+        let mut source = test_source(&server).with_download_limits(DownloadLimits {
+            workers: Some(3),
+            max_in_flight: None,
+        });
+        let wait_for_workers = |loader: &TileLoader, num: usize| {
+            let start = Instant::now();
+            while loader.download_threads() != num &&
+                start.elapsed() < Duration::from_secs(10)
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(loader.download_threads(), num);
+        };
+
+        load_all(&mut loader, &source, &[TileCoord::new(2, 0, 0)]);
+        wait_for_workers(&loader, 5);
+
+        // A reloaded source gets a new id and new dedicated workers.
+        for id in 1..3 {
+            source.set_id(TileSourceId::new(id));
+            loader.retain_sources(&[source.id()]);
+            load_all(&mut loader, &source, &[TileCoord::new(2, id as i32, 0)]);
+            wait_for_workers(&loader, 5);
+        }
+    }
+
+    /// Waits up to ten seconds for the next result of an asynchronous request.
+    fn wait_for_result(loader: &mut TileLoader) -> Option<(Tile, DynamicImage)> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if let Some(result) = loader.async_result() {
+                return Some(result);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    /// Requests the given tiles and waits until all of them are loaded.
+    fn load_all(loader: &mut TileLoader, source: &TileSource, coords: &[TileCoord]) {
+        for &coord in coords {
+            loader.async_request(coord, source, false);
+        }
+
+        let start = Instant::now();
+        let mut num_loaded = 0;
+        while num_loaded < coords.len() && start.elapsed() < Duration::from_secs(20) {
+            if loader.async_result().is_some() {
+                num_loaded += 1;
+            } else {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(num_loaded, coords.len());
+    }
+
+    #[test]
+    fn max_in_flight() {
+        let current = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let current = Arc::clone(&current);
+            let max = Arc::clone(&max);
+            TestServer::start(move |_| {
+                let num = current.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                max.fetch_max(num, AtomicOrdering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                current.fetch_sub(1, AtomicOrdering::SeqCst);
+                Response::png(256)
+            })
+        };
+
+        let settings = DownloadSettings { workers: 4, ..DownloadSettings::default() };
+        let mut loader = TileLoader::new(|_| {}, true, &settings);
+        let coords: Vec<_> = (0..6).map(|x| TileCoord::new(3, x, 0)).collect();
+
+        let source = test_source(&server).with_download_limits(DownloadLimits {
+            workers: None,
+            max_in_flight: Some(2),
+        });
+        load_all(&mut loader, &source, &coords);
+        assert_eq!(server.requests().len(), 6);
+        assert_eq!(max.load(AtomicOrdering::SeqCst), 2);
+
+        // A dedicated worker downloads one tile after the other
+        max.store(0, AtomicOrdering::SeqCst);
+        let source = TileSource::new(
+            1,
+            UrlTemplate::new(server.url("/b/{z}/{x}/{y}.png")).unwrap(),
+            "/nonexistent",
+            "png".to_string(),
+            0,
+            19,
+        ).with_download_limits(DownloadLimits {
+            workers: Some(1),
+            max_in_flight: None,
+        });
+        load_all(&mut loader, &source, &coords);
+        assert_eq!(server.requests().len(), 12);
+        assert_eq!(max.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn parallel_local_loads() {
+        /// Counts how many tiles are loaded at the same time.
+        #[derive(Debug, Default)]
+        struct SlowProvider {
+            current: AtomicUsize,
+            max: AtomicUsize,
+        }
+
+        impl TileProvider for SlowProvider {
+            fn load_local(&self, _tile_coord: TileCoord) -> Option<DynamicImage> {
+                let num = self.current.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                self.max.fetch_max(num, AtomicOrdering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                self.current.fetch_sub(1, AtomicOrdering::SeqCst);
+                Some(DynamicImage::ImageRgb8(RgbImage::new(8, 8)))
+            }
+        }
+
+        let provider = Arc::new(SlowProvider::default());
+        let source = TileSource::from_provider(0, Arc::clone(&provider) as Arc<dyn TileProvider>, 0, 19);
+        let mut loader = TileLoader::new(|_| {}, false, &DownloadSettings::default());
+        let coords: Vec<_> = (0..8).map(|x| TileCoord::new(3, x, 0)).collect();
+
+        load_all(&mut loader, &source, &coords);
+        let max = provider.max.load(AtomicOrdering::SeqCst);
+        assert!(max <= num_cpus::get());
+        if num_cpus::get() > 1 {
+            assert!(max > 1);
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let server = TestServer::start(|_| Response::png(256));
+        let source = test_source(&server);
+        let mut settings = DownloadSettings { workers: 4, ..DownloadSettings::default() };
+        settings.rate_limits.insert("127.0.0.1".to_string(), 20.0);
+        let mut loader = TileLoader::new(|_| {}, true, &settings);
+
+        let coords: Vec<_> = (0..4).map(|x| TileCoord::new(3, x, 0)).collect();
+        let start = Instant::now();
+        load_all(&mut loader, &source, &coords);
+
+        // The last request is sent at least three intervals after the first one.
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests.iter().all(|r| r.time >= start));
+        assert!(requests.iter().any(|r| r.time - start >= Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn remember_failures() {
+        let server = TestServer::start(|request| {
+            match request.path.as_str() {
+                "/2/1/0.png" => Response::new(404, vec![]),
+                "/2/2/0.png" => Response::new(503, vec![]),
+                _ => Response::png(256),
+            }
+        });
+        let source = test_source(&server);
+        let mut loader = TileLoader::new(|_| {}, true, &DownloadSettings::default());
+        let not_found = Tile::new(TileCoord::new(2, 1, 0), source.id());
+        let unavailable = Tile::new(TileCoord::new(2, 2, 0), source.id());
+
+        for _ in 0..2 {
+            assert!(loader.get_sync(not_found.coord, &source, false).is_none());
+            assert!(loader.get_sync(unavailable.coord, &source, false).is_none());
+        }
+        loader.async_request(not_found.coord, &source, false);
+
+        assert_eq!(loader.failure(not_found).map(|f| f.retry_at), Some(None));
+        let failure = loader.failure(unavailable).unwrap();
+        assert_eq!(failure.error, LoadError::Status(503));
+        assert_eq!(failure.attempts, 1);
+        assert!(failure.retry_at.is_some());
+
+        // Only the first attempt of each tile reaches the server
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/2/1/0.png");
+        assert_eq!(requests[1].path, "/2/2/0.png");
+    }
+
+    #[test]
+    fn enable_network() {
+        let server = TestServer::start(|_| Response::png(256));
+        let source = test_source(&server);
+        let mut loader = TileLoader::new(|_| {}, false, &DownloadSettings::default());
+        let tile = Tile::new(TileCoord::new(2, 1, 0), source.id());
+
+        assert!(loader.get_sync(tile.coord, &source, false).is_none());
+        let failure = loader.failure(tile).unwrap();
+        assert_eq!(failure.error, LoadError::Offline);
+        assert!(failure.retry_at.is_some());
+
+        loader.set_use_network(true);
+        assert_eq!(loader.failure(tile), None);
+        assert!(loader.get_sync(tile.coord, &source, false).is_some());
+        assert_eq!(server.requests().len(), 1);
+    }
+}
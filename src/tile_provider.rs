@@ -0,0 +1,502 @@
+//! Backends that load the tiles of a `TileSource`.
+
+use coord::TileCoord;
+use http_cache::{CacheMetadata, unix_seconds};
+use image::DynamicImage;
+use image;
+use mbtiles::MbTiles;
+use reqwest::{self, Client, StatusCode, Url};
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tile_source::TileScheme;
+use url_template::UrlTemplate;
+
+
+/// A backend that loads tiles. The `TileLoader` decides when tiles are loaded and calls these
+/// methods from its worker threads.
+///
+/// Loading happens in two stages: `load_local` is supposed to be fast and is called in the order
+/// of priority. If it fails and `is_remote` returns true, `load_remote` is called later on one of
+/// the network threads.
+pub trait TileProvider: fmt::Debug + Send + Sync {
+    /// Loads a tile from a local storage.
+    fn load_local(&self, tile_coord: TileCoord) -> Option<DynamicImage>;
+
+    /// Returns true if tiles that are not available locally may be loaded with `load_remote`.
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    /// Returns true if a locally available tile is outdated and should be loaded again with
+    /// `load_remote`. The outdated tile may still be shown in the meantime.
+    fn is_expired(&self, _tile_coord: TileCoord) -> bool {
+        false
+    }
+
+    /// Returns the host name of the server that `load_remote` requests the tile from.
+    fn remote_host(&self, _tile_coord: TileCoord) -> Option<String> {
+        None
+    }
+
+    /// Loads a tile over the network. Successfully loaded tiles are written to a local storage if
+    /// `write_to_cache` is true. Returns `Ok(None)` if an expired tile has not changed.
+    fn load_remote(&self, _client: &Client, _tile_coord: TileCoord, _write_to_cache: bool)
+        -> Result<Option<DynamicImage>, LoadError>
+    {
+        Err(LoadError::Unavailable)
+    }
+}
+
+/// The reason why a tile could not be loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LoadError {
+    /// The tile is not available locally and cannot be downloaded.
+    Unavailable,
+    /// The tile is not available locally and downloading is disabled. It may be downloaded later
+    /// when the network is used again.
+    Offline,
+    /// The server responded with an unsuccessful HTTP status code.
+    Status(u16),
+    /// No response was received, e.g. because of a timeout or a connection error.
+    Request(String),
+    /// The response is not a valid image.
+    Decode(String),
+}
+
+impl LoadError {
+    /// Returns true if trying again is pointless, e.g. if the server responded with "404 Not
+    /// Found".
+    pub fn is_permanent(&self) -> bool {
+        matches!(*self, LoadError::Unavailable | LoadError::Status(404) | LoadError::Status(410))
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Unavailable => write!(f, "tile is not available"),
+            LoadError::Offline => write!(f, "tile is not available offline"),
+            LoadError::Status(status) => write!(f, "HTTP status {}", status),
+            LoadError::Request(ref e) => write!(f, "request failed, {}", e),
+            LoadError::Decode(ref e) => write!(f, "invalid image, {}", e),
+        }
+    }
+}
+
+/// Returns the URL without its query string and user information, so it can be logged or shown
+/// without revealing API keys.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            if url.query().is_some() {
+                url.set_query(Some("redacted"));
+            }
+            url.as_str().to_string()
+        },
+        Err(_) => url.split('?').next().unwrap_or_default().to_string(),
+    }
+}
+
+/// Formats a request error with a redacted URL.
+pub fn request_error(error: &reqwest::Error) -> String {
+    let message = error.to_string();
+    match error.url() {
+        Some(url) => message.replace(url.as_str(), &redact_url(url.as_str())),
+        None => message,
+    }
+}
+
+/// A directory with tile images in the layout `{z}/{x}/{y}.{extension}`.
+#[derive(Clone, Debug)]
+pub struct TileDirectory {
+    pub path: PathBuf,
+    pub extension: String,
+    /// Numbering of the `y` directory entries
+    pub scheme: TileScheme,
+    /// Resolution scale factor. File names get a suffix like "@2x" if it is greater than 1.
+    pub scale: u32,
+}
+
+impl TileDirectory {
+    /// Returns the path of the file for the given tile in XYZ numbering.
+    pub fn tile_path(&self, tile_coord: TileCoord) -> PathBuf {
+        let tile_coord = self.scheme.convert(tile_coord);
+        let mut path = PathBuf::from(&self.path);
+        path.push(tile_coord.zoom.to_string());
+        path.push(tile_coord.x.to_string());
+        match self.scale {
+            1 => path.push(format!("{}.{}", tile_coord.y, self.extension)),
+            scale => path.push(format!("{}@{}x.{}", tile_coord.y, scale, self.extension)),
+        }
+
+        path
+    }
+
+    fn read(&self, tile_coord: TileCoord) -> Option<DynamicImage> {
+        image::open(self.tile_path(tile_coord)).ok()
+    }
+
+    fn write(&self, tile_coord: TileCoord, img_data: &[u8]) -> ::std::io::Result<()> {
+        let path = self.tile_path(tile_coord);
+        if let Some(dir) = path.parent() {
+            ::std::fs::create_dir_all(dir)?;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(img_data)
+    }
+}
+
+/// Reads tiles from a local directory.
+#[derive(Debug)]
+pub struct DirectoryProvider {
+    directory: TileDirectory,
+}
+
+impl DirectoryProvider {
+    pub fn new(directory: TileDirectory) -> Self {
+        DirectoryProvider { directory }
+    }
+}
+
+impl TileProvider for DirectoryProvider {
+    fn load_local(&self, tile_coord: TileCoord) -> Option<DynamicImage> {
+        self.directory.read(tile_coord)
+    }
+}
+
+/// Downloads tiles over HTTP and stores them in a cache directory. The caching headers of each
+/// response are stored next to the tile to revalidate it when it expires.
+#[derive(Debug)]
+pub struct HttpProvider {
+    cache: TileDirectory,
+    url_template: Arc<UrlTemplate>,
+    headers: HeaderMap,
+    /// Overrides the maximum age in seconds from the responses.
+    max_age: Option<u64>,
+    /// Timestamp for the `{time}` placeholder of the template
+    time: Option<String>,
+}
+
+impl HttpProvider {
+    /// Creates a provider that requests tiles from `url_template` with the given HTTP headers.
+    /// The scheme and scale of `cache` are also used to fill the template.
+    pub fn new(cache: TileDirectory, url_template: Arc<UrlTemplate>, headers: HeaderMap) -> Self {
+        HttpProvider {
+            cache,
+            url_template,
+            headers,
+            max_age: None,
+            time: None,
+        }
+    }
+
+    /// Set the maximum age of cached tiles in seconds, regardless of the caching headers of the
+    /// responses.
+    pub fn with_max_age(mut self, max_age: Option<u64>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Set the timestamp for the `{time}` placeholder. The tiles of each time step should be
+    /// cached in a separate directory.
+    pub fn with_time(mut self, time: Option<String>) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn tile_url(&self, tile_coord: TileCoord) -> Option<String> {
+        self.url_template.fill_tile(
+            tile_coord,
+            self.cache.scheme,
+            self.cache.scale,
+            self.time.as_deref(),
+        )
+    }
+}
+
+impl TileProvider for HttpProvider {
+    fn load_local(&self, tile_coord: TileCoord) -> Option<DynamicImage> {
+        self.cache.read(tile_coord)
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    fn is_expired(&self, tile_coord: TileCoord) -> bool {
+        let path = self.cache.tile_path(tile_coord);
+        let now = SystemTime::now();
+
+        match CacheMetadata::read(&path) {
+            Some(metadata) => metadata.is_expired(now, self.max_age),
+            None => {
+                // Tiles without metadata were cached before it was stored. Use the modification
+                // time of the file instead.
+                match (self.max_age, ::std::fs::metadata(&path).and_then(|m| m.modified())) {
+                    (Some(max_age), Ok(modified)) => {
+                        let metadata = CacheMetadata {
+                            fetched: unix_seconds(modified),
+                            ..CacheMetadata::default()
+                        };
+                        metadata.is_expired(now, Some(max_age))
+                    },
+                    _ => false,
+                }
+            },
+        }
+    }
+
+    fn remote_host(&self, tile_coord: TileCoord) -> Option<String> {
+        let url = Url::parse(&self.tile_url(tile_coord)?).ok()?;
+        url.host_str().map(|host| host.to_string())
+    }
+
+    fn load_remote(&self, client: &Client, tile_coord: TileCoord, write_to_cache: bool)
+        -> Result<Option<DynamicImage>, LoadError>
+    {
+        let url = self.tile_url(tile_coord).ok_or(LoadError::Unavailable)?;
+        let path = self.cache.tile_path(tile_coord);
+
+        // Only revalidate tiles that are still in the cache.
+        let cached_metadata = if path.is_file() {
+            CacheMetadata::read(&path)
+        } else {
+            None
+        };
+
+        let mut headers = self.headers.clone();
+        if let Some(ref metadata) = cached_metadata {
+            metadata.add_conditional_headers(&mut headers);
+        }
+
+        info!("download {:?}", redact_url(&url));
+
+        let mut response = client.get(&url)
+            .headers(headers)
+            .send()
+            .map_err(|e| LoadError::Request(request_error(&e)))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("not modified {:?}", redact_url(&url));
+            if let (Some(mut metadata), true) = (cached_metadata, write_to_cache) {
+                metadata.revalidated(response.headers(), SystemTime::now());
+                if let Err(e) = metadata.write(&path) {
+                    warn!("could not write metadata of {}, {}", path.display(), e);
+                }
+            }
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(LoadError::Status(response.status().as_u16()));
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        response.copy_to(&mut buf).map_err(|e| LoadError::Request(request_error(&e)))?;
+        let img = image::load_from_memory(&buf).map_err(|e| LoadError::Decode(format!("{}", e)))?;
+
+        if write_to_cache {
+            if let Err(e) = self.cache.write(tile_coord, &buf) {
+                warn!("could not write file {}, {}", path.display(), e);
+            } else {
+                let metadata = CacheMetadata::from_headers(response.headers(), SystemTime::now());
+                if let Err(e) = metadata.write(&path) {
+                    warn!("could not write metadata of {}, {}", path.display(), e);
+                }
+            }
+        }
+
+        Ok(Some(img))
+    }
+}
+
+/// Reads tiles from an MBTiles file. The file is opened on first use.
+#[derive(Debug)]
+pub struct MbTilesProvider {
+    path: PathBuf,
+    file: Mutex<Option<MbTiles>>,
+}
+
+impl MbTilesProvider {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        MbTilesProvider {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+}
+
+impl TileProvider for MbTilesProvider {
+    fn load_local(&self, tile_coord: TileCoord) -> Option<DynamicImage> {
+        let mut file = self.file.lock().ok()?;
+
+        if file.is_none() {
+            match MbTiles::open(&self.path) {
+                Ok(mbtiles) => *file = Some(mbtiles),
+                Err(e) => {
+                    warn!("{}", e);
+                    return None;
+                },
+            }
+        }
+
+        match file.as_ref()?.read_tile(tile_coord) {
+            Ok(Some(data)) => image::load_from_memory(&data).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("{}", e);
+                None
+            },
+        }
+    }
+}
+
+/// Keeps tiles in memory, e.g. for generated tiles or test fixtures.
+#[derive(Default)]
+pub struct MemoryProvider {
+    tiles: RwLock<HashMap<TileCoord, DynamicImage>>,
+}
+
+impl MemoryProvider {
+    pub fn new() -> Self {
+        MemoryProvider::default()
+    }
+
+    pub fn insert(&self, tile_coord: TileCoord, img: DynamicImage) {
+        if let Ok(mut tiles) = self.tiles.write() {
+            tiles.insert(tile_coord, img);
+        }
+    }
+}
+
+impl fmt::Debug for MemoryProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.tiles.read().map(|t| t.len()).unwrap_or(0);
+        write!(f, "MemoryProvider {{ {} tiles }}", len)
+    }
+}
+
+impl TileProvider for MemoryProvider {
+    fn load_local(&self, tile_coord: TileCoord) -> Option<DynamicImage> {
+        self.tiles.read().ok()?.get(&tile_coord).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+    use std::path::Path;
+    use test_server::{png_data, Response, TestServer};
+    use test_util::TempDir;
+    use tile_provider::*;
+
+    fn directory(path: &Path, scheme: TileScheme) -> TileDirectory {
+        TileDirectory {
+            path: path.to_path_buf(),
+            extension: "png".to_string(),
+            scheme,
+            scale: 1,
+        }
+    }
+
+    #[test]
+    fn redact() {
+        assert_eq!(
+            redact_url("https://user:pw@tiles.example.com/1/2/3.png?key=secret&v=2"),
+            "https://tiles.example.com/1/2/3.png?redacted",
+        );
+        assert_eq!(redact_url("https://tiles.example.com/1/2/3.png"), "https://tiles.example.com/1/2/3.png");
+        assert_eq!(redact_url("not a url?key=secret"), "not a url");
+    }
+
+    #[test]
+    fn directory_provider() {
+        let temp_dir = TempDir::new("deltamap_test_directory_provider");
+        let dir = directory(&temp_dir, TileScheme::Tms);
+        dir.write(TileCoord::new(2, 1, 0), &png_data(16)).unwrap();
+        assert!(dir.tile_path(TileCoord::new(2, 1, 0)).ends_with("2/1/3.png"));
+
+        let provider = DirectoryProvider::new(dir);
+        assert!(!provider.is_remote());
+        assert!(provider.load_local(TileCoord::new(2, 1, 0)).is_some());
+        assert!(provider.load_local(TileCoord::new(2, 1, 3)).is_none());
+    }
+
+    #[test]
+    fn memory_provider() {
+        let provider = MemoryProvider::new();
+        provider.insert(TileCoord::new(1, 0, 0), DynamicImage::ImageRgb8(RgbImage::new(8, 8)));
+        assert!(provider.load_local(TileCoord::new(1, 0, 0)).is_some());
+        assert!(provider.load_local(TileCoord::new(1, 1, 0)).is_none());
+    }
+
+    #[test]
+    fn mbtiles_provider() {
+        let png = png_data(16);
+        let dir = TempDir::new("deltamap_test_mbtiles_provider");
+        let path = ::mbtiles::tests::create_mbtiles(
+            &dir,
+            "mbtiles_provider.mbtiles",
+            &[],
+            &[(TileCoord::new(2, 1, 0), &png)],
+        );
+        let provider = MbTilesProvider::new(path);
+        assert!(provider.load_local(TileCoord::new(2, 1, 0)).is_some());
+        assert!(provider.load_local(TileCoord::new(2, 1, 1)).is_none());
+    }
+
+    #[test]
+    fn http_revalidation() {
+        let server = TestServer::start(|request| {
+            if request.header("if-none-match") == Some("\"v1\"") {
+                Response::new(304, vec![])
+            } else {
+                Response::png(16)
+                    .with_header("ETag", "\"v1\"")
+                    .with_header("Cache-Control", "max-age=3600")
+            }
+        });
+        let temp_dir = TempDir::new("deltamap_test_http_revalidation");
+        let dir = directory(&temp_dir, TileScheme::Xyz);
+        let tile_path = dir.tile_path(TileCoord::new(1, 0, 0));
+        let url_template = Arc::new(UrlTemplate::new(server.url("/{z}/{x}/{y}.png")).unwrap());
+        let provider = HttpProvider::new(dir, url_template, HeaderMap::new());
+        let client = Client::new();
+
+        assert!(provider.load_remote(&client, TileCoord::new(1, 0, 0), true).unwrap().is_some());
+        assert!(provider.load_local(TileCoord::new(1, 0, 0)).is_some());
+        assert!(!provider.is_expired(TileCoord::new(1, 0, 0)));
+
+        // Pretend that the tile was fetched a long time ago
+        let mut metadata = CacheMetadata::read(&tile_path).unwrap();
+        metadata.fetched = 0;
+        metadata.write(&tile_path).unwrap();
+        assert!(provider.is_expired(TileCoord::new(1, 0, 0)));
+
+        // The tile has not changed
+        assert_eq!(provider.load_remote(&client, TileCoord::new(1, 0, 0), true).map(|img| img.is_some()), Ok(false));
+        assert!(!provider.is_expired(TileCoord::new(1, 0, 0)));
+        assert!(provider.load_local(TileCoord::new(1, 0, 0)).is_some());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("if-none-match"), None);
+        assert_eq!(requests[1].header("if-none-match"), Some("\"v1\""));
+
+        // A source-specific maximum age overrides the response headers
+        let provider = provider.with_max_age(Some(0));
+        let mut metadata = CacheMetadata::read(&tile_path).unwrap();
+        metadata.fetched -= 1;
+        metadata.write(&tile_path).unwrap();
+        assert!(provider.is_expired(TileCoord::new(1, 0, 0)));
+    }
+}
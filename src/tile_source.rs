@@ -1,16 +1,94 @@
-use coord::TileCoord;
-use std::path::PathBuf;
+use coord::{BoundingBox, TileCoord};
+use reqwest::header::HeaderMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tile::Tile;
+use tile_provider::{DirectoryProvider, HttpProvider, MbTilesProvider, TileDirectory, TileProvider};
 use url_template::UrlTemplate;
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TileSource {
     id: u32,
-    url_template: UrlTemplate,
+    origin: TileOrigin,
+    /// Loads the tiles, one provider for each time step. They are rebuilt whenever a setting
+    /// changes that affects loading.
+    providers: Vec<Arc<dyn TileProvider>>,
+    /// Timestamps for the `{time}` placeholder. Empty if the source has no time dimension.
+    times: Arc<Vec<String>>,
+    /// Index of the time step that is shown
+    time_index: u32,
+    scheme: TileScheme,
+    /// Edge length of a tile in pixels at a resolution scale of 1.
+    tile_size: u32,
+    /// Resolution scale factor for templates with a `{r}` placeholder.
+    resolution_scale: u32,
+    /// Additional HTTP headers for tile requests.
+    headers: HeaderMap,
+    /// Maximum age of cached tiles in seconds. Overrides the caching headers of the responses.
+    max_age: Option<u64>,
     directory: PathBuf,
     extension: String,
     min_zoom: u32,
     max_zoom: u32,
+    /// Area that is covered by the tiles of this source.
+    bounds: Option<BoundingBox>,
+    /// Default settings if this source is drawn on top of a base map.
+    overlay: Option<OverlaySettings>,
+    info: SourceInfo,
+    download_limits: DownloadLimits,
+    /// Maximum size of the downloaded tiles on disk in bytes
+    max_cache_size: Option<u64>,
+}
+
+/// Descriptive information about a tile source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceInfo {
+    /// Text that has to be shown alongside the tiles, e.g. "© OpenStreetMap contributors"
+    pub attribution: Option<String>,
+    pub description: Option<String>,
+    pub license: Option<String>,
+}
+
+/// Restrictions for downloading the tiles of a source.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DownloadLimits {
+    /// Number of threads that only download tiles of this source. If `None`, the threads that are
+    /// shared with other sources are used.
+    pub workers: Option<usize>,
+    /// Maximum number of concurrent requests
+    pub max_in_flight: Option<usize>,
+}
+
+/// How an overlay source is drawn on top of the base map.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OverlaySettings {
+    /// Opacity in the interval [0.0, 1.0]
+    pub opacity: f32,
+    pub visible: bool,
+}
+
+impl Default for OverlaySettings {
+    fn default() -> Self {
+        OverlaySettings {
+            opacity: 1.0,
+            visible: true,
+        }
+    }
+}
+
+/// Where the tiles of a `TileSource` come from.
+#[derive(Clone, Debug)]
+enum TileOrigin {
+    /// Tiles are downloaded and stored in the tile directory.
+    Remote(Arc<UrlTemplate>),
+    /// Tiles are read from the tile directory.
+    Directory,
+    /// Tiles are read from an MBTiles file.
+    MbTiles(PathBuf),
+    /// Tiles are loaded by a custom provider.
+    Custom(Arc<dyn TileProvider>),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -18,23 +96,233 @@ pub struct TileSourceId {
     id: u32,
 }
 
+impl TileSourceId {
+    pub fn new(id: u32) -> Self {
+        TileSourceId { id }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Numbering of tile rows of a `TileSource`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TileScheme {
+    /// The y coordinate counts from the north (used by OSM, Google and most others)
+    Xyz,
+    /// The y coordinate counts from the south (Tile Map Service)
+    Tms,
+}
+
+impl TileScheme {
+    pub fn to_str(&self) -> &str {
+        match *self {
+            TileScheme::Xyz => "xyz",
+            TileScheme::Tms => "tms",
+        }
+    }
+
+    /// Converts a `TileCoord` in XYZ numbering to the numbering of this scheme.
+    pub fn convert(&self, tile_coord: TileCoord) -> TileCoord {
+        match *self {
+            TileScheme::Xyz => tile_coord,
+            TileScheme::Tms => tile_coord.flip_y(),
+        }
+    }
+}
+
+impl FromStr for TileScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "xyz" => Ok(TileScheme::Xyz),
+            "tms" => Ok(TileScheme::Tms),
+            _ => Err(()),
+        }
+    }
+}
+
 impl TileSource {
-    pub fn new<S: Into<String>, P: Into<PathBuf>>(
+    /// Creates a source that downloads tiles and caches them in `directory`.
+    pub fn new<P: Into<PathBuf>>(
         id: u32,
-        url_template: S,
+        url_template: UrlTemplate,
         directory: P,
         extension: String,
         min_zoom: u32,
         max_zoom: u32,
-    ) -> Result<Self, String> {
-        Ok(TileSource {
+    ) -> Self {
+        let origin = TileOrigin::Remote(Arc::new(url_template));
+        Self::from_origin(id, origin, directory.into(), extension, min_zoom, max_zoom)
+    }
+
+    /// Creates a source that only reads tiles from `directory`.
+    pub fn from_directory<P: Into<PathBuf>>(
+        id: u32,
+        directory: P,
+        extension: String,
+        min_zoom: u32,
+        max_zoom: u32,
+    ) -> Self {
+        Self::from_origin(id, TileOrigin::Directory, directory.into(), extension, min_zoom, max_zoom)
+    }
+
+    /// Creates a source that reads tiles from the MBTiles file at `path`.
+    pub fn from_mbtiles<P: Into<PathBuf>>(id: u32, path: P, min_zoom: u32, max_zoom: u32) -> Self {
+        let origin = TileOrigin::MbTiles(path.into());
+        Self::from_origin(id, origin, PathBuf::new(), String::new(), min_zoom, max_zoom)
+    }
+
+    /// Creates a source that loads tiles with the given provider.
+    pub fn from_provider(id: u32, provider: Arc<dyn TileProvider>, min_zoom: u32, max_zoom: u32) -> Self {
+        let origin = TileOrigin::Custom(provider);
+        Self::from_origin(id, origin, PathBuf::new(), String::new(), min_zoom, max_zoom)
+    }
+
+    fn from_origin(
+        id: u32,
+        origin: TileOrigin,
+        directory: PathBuf,
+        extension: String,
+        min_zoom: u32,
+        max_zoom: u32,
+    ) -> Self {
+        let tile_directory = TileDirectory {
+            path: directory.clone(),
+            extension: extension.clone(),
+            scheme: TileScheme::Xyz,
+            scale: 1,
+        };
+        let headers = HeaderMap::new();
+        let provider = build_provider(&origin, tile_directory, &headers, None, None);
+
+        TileSource {
             id,
-            url_template: UrlTemplate::new(url_template)?,
-            directory: directory.into(),
+            origin,
+            providers: vec![provider],
+            times: Arc::new(vec![]),
+            time_index: 0,
+            scheme: TileScheme::Xyz,
+            tile_size: 256,
+            resolution_scale: 1,
+            headers,
+            max_age: None,
+            directory,
             extension,
             min_zoom,
             max_zoom,
-        })
+            bounds: None,
+            overlay: None,
+            info: SourceInfo::default(),
+            download_limits: DownloadLimits::default(),
+            max_cache_size: None,
+        }
+    }
+
+    fn rebuild_provider(&mut self) {
+        self.providers = if self.times.is_empty() {
+            vec![build_provider(&self.origin, self.tile_directory_at(None), &self.headers, self.max_age, None)]
+        } else {
+            self.times.iter()
+                .map(|time| {
+                    let tile_directory = self.tile_directory_at(Some(time));
+                    build_provider(&self.origin, tile_directory, &self.headers, self.max_age, Some(time))
+                })
+                .collect()
+        };
+    }
+
+    /// Set the numbering of tile rows. The default is `TileScheme::Xyz`.
+    pub fn with_scheme(mut self, scheme: TileScheme) -> Self {
+        self.scheme = scheme;
+        self.rebuild_provider();
+        self
+    }
+
+    /// Set the edge length of tiles in pixels. The default is 256.
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    /// Set HTTP headers that are sent with each tile request.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self.rebuild_provider();
+        self
+    }
+
+    /// Set the maximum age of cached tiles in seconds. Expired tiles are revalidated. By default,
+    /// the caching headers of the tile server are used.
+    pub fn with_max_age(mut self, max_age: u64) -> Self {
+        self.max_age = Some(max_age);
+        self.rebuild_provider();
+        self
+    }
+
+    /// Set the timestamps for the `{time}` placeholder of the URL template. The tiles of each time
+    /// step are cached in a separate subdirectory. The first time step is shown initially.
+    pub fn with_times(mut self, times: Vec<String>) -> Self {
+        self.times = Arc::new(times);
+        self.time_index = 0;
+        self.rebuild_provider();
+        self
+    }
+
+    /// Restrict tile requests to the given area.
+    pub fn with_bounds(mut self, bounds: BoundingBox) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// Mark this source as an overlay that is drawn on top of a base map.
+    pub fn with_overlay(mut self, settings: OverlaySettings) -> Self {
+        self.overlay = Some(settings);
+        self
+    }
+
+    /// Set the attribution, description and license of this source.
+    pub fn with_info(mut self, info: SourceInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Set the number of dedicated download threads and the maximum number of concurrent
+    /// requests.
+    pub fn with_download_limits(mut self, limits: DownloadLimits) -> Self {
+        self.download_limits = limits;
+        self
+    }
+
+    /// Limit the size of the downloaded tiles on disk. The least recently accessed tiles are
+    /// removed first.
+    pub fn with_max_cache_size(mut self, max_cache_size: u64) -> Self {
+        self.max_cache_size = Some(max_cache_size);
+        self
+    }
+
+    /// Choose the resolution of requested tiles for the given DPI factor. This only has an effect
+    /// if the URL template contains a `{r}` placeholder.
+    pub fn set_dpi_factor(&mut self, dpi_factor: f64) {
+        self.resolution_scale = if dpi_factor >= 1.5 { 2 } else { 1 };
+        self.rebuild_provider();
+    }
+
+    /// Returns the effective resolution scale factor.
+    fn scale(&self) -> u32 {
+        match self.origin {
+            TileOrigin::Remote(ref url_template) if url_template.has_resolution_placeholder() => {
+                self.resolution_scale
+            },
+            _ => 1,
+        }
+    }
+
+    /// Returns the edge length of tile images in pixels.
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size * self.scale()
     }
 
     pub fn id(&self) -> TileSourceId {
@@ -43,17 +331,186 @@ impl TileSource {
         }
     }
 
+    /// Replaces the id, e.g. to keep the loaded tiles of an unchanged source after reloading the
+    /// configuration.
+    pub fn set_id(&mut self, id: TileSourceId) {
+        self.id = id.id;
+    }
+
+    /// Returns true if both sources load the same tiles in the same way, so loaded tiles and
+    /// download state can be shared between them. Settings that only restrict which tiles are
+    /// drawn, like the zoom range or the bounds, are not compared.
+    pub fn loads_same_tiles(&self, other: &TileSource) -> bool {
+        let same_origin = match (&self.origin, &other.origin) {
+            (TileOrigin::Remote(a), TileOrigin::Remote(b)) => a == b,
+            (TileOrigin::Directory, TileOrigin::Directory) => true,
+            (TileOrigin::MbTiles(a), TileOrigin::MbTiles(b)) => a == b,
+            (TileOrigin::Custom(a), TileOrigin::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        };
+
+        same_origin &&
+        self.scheme == other.scheme &&
+        self.tile_size() == other.tile_size() &&
+        self.headers == other.headers &&
+        self.max_age == other.max_age &&
+        self.directory == other.directory &&
+        self.extension == other.extension &&
+        self.download_limits == other.download_limits &&
+        self.times == other.times
+    }
+
+    /// Returns the provider for the current time step.
+    pub fn provider(&self) -> &Arc<dyn TileProvider> {
+        self.provider_at(self.time_index)
+    }
+
+    /// Returns the provider for the given time step. Sources without a time dimension have a
+    /// single provider for all time steps.
+    pub fn provider_at(&self, time: u32) -> &Arc<dyn TileProvider> {
+        self.providers.get(time as usize).unwrap_or(&self.providers[0])
+    }
+
+    /// Returns the timestamps of all time steps. The list is empty if this source has no time
+    /// dimension.
+    pub fn times(&self) -> &[String] {
+        &self.times
+    }
+
+    /// Returns the index of the time step that is shown.
+    pub fn time_index(&self) -> u32 {
+        self.time_index
+    }
+
+    /// Selects the time step that is shown. The index is limited to the last time step.
+    pub fn set_time_index(&mut self, time_index: u32) {
+        let last = self.times.len().saturating_sub(1) as u32;
+        self.time_index = time_index.min(last);
+    }
+
+    /// Returns the timestamp of the current time step or `None` if this source has no time
+    /// dimension.
+    pub fn current_time(&self) -> Option<&str> {
+        self.times.get(self.time_index as usize).map(|t| t.as_str())
+    }
+
+    /// Returns the tile at the given coordinates in the current time step.
+    pub fn tile(&self, tile_coord: TileCoord) -> Tile {
+        Tile::at_time(tile_coord, self.id(), self.time_index)
+    }
+
+    /// Returns the tile directory for the given timestamp. Each time step has its own
+    /// subdirectory.
+    fn tile_directory_at(&self, time: Option<&str>) -> TileDirectory {
+        let path = match time {
+            Some(time) => self.directory.join(time_directory_name(time)),
+            None => self.directory.clone(),
+        };
+        TileDirectory {
+            path,
+            extension: self.extension.clone(),
+            scheme: self.scheme,
+            scale: self.scale(),
+        }
+    }
+
+    /// Returns the path of a tile in the tile directory. The directory layout uses the numbering
+    /// of the source's scheme. Sources without a tile directory return a relative path.
     pub fn local_tile_path(&self, tile_coord: TileCoord) -> PathBuf {
-        let mut path = PathBuf::from(&self.directory);
-        path.push(tile_coord.zoom.to_string());
-        path.push(tile_coord.x.to_string());
-        path.push(tile_coord.y.to_string() + "." + &self.extension);
+        self.local_tile_path_at(tile_coord, self.time_index)
+    }
 
-        path
+    /// Returns the path of a tile of the given time step in the tile directory.
+    pub fn local_tile_path_at(&self, tile_coord: TileCoord, time: u32) -> PathBuf {
+        self.tile_directory_at(self.times.get(time as usize).map(|t| t.as_str())).tile_path(tile_coord)
     }
 
+    /// Returns the URL of a tile in the current time step. The `{y}` placeholder is filled with the
+    /// numbering of the source's scheme and `{-y}` always with the TMS numbering.
+    /// Returns `None` for sources that are not downloaded.
     pub fn remote_tile_url(&self, tile_coord: TileCoord) -> Option<String> {
-        self.url_template.fill(tile_coord)
+        match self.origin {
+            TileOrigin::Remote(ref url_template) => {
+                url_template.fill_tile(tile_coord, self.scheme, self.scale(), self.current_time())
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the path of the MBTiles file if this source reads tiles from one.
+    pub fn mbtiles_path(&self) -> Option<&Path> {
+        match self.origin {
+            TileOrigin::MbTiles(ref path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Returns the directory of downloaded tiles or `None` for sources that are not downloaded.
+    pub fn cache_directory(&self) -> Option<&Path> {
+        match self.origin {
+            TileOrigin::Remote(_) => Some(&self.directory),
+            _ => None,
+        }
+    }
+
+    /// Returns the directories that contain downloaded tiles directly in the `{z}/{x}/{y}`
+    /// layout, one for each time step. Sources that are not downloaded have none.
+    pub fn cache_tile_directories(&self) -> Vec<PathBuf> {
+        match self.origin {
+            TileOrigin::Remote(_) if self.times.is_empty() => vec![self.directory.clone()],
+            TileOrigin::Remote(_) => {
+                self.times.iter().map(|t| self.tile_directory_at(Some(t)).path).collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        self.bounds
+    }
+
+    /// Returns the default overlay settings or `None` if this source is a base map.
+    pub fn overlay(&self) -> Option<OverlaySettings> {
+        self.overlay
+    }
+
+    pub fn info(&self) -> &SourceInfo {
+        &self.info
+    }
+
+    pub fn download_limits(&self) -> DownloadLimits {
+        self.download_limits
+    }
+
+    pub fn max_cache_size(&self) -> Option<u64> {
+        self.max_cache_size
+    }
+
+    /// Returns true if the given tile is in the zoom range and overlaps with the bounds of this
+    /// source.
+    pub fn contains_tile(&self, tile_coord: TileCoord) -> bool {
+        tile_coord.zoom >= self.min_zoom &&
+        tile_coord.zoom <= self.max_zoom &&
+        self.intersects_bounds(tile_coord)
+    }
+
+    /// Returns true if the given tile overlaps with the bounds of this source or if the source has
+    /// no bounds.
+    pub fn intersects_bounds(&self, tile_coord: TileCoord) -> bool {
+        self.bounds.map(|b| b.intersects_tile(tile_coord)).unwrap_or(true)
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn scheme(&self) -> TileScheme {
+        self.scheme
+    }
+
+    /// Returns the file extension of tiles in the tile directory.
+    pub fn extension(&self) -> &str {
+        &self.extension
     }
 
     pub fn min_tile_zoom(&self) -> u32 {
@@ -64,3 +521,135 @@ impl TileSource {
         self.max_zoom
     }
 }
+
+fn build_provider(
+    origin: &TileOrigin,
+    tile_directory: TileDirectory,
+    headers: &HeaderMap,
+    max_age: Option<u64>,
+    time: Option<&str>,
+) -> Arc<dyn TileProvider> {
+    match *origin {
+        TileOrigin::Remote(ref url_template) => {
+            Arc::new(
+                HttpProvider::new(tile_directory, Arc::clone(url_template), headers.clone())
+                    .with_max_age(max_age)
+                    .with_time(time.map(|t| t.to_string()))
+            )
+        },
+        TileOrigin::Directory => Arc::new(DirectoryProvider::new(tile_directory)),
+        TileOrigin::MbTiles(ref path) => Arc::new(MbTilesProvider::new(path.clone())),
+        TileOrigin::Custom(ref provider) => Arc::clone(provider),
+    }
+}
+
+/// Returns the name of the subdirectory for the tiles of a time step. Characters other than ASCII
+/// letters, digits, `-` and `_`, like the colons of ISO 8601 timestamps, are percent-encoded, so
+/// that distinct timestamps never share a directory.
+fn time_directory_name(time: &str) -> String {
+    let mut name = String::with_capacity(time.len());
+    for byte in time.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use tile_source::*;
+
+    fn source(url_template: &str, scheme: TileScheme) -> TileSource {
+        let url_template = UrlTemplate::new(url_template).unwrap();
+        TileSource::new(0, url_template, "cache", "png".to_string(), 0, 19).with_scheme(scheme)
+    }
+
+    #[test]
+    fn tms_scheme() {
+        let xyz = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Xyz);
+        let tms = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Tms);
+        let coord = TileCoord::new(2, 1, 0);
+
+        assert_eq!(xyz.remote_tile_url(coord), Some("https://example.com/2/1/0.png".to_string()));
+        assert_eq!(tms.remote_tile_url(coord), Some("https://example.com/2/1/3.png".to_string()));
+        assert_eq!(xyz.local_tile_path(coord), PathBuf::from("cache/2/1/0.png"));
+        assert_eq!(tms.local_tile_path(coord), PathBuf::from("cache/2/1/3.png"));
+
+        // Only `{y}` depends on the scheme, the other placeholders always refer to the same tile.
+        let tms_placeholder = source("https://example.com/{z}/{x}/{-y}.png", TileScheme::Tms);
+        assert_eq!(tms_placeholder.remote_tile_url(coord), Some("https://example.com/2/1/3.png".to_string()));
+        let quadkey = source("https://example.com/{quadkey}.png", TileScheme::Tms);
+        assert_eq!(quadkey.remote_tile_url(coord), Some("https://example.com/01.png".to_string()));
+    }
+
+    #[test]
+    fn same_tiles() {
+        let osm = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Xyz);
+        let mut other_id = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Xyz)
+            .with_bounds(BoundingBox::new(0.0, 0.0, 10.0, 10.0));
+        other_id.set_id(TileSourceId::new(7));
+        assert!(osm.loads_same_tiles(&other_id));
+        assert_eq!(other_id.id(), TileSourceId::new(7));
+
+        let tms = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Tms);
+        let other_url = source("https://example.org/{z}/{x}/{y}.png", TileScheme::Xyz);
+        let larger = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Xyz).with_tile_size(512);
+        for changed in &[tms, other_url, larger] {
+            assert!(!osm.loads_same_tiles(changed));
+        }
+    }
+
+    #[test]
+    fn time_steps() {
+        let times = vec!["2021-06-01T12:00:00Z".to_string(), "2021-06-01T12:10:00Z".to_string()];
+        let mut radar = source("https://example.com/{time}/{z}/{x}/{y}.png", TileScheme::Xyz)
+            .with_times(times.clone());
+        let coord = TileCoord::new(2, 1, 0);
+        assert_eq!(radar.current_time(), Some("2021-06-01T12:00:00Z"));
+        assert_eq!(radar.tile(coord).time, 0);
+
+        radar.set_time_index(5);
+        assert_eq!(radar.time_index(), 1);
+        assert_eq!(radar.tile(coord), Tile::at_time(coord, radar.id(), 1));
+        assert_eq!(radar.remote_tile_url(coord), Some("https://example.com/2021-06-01T12:10:00Z/2/1/0.png".to_string()));
+        assert_eq!(radar.local_tile_path(coord), PathBuf::from("cache/2021-06-01T12%3A10%3A00Z/2/1/0.png"));
+        assert!(radar.provider().is_remote());
+
+        let same = source("https://example.com/{time}/{z}/{x}/{y}.png", TileScheme::Xyz).with_times(times);
+        assert!(radar.loads_same_tiles(&same));
+        let fewer = source("https://example.com/{time}/{z}/{x}/{y}.png", TileScheme::Xyz)
+            .with_times(vec!["2021-06-01T12:00:00Z".to_string()]);
+        assert!(!radar.loads_same_tiles(&fewer));
+    }
+
+    #[test]
+    fn time_directory_names() {
+        assert_eq!(time_directory_name("2021-06-01T12:00:00Z"), "2021-06-01T12%3A00%3A00Z");
+        assert_eq!(time_directory_name("2021-06-01T12_00_00Z"), "2021-06-01T12_00_00Z");
+        assert_eq!(time_directory_name("12%3A00"), "12%253A00");
+        assert_eq!(time_directory_name(".."), "%2E%2E");
+        assert_eq!(time_directory_name("a/b"), "a%2Fb");
+    }
+
+    #[test]
+    fn resolution() {
+        let mut hidpi = source("https://example.com/{z}/{x}/{y}{r}.png", TileScheme::Xyz);
+        let coord = TileCoord::new(2, 1, 0);
+        assert_eq!(hidpi.tile_size(), 256);
+        assert_eq!(hidpi.remote_tile_url(coord), Some("https://example.com/2/1/0.png".to_string()));
+
+        hidpi.set_dpi_factor(2.0);
+        assert_eq!(hidpi.tile_size(), 512);
+        assert_eq!(hidpi.remote_tile_url(coord), Some("https://example.com/2/1/0@2x.png".to_string()));
+        assert_eq!(hidpi.local_tile_path(coord), PathBuf::from("cache/2/1/0@2x.png"));
+
+        let mut large = source("https://example.com/{z}/{x}/{y}.png", TileScheme::Xyz)
+            .with_tile_size(512);
+        large.set_dpi_factor(2.0);
+        assert_eq!(large.tile_size(), 512);
+        assert_eq!(large.local_tile_path(coord), PathBuf::from("cache/2/1/0.png"));
+    }
+}
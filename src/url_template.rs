@@ -1,5 +1,9 @@
-use coord::TileCoord;
+use coord::{LatLonRad, TileCoord};
 use regex::Regex;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use tile_source::TileScheme;
+use url::form_urlencoded;
 
 
 /// Kinds of placeholders for a `UrlTemplate`
@@ -9,24 +13,113 @@ enum Placeholder {
     X,
     /// Tile y coordinate
     Y,
+    /// Tile y coordinate with a flipped y-axis (TMS numbering)
+    YFlipped,
     /// Tile zoom
     Z,
     /// Quadkey encoded coord
-    Quadkey
+    Quadkey,
+    /// Subdomain that is chosen from a list of subdomains
+    Subdomain,
+    /// Resolution suffix for high-DPI tiles, e.g. "@2x"
+    Resolution,
+    /// Bounding box of the tile in the coordinate reference system of the template
+    BBox,
+    /// Timestamp of a time step of a source with a time dimension
+    Time,
 }
 
 impl Placeholder {
     /// Returns maximum number of bytes that the value for a placeholder will occupy.
     fn max_size(&self) -> usize {
         match *self {
-            Placeholder::X | Placeholder::Y | Placeholder::Z => 11,
+            Placeholder::X | Placeholder::Y | Placeholder::YFlipped | Placeholder::Z => 11,
             Placeholder::Quadkey => 30,
+            Placeholder::Resolution => 4,
+            Placeholder::BBox => 4 * 24 + 3,
+            // The actual size is accounted for in `UrlTemplate::with_subdomains`.
+            Placeholder::Subdomain => 0,
+            // The actual size is accounted for in `UrlTemplate::fill_tile`.
+            Placeholder::Time => 0,
         }
     }
 }
 
+/// Coordinate reference systems for the `{bbox}` placeholder.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum BBoxCrs {
+    /// Web Mercator in meters, axis order is x, y (`minx,miny,maxx,maxy`)
+    Epsg3857,
+    /// WGS 84 in degrees, axis order is latitude, longitude (`minlat,minlon,maxlat,maxlon`) as
+    /// required by WMS 1.3.0. Tiles are stretched to fit into the Mercator tile grid.
+    Epsg4326,
+}
+
+impl BBoxCrs {
+    pub fn to_str(&self) -> &str {
+        match *self {
+            BBoxCrs::Epsg3857 => "EPSG:3857",
+            BBoxCrs::Epsg4326 => "EPSG:4326",
+        }
+    }
+
+    /// Returns the bounding box of the given tile in the axis order of this CRS.
+    pub fn bbox(&self, tile_coord: TileCoord) -> [f64; 4] {
+        let nw = tile_coord.latlon_rad_north_west();
+        let se = tile_coord.latlon_rad_south_east();
+
+        match *self {
+            BBoxCrs::Epsg3857 => {
+                let (min_x, max_y) = web_mercator_meters(nw);
+                let (max_x, min_y) = web_mercator_meters(se);
+                [min_x, min_y, max_x, max_y]
+            },
+            BBoxCrs::Epsg4326 => {
+                let nw = nw.to_degrees();
+                let se = se.to_degrees();
+                [se.lat, nw.lon, nw.lat, se.lon]
+            },
+        }
+    }
+}
+
+impl FromStr for BBoxCrs {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "EPSG:3857" => Ok(BBoxCrs::Epsg3857),
+            "EPSG:4326" => Ok(BBoxCrs::Epsg4326),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the EPSG:3857 coordinates (x, y) in meters for the given position.
+fn web_mercator_meters(pos: LatLonRad) -> (f64, f64) {
+    const EARTH_RADIUS: f64 = 6_378_137.0;
+    (
+        EARTH_RADIUS * pos.lon,
+        EARTH_RADIUS * (PI * 0.25 + pos.lat * 0.5).tan().ln(),
+    )
+}
+
+/// Parameters of a WMS GetMap request.
+#[derive(Clone, Debug)]
+pub struct WmsParams {
+    /// Comma-separated list of layer names
+    pub layers: String,
+    /// Comma-separated list of styles, one for each layer. May be empty for the default styles.
+    pub styles: String,
+    /// Image MIME type, e.g. "image/png"
+    pub format: String,
+    pub crs: BBoxCrs,
+    /// Width and height of the requested images in pixels
+    pub tile_size: u32,
+}
+
 /// A template for tile URLs that can be efficiently filled with values from a `TileCoord`.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct UrlTemplate {
     /// The template string that includes placeholders between static parts
     template_string: String,
@@ -34,19 +127,32 @@ pub struct UrlTemplate {
     static_parts: Vec<::std::ops::Range<usize>>,
     /// Kinds of placeholders between the static parts
     placeholders: Vec<Placeholder>,
+    /// Values for the subdomain placeholder
+    subdomains: Vec<String>,
+    /// Coordinate reference system for the bbox placeholder
+    bbox_crs: BBoxCrs,
     /// Maximum length in bytes of a filled template
     max_size: usize,
 }
 
 impl UrlTemplate {
     pub fn new<S: Into<String>>(template_str: S) -> Result<UrlTemplate, String> {
+        Self::with_subdomains(template_str, vec![])
+    }
+
+    /// Create a template that fills the `{s}` placeholder with one of the given subdomains. The
+    /// subdomain is picked deterministically for each tile, so a tile is always fetched from the
+    /// same host.
+    pub fn with_subdomains<S: Into<String>>(template_str: S, subdomains: Vec<String>)
+        -> Result<UrlTemplate, String>
+    {
         let template_string = template_str.into();
         let mut static_parts = vec![];
         let mut placeholders = vec![];
         let mut max_size = 0;
 
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"\{([a-z]+)\}").unwrap();
+            static ref RE: Regex = Regex::new(r"\{(-?[a-z]+)\}").unwrap();
         }
 
         let mut offset = 0;
@@ -59,11 +165,19 @@ impl UrlTemplate {
                 let ph = match cap.get(1).unwrap().as_str() {
                     "x" => Placeholder::X,
                     "y" => Placeholder::Y,
+                    "-y" => Placeholder::YFlipped,
                     "z" => Placeholder::Z,
                     "quadkey" => Placeholder::Quadkey,
+                    "s" => Placeholder::Subdomain,
+                    "r" => Placeholder::Resolution,
+                    "bbox" => Placeholder::BBox,
+                    "time" => Placeholder::Time,
                     s => return Err(format!("Invalid placeholder in url template: {:?}", s)),
                 };
-                max_size += ph.max_size();
+                max_size += match ph {
+                    Placeholder::Subdomain => subdomains.iter().map(|s| s.len()).max().unwrap_or(0),
+                    _ => ph.max_size(),
+                };
                 placeholders.push(ph);
             }
 
@@ -75,8 +189,10 @@ impl UrlTemplate {
 
         let template_valid =
             placeholders.contains(&Placeholder::Quadkey) ||
+            placeholders.contains(&Placeholder::BBox) ||
             (placeholders.contains(&Placeholder::X) &&
-             placeholders.contains(&Placeholder::Y) &&
+             (placeholders.contains(&Placeholder::Y) ||
+              placeholders.contains(&Placeholder::YFlipped)) &&
              placeholders.contains(&Placeholder::Z));
 
         if !template_valid {
@@ -86,16 +202,79 @@ impl UrlTemplate {
             );
         }
 
+        if placeholders.contains(&Placeholder::Subdomain) && subdomains.is_empty() {
+            return Err(format!(
+                "template contains a subdomain placeholder but no subdomains are given: {:?}",
+                template_string)
+            );
+        }
+
         Ok(UrlTemplate {
             template_string,
             static_parts,
             placeholders,
+            subdomains,
+            bbox_crs: BBoxCrs::Epsg3857,
             max_size,
         })
     }
 
+    /// Create a template for a WMS GetMap request for the service at `base_url`. Each tile is
+    /// requested with its bounding box in the CRS of the given parameters. The layers, styles and
+    /// format are percent-encoded.
+    pub fn wms(base_url: &str, subdomains: Vec<String>, params: &WmsParams)
+        -> Result<UrlTemplate, String>
+    {
+        let separator = if !base_url.contains('?') {
+            "?"
+        } else if base_url.ends_with('?') || base_url.ends_with('&') {
+            ""
+        } else {
+            "&"
+        };
+        let encode = |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+
+        let template_str = format!(
+            "{}{}SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&LAYERS={}&STYLES={}&FORMAT={}&CRS={}&WIDTH={}&HEIGHT={}&BBOX={{bbox}}",
+            base_url,
+            separator,
+            encode(&params.layers),
+            encode(&params.styles),
+            encode(&params.format),
+            params.crs.to_str(),
+            params.tile_size,
+            params.tile_size,
+        );
+
+        Ok(Self::with_subdomains(template_str, subdomains)?.with_bbox_crs(params.crs))
+    }
+
+    /// Set the coordinate reference system for the `{bbox}` placeholder. The default is
+    /// EPSG:3857.
+    pub fn with_bbox_crs(mut self, crs: BBoxCrs) -> Self {
+        self.bbox_crs = crs;
+        self
+    }
+
     pub fn fill(&self, tile_coord: TileCoord) -> Option<String> {
-        let mut ret = String::with_capacity(self.max_size);
+        self.fill_with_scale(tile_coord, 1)
+    }
+
+    /// Fill the template and replace the `{r}` placeholder with a suffix for the given resolution
+    /// scale factor (an empty string for `1`, "@2x" for `2`, ...).
+    pub fn fill_with_scale(&self, tile_coord: TileCoord, scale: u32) -> Option<String> {
+        self.fill_tile(tile_coord, TileScheme::Xyz, scale, None)
+    }
+
+    /// Fill the template like `fill_with_scale` for a source with the given numbering of tile
+    /// rows. Only `{y}` is filled with the row in this numbering, `{-y}` is always the TMS row and
+    /// all other placeholders use the XYZ coordinates of `tile_coord`. The `{time}` placeholder is
+    /// replaced with the given timestamp. Returns `None` if the template has a `{time}`
+    /// placeholder but no timestamp is given.
+    pub fn fill_tile(&self, tile_coord: TileCoord, scheme: TileScheme, scale: u32, time: Option<&str>)
+        -> Option<String>
+    {
+        let mut ret = String::with_capacity(self.max_size + time.map_or(0, |t| t.len()));
 
         if let Some(prefix) = self.static_parts.first() {
             ret += &self.template_string[prefix.start..prefix.end];
@@ -104,20 +283,57 @@ impl UrlTemplate {
         for (i, static_part) in self.static_parts.iter().skip(1).enumerate() {
             let dyn_part = match self.placeholders[i] {
                 Placeholder::X => tile_coord.x.to_string(),
-                Placeholder::Y => tile_coord.y.to_string(),
+                Placeholder::Y => scheme.convert(tile_coord).y.to_string(),
+                Placeholder::YFlipped => tile_coord.flip_y().y.to_string(),
                 Placeholder::Z => tile_coord.zoom.to_string(),
                 Placeholder::Quadkey => {
                     match tile_coord.to_quadkey() {
                         Some(q) => q,
                         None => return None,
                     }
-                }
+                },
+                Placeholder::Subdomain => self.subdomain(tile_coord).to_string(),
+                Placeholder::Resolution => {
+                    if scale > 1 {
+                        format!("@{}x", scale)
+                    } else {
+                        String::new()
+                    }
+                },
+                Placeholder::BBox => {
+                    let b = self.bbox_crs.bbox(tile_coord);
+                    format!("{},{},{},{}", b[0], b[1], b[2], b[3])
+                },
+                Placeholder::Time => time?.to_string(),
             };
             ret += &dyn_part;
             ret += &self.template_string[static_part.start..static_part.end];;
         }
         Some(ret)
     }
+
+    /// Returns true if the template contains a `{r}` placeholder, so that tiles with a higher
+    /// resolution can be requested.
+    pub fn has_resolution_placeholder(&self) -> bool {
+        self.placeholders.contains(&Placeholder::Resolution)
+    }
+
+    /// Returns true if the template contains a `{time}` placeholder, so that it needs a list of
+    /// timestamps to be filled.
+    pub fn has_time_placeholder(&self) -> bool {
+        self.placeholders.contains(&Placeholder::Time)
+    }
+
+    /// Returns the subdomain for the given tile. Neighboring tiles are distributed over all
+    /// subdomains.
+    fn subdomain(&self, tile_coord: TileCoord) -> &str {
+        if self.subdomains.is_empty() {
+            return "";
+        }
+        let sum = i64::from(tile_coord.x) + i64::from(tile_coord.y);
+        let index = sum.rem_euclid(self.subdomains.len() as i64) as usize;
+        &self.subdomains[index]
+    }
 }
 
 #[cfg(test)]
@@ -138,6 +354,10 @@ mod tests {
         assert!(UrlTemplate::new("{x}{z}{y}").is_ok());
         assert!(UrlTemplate::new("{quadkey}").is_ok());
         assert!(UrlTemplate::new("{x}{quadkey}").is_ok());
+        assert!(UrlTemplate::new("{x}{z}{-y}").is_ok());
+        assert!(UrlTemplate::new("{x}{z}{-x}").is_err());
+        assert!(UrlTemplate::new("{s}{x}{y}{z}").is_err());
+        assert!(UrlTemplate::with_subdomains("{s}{x}{y}{z}", vec!["a".to_string()]).is_ok());
     }
 
     #[test]
@@ -157,5 +377,93 @@ mod tests {
         check_templ("a{quadkey}b{z}c",
                     TileCoord::new(1, 0, 0),
                     "a0b1c");
+        check_templ("https://tiles.example.com/{z}/{x}/{-y}.png",
+                    TileCoord::new(2, 1, 0),
+                    "https://tiles.example.com/2/1/3.png");
+        check_templ("{z}{x}{y}{-y}",
+                    TileCoord::new(3, 5, 2),
+                    "3525");
+    }
+
+    #[test]
+    fn check_subdomains() {
+        let subdomains = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let t = UrlTemplate::with_subdomains("{s}.example.com/{z}/{x}/{y}", subdomains).unwrap();
+        assert_eq!(t.fill(TileCoord::new(2, 0, 0)), Some("a.example.com/2/0/0".to_string()));
+        assert_eq!(t.fill(TileCoord::new(2, 1, 0)), Some("b.example.com/2/1/0".to_string()));
+        assert_eq!(t.fill(TileCoord::new(2, 1, 1)), Some("c.example.com/2/1/1".to_string()));
+        assert_eq!(t.fill(TileCoord::new(2, 3, 0)), Some("a.example.com/2/3/0".to_string()));
+        // same tile, same host
+        assert_eq!(t.fill(TileCoord::new(2, 1, 1)), t.fill(TileCoord::new(2, 1, 1)));
+    }
+
+    #[test]
+    fn check_resolution() {
+        let t = UrlTemplate::new("https://tiles.example.com/{z}/{x}/{y}{r}.png").unwrap();
+        assert!(t.has_resolution_placeholder());
+        assert_eq!(t.fill_with_scale(TileCoord::new(2, 1, 0), 1),
+                   Some("https://tiles.example.com/2/1/0.png".to_string()));
+        assert_eq!(t.fill_with_scale(TileCoord::new(2, 1, 0), 2),
+                   Some("https://tiles.example.com/2/1/0@2x.png".to_string()));
+        assert!(!UrlTemplate::new("{z}{x}{y}").unwrap().has_resolution_placeholder());
+    }
+
+    #[test]
+    fn check_time() {
+        let t = UrlTemplate::new("https://radar.example.com/{time}/{z}/{x}/{y}.png").unwrap();
+        assert!(t.has_time_placeholder());
+        assert_eq!(t.fill_tile(TileCoord::new(2, 1, 0), TileScheme::Xyz, 1, Some("2021-06-01T12:00:00Z")),
+                   Some("https://radar.example.com/2021-06-01T12:00:00Z/2/1/0.png".to_string()));
+        assert_eq!(t.fill(TileCoord::new(2, 1, 0)), None);
+        assert!(!UrlTemplate::new("{z}{x}{y}").unwrap().has_time_placeholder());
+    }
+
+    fn assert_bbox_eq(a: [f64; 4], b: [f64; 4], epsilon: f64) {
+        for i in 0..4 {
+            assert!((a[i] - b[i]).abs() < epsilon, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn check_bbox() {
+        let max = 20_037_508.342_789_244;
+        assert_bbox_eq(BBoxCrs::Epsg3857.bbox(TileCoord::new(0, 0, 0)), [-max, -max, max, max], 1e-6);
+        assert_bbox_eq(BBoxCrs::Epsg3857.bbox(TileCoord::new(1, 1, 0)), [0.0, 0.0, max, max], 1e-6);
+
+        let max_lat = 85.051_128_779_806_59;
+        assert_bbox_eq(BBoxCrs::Epsg4326.bbox(TileCoord::new(0, 0, 0)), [-max_lat, -180.0, max_lat, 180.0], 1e-9);
+        assert_bbox_eq(BBoxCrs::Epsg4326.bbox(TileCoord::new(1, 0, 1)), [-max_lat, -180.0, 0.0, 0.0], 1e-9);
+    }
+
+    #[test]
+    fn check_wms() {
+        let params = WmsParams {
+            layers: "roads,water".to_string(),
+            styles: String::new(),
+            format: "image/png".to_string(),
+            crs: BBoxCrs::Epsg4326,
+            tile_size: 256,
+        };
+        let t = UrlTemplate::wms("https://gis.example.com/wms?map=base", vec![], &params).unwrap();
+        assert_eq!(
+            t.fill(TileCoord::new(1, 0, 1)),
+            Some(format!(
+                "https://gis.example.com/wms?map=base&SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap\
+                 &LAYERS=roads%2Cwater&STYLES=&FORMAT=image%2Fpng&CRS=EPSG:4326&WIDTH=256&HEIGHT=256\
+                 &BBOX={},-180,0,0",
+                BBoxCrs::Epsg4326.bbox(TileCoord::new(1, 0, 1))[0],
+            ))
+        );
+
+        // Reserved characters in the parameters do not end up in the query string.
+        let params = WmsParams {
+            layers: "land use&{z}".to_string(),
+            styles: "a=b".to_string(),
+            ..params
+        };
+        let t = UrlTemplate::wms("https://gis.example.com/wms", vec![], &params).unwrap();
+        let url = t.fill(TileCoord::new(1, 0, 1)).unwrap();
+        assert!(url.contains("&LAYERS=land+use%26%7Bz%7D&STYLES=a%3Db&"));
+        assert!(UrlTemplate::new("https://gis.example.com/wms?BBOX={bbox}").is_ok());
     }
 }